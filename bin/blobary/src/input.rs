@@ -3,6 +3,7 @@
 use std::{
     fs::File,
     io::{stdin, Read, Result},
+    ops::Range,
     path::Path,
 };
 
@@ -13,6 +14,28 @@ pub fn parse_bytesize(input: &str) -> std::io::Result<usize> {
     Ok(input as _)
 }
 
+pub fn parse_range(input: &str) -> Result<Range<u64>> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid byte range: {}", input),
+        )
+    };
+    let (start, end) = input.split_once("..").ok_or_else(invalid)?;
+    let start = match start {
+        "" => 0,
+        start => start.parse().map_err(|_| invalid())?,
+    };
+    let end = match end {
+        "" => u64::MAX,
+        end => end.parse().map_err(|_| invalid())?,
+    };
+    if start > end {
+        return Err(invalid());
+    }
+    Ok(start..end)
+}
+
 pub fn list_inputs(input_paths: &Vec<impl AsRef<Path>>) -> Result<Vec<String>> {
     if input_paths.is_empty() {
         Ok(vec![String::from("/dev/stdin")])
@@ -10,21 +10,26 @@ mod sysexits;
 
 use crate::{
     hash::{encode_hash, parse_hash},
-    input::{list_inputs, open_inputs, parse_bytesize},
+    input::{list_inputs, open_inputs, parse_bytesize, parse_range},
     output::open_output,
-    store::{open_store, open_store_from_url},
+    store::{
+        open_directory_store, open_directory_store_with, open_store, open_store_from_url,
+        store_options,
+    },
     sysexits::{exit, Sysexits},
 };
 use blobary::{
-    Blob, BlobHash, BlobHasher, BlobStoreExt, IndexedBlobStoreIterator, DEFAULT_MIME_TYPE,
+    Blob, BlobHash, BlobHasher, BlobMetadata, BlobStoreError, BlobStoreExt, BlobStoreIterator,
+    CheckIssue, ChunkerOptions, DirectoryLayout, Filter, IndexedBlobStore,
+    IndexedBlobStoreIterator, Manifest, DEFAULT_MIME_TYPE, MANIFEST_MAGIC,
 };
-use cap_tempfile::{ambient_authority, TempDir, TempFile};
+use bytesize::ByteSize;
 use clap::{Parser, Subcommand};
 use dotenvy::dotenv;
 use shadow_rs::shadow;
 use std::{
-    io::{stdout, Read, Seek},
-    ops::{Deref, DerefMut},
+    io::{stdout, ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::{DerefMut, Range},
     path::{Path, PathBuf},
     time::{SystemTime, UNIX_EPOCH},
 };
@@ -34,6 +39,9 @@ use url::Url;
 
 shadow!(build);
 
+/// The most bytes of a range that `get --range` fetches at a time.
+const RANGE_PIECE_SIZE: u64 = 64 * 1024 * 1024;
+
 /// Blobary command-line interface (CLI)
 #[derive(Parser, Debug)]
 #[command(name = "Blobary", about)]
@@ -72,10 +80,53 @@ enum Commands {
     Hash { paths: Vec<PathBuf> },
     /// Initialize `$HOME/.config/blobary`
     Init {},
+    /// Generate an encryption key
+    ///
+    /// Prints a new random key, hex-encoded. Setting `BLOBARY_KEY` to it, or
+    /// `BLOBARY_KEY_FILE` to the path of a file holding it, encrypts the
+    /// blobs added to the repository, and decrypts them when fetched.
+    #[cfg(feature = "encrypt")]
+    Keygen {},
     /// Check the repository integrity
-    Check {},
+    ///
+    /// Re-hashes every blob of a directory repository, and reports missing
+    /// blob files, size and hash mismatches, and unindexed files. Exits with
+    /// EX_DATAERR (65) for corrupt blobs, EX_NOINPUT (66) for missing blob
+    /// files, or EX_TEMPFAIL (75) for unindexed files, whichever is most
+    /// severe, in that order.
+    Check {
+        /// Repair a directory repository after a crash: index orphaned blob
+        /// files, delete leftover and temporary files, and rebuild a lost
+        /// index
+        #[clap(long)]
+        repair: bool,
+
+        /// Move corrupt and undecodable blobs into `.blobary/.quarantine`
+        /// and remove them from the repository
+        #[clap(long)]
+        quarantine: bool,
+    },
     /// Compact and compress the repository
-    Compact {},
+    ///
+    /// Rewrites the index of a directory repository without the records of
+    /// removed blobs, printing the old and new ID of each renumbered blob.
+    Compact {
+        /// Re-encode the blobs with the given compression
+        #[clap(long, value_name = "ALGORITHM", value_parser = ["gzip", "lz4", "zstd", "none"])]
+        compress: Option<String>,
+    },
+    /// Re-lay out the blob files of a directory repository in place
+    Migrate {
+        /// The number of nested subdirectories, or 0 for a flat layout
+        #[clap(long, default_value_t = 2)]
+        levels: u8,
+
+        /// The number of hash characters naming each subdirectory
+        #[clap(long, default_value_t = 2)]
+        width: u8,
+    },
+    /// Upgrade a directory repository to the current index format
+    Upgrade {},
     /// List blobs in the repository
     #[clap(alias = "ls")]
     List {},
@@ -84,11 +135,11 @@ enum Commands {
         /// The input file(s) to add
         paths: Vec<PathBuf>,
 
-        /// Split input into chunks of the given size
+        /// Split input into content-defined chunks of the given average size
         #[clap(short = 'C', long, value_name = "SIZE", value_parser = parse_bytesize)]
         chunk: Option<usize>,
 
-        /// Concatenate input files into a single blob
+        /// Store a manifest blob describing the concatenation of the inputs
         #[clap(long, conflicts_with = "chunk")]
         concat: bool,
     },
@@ -99,6 +150,15 @@ enum Commands {
     Get {
         #[arg(value_parser = parse_hash)]
         ids: Vec<BlobHash>,
+
+        /// Output manifest blobs as is instead of the content they describe
+        #[clap(long)]
+        raw: bool,
+
+        /// Output only the given byte range, as in `START..END`, `START..`,
+        /// or `..END`
+        #[clap(long, value_parser = parse_range)]
+        range: Option<Range<u64>>,
     },
     /// Remove a blob by its hash
     #[clap(aliases = &["rm", "del", "delete"])]
@@ -155,8 +215,12 @@ pub fn main() {
         Commands::Config {} => Commands::config(&options),
         Commands::Hash { paths } => Commands::hash(paths, &options),
         Commands::Init {} => Commands::init(&options),
-        Commands::Check {} => Commands::check(&options),
-        Commands::Compact {} => Commands::compact(&options),
+        #[cfg(feature = "encrypt")]
+        Commands::Keygen {} => Commands::keygen(&options),
+        Commands::Check { repair, quarantine } => Commands::check(*repair, *quarantine, &options),
+        Commands::Compact { compress } => Commands::compact(compress.as_deref(), &options),
+        Commands::Migrate { levels, width } => Commands::migrate(*levels, *width, &options),
+        Commands::Upgrade {} => Commands::upgrade(&options),
         Commands::List {} => Commands::list(&options),
         Commands::Add {
             paths,
@@ -168,7 +232,7 @@ pub fn main() {
             (false, Some(chunk_size)) => Commands::add_chunked(paths, chunk_size, &options),
         },
         Commands::Put { text } => Commands::put(text, &options),
-        Commands::Get { ids } => Commands::get(ids, &options),
+        Commands::Get { ids, raw, range } => Commands::get(ids, *raw, range.clone(), &options),
         Commands::Remove { ids } => Commands::remove(ids, &options),
         Commands::Pull { url } => Commands::pull(url, &options),
         Commands::Push { url } => Commands::push(url, &options),
@@ -245,27 +309,222 @@ impl Commands {
         Ok(()) // TODO
     }
 
-    fn check(_options: &Options) -> Result<(), Sysexits> {
-        let _store = open_store(false)?;
-        Ok(()) // TODO
+    #[cfg(feature = "encrypt")]
+    fn keygen(_options: &Options) -> Result<(), Sysexits> {
+        let key = blobary::encrypt::EncryptionKey::generate();
+        println!("{}", key.to_hex().as_str());
+        Ok(())
     }
 
-    fn compact(options: &Options) -> Result<(), Sysexits> {
-        let _store = open_store(!options.read_only)?;
-        Ok(()) // TODO
+    fn check(repair: bool, quarantine: bool, options: &Options) -> Result<(), Sysexits> {
+        if repair {
+            Commands::repair(options)?;
+        }
+        let mut store = open_directory_store(quarantine && !options.read_only)?;
+        let report = store.check()?;
+        let mut result = Ok(());
+        for issue in &report.issues {
+            let (message, code) = match issue {
+                CheckIssue::Missing { hash, .. } => (
+                    format!("missing blob: {}", encode_hash(*hash)),
+                    Sysexits::EX_NOINPUT,
+                ),
+                CheckIssue::Undecodable { hash, .. } => (
+                    format!("undecodable blob: {}", encode_hash(*hash)),
+                    Sysexits::EX_DATAERR,
+                ),
+                CheckIssue::SizeMismatch {
+                    hash,
+                    expected,
+                    actual,
+                    ..
+                } => (
+                    format!(
+                        "size mismatch: {} ({} bytes instead of {})",
+                        encode_hash(*hash),
+                        actual,
+                        expected
+                    ),
+                    Sysexits::EX_DATAERR,
+                ),
+                CheckIssue::HashMismatch { hash, .. } => (
+                    format!("hash mismatch: {}", encode_hash(*hash)),
+                    Sysexits::EX_DATAERR,
+                ),
+                CheckIssue::Unindexed { path } => (
+                    format!("unindexed file: {}", path.display()),
+                    Sysexits::EX_TEMPFAIL,
+                ),
+            };
+            eprintln!("blobary: {}", message);
+            let severity = |code: &Sysexits| match code {
+                Sysexits::EX_DATAERR => 2,
+                Sysexits::EX_NOINPUT => 1,
+                _ => 0,
+            };
+            result = match result {
+                Err(worst) if severity(&worst) >= severity(&code) => Err(worst),
+                _ => Err(code),
+            };
+        }
+        if quarantine {
+            for issue in &report.issues {
+                if let CheckIssue::Undecodable { hash, .. }
+                | CheckIssue::SizeMismatch { hash, .. }
+                | CheckIssue::HashMismatch { hash, .. } = issue
+                {
+                    store.quarantine(*hash)?;
+                    if options.verbose || options.debug {
+                        println!("Quarantined {}", encode_hash(*hash));
+                    }
+                }
+            }
+        }
+        if options.verbose || options.debug {
+            println!(
+                "Checked {} blobs, found {} problems",
+                report.checked,
+                report.issues.len()
+            );
+        }
+        result
+    }
+
+    fn repair(options: &Options) -> Result<(), Sysexits> {
+        let mut store = open_directory_store(!options.read_only)?;
+        let report = store.repair()?;
+        for path in &report.corrupt_files {
+            eprintln!("blobary: corrupt blob file: {}", path.display());
+        }
+        if options.debug {
+            for blob_hash in &report.reindexed {
+                println!("Indexed {}", encode_hash(*blob_hash));
+            }
+        }
+        if options.verbose || options.debug {
+            if report.rebuilt_index {
+                println!("Rebuilt the index from the blob files");
+            }
+            println!(
+                "Indexed {} blobs, deleted {} leftover and {} temporary files",
+                report.reindexed.len(),
+                report.removed_files,
+                report.removed_temp_files
+            );
+        }
+        Ok(())
+    }
+
+    fn compact(compress: Option<&str>, options: &Options) -> Result<(), Sysexits> {
+        let filters: Vec<Box<dyn Filter>> = match compress {
+            None | Some("none") => vec![],
+            #[cfg(feature = "gzip")]
+            Some("gzip") => vec![Box::new(blobary::GzipCompressor {})],
+            #[cfg(feature = "lz4")]
+            Some("lz4") => vec![Box::new(blobary::Lz4Compressor {})],
+            #[cfg(feature = "zstd")]
+            Some("zstd") => vec![Box::new(blobary::ZstdCompressor::new())],
+            Some(algorithm) => {
+                eprintln!("blobary: unsupported compression: {}", algorithm);
+                return Err(Sysexits::EX_USAGE);
+            }
+        };
+        // Compress before encrypting, with any configured key:
+        let mut config = store_options(!options.read_only)?;
+        config.filters.splice(0..0, filters);
+        let mut store = open_directory_store_with(config)?;
+        let report = store.compact(compress.is_some())?;
+        for (old_id, new_id) in &report.id_map {
+            println!("{} {}", old_id, new_id);
+        }
+        if options.verbose || options.debug {
+            println!(
+                "Renumbered {} blobs, re-encoded {} blobs, reclaimed {}",
+                report.id_map.len(),
+                report.reencoded,
+                ByteSize(report.reclaimed())
+            );
+        }
+        Ok(())
+    }
+
+    fn migrate(levels: u8, width: u8, options: &Options) -> Result<(), Sysexits> {
+        let Some(layout) = DirectoryLayout::fanout(levels, width) else {
+            eprintln!(
+                "blobary: invalid layout: {} levels of width {}",
+                levels, width
+            );
+            return Err(Sysexits::EX_USAGE);
+        };
+        let mut store = open_directory_store(!options.read_only)?;
+        let moved = store.relayout(layout)?;
+        if options.verbose || options.debug {
+            println!("Moved {} blobs into the {} layout", moved, layout);
+        }
+        Ok(())
+    }
+
+    fn upgrade(options: &Options) -> Result<(), Sysexits> {
+        let mut store = open_directory_store(!options.read_only)?;
+        let upgraded = store.upgrade()?;
+        if options.verbose || options.debug {
+            match upgraded {
+                true => println!(
+                    "Upgraded the index to format version {}",
+                    store.format_version()
+                ),
+                false => println!(
+                    "The index is already at format version {}",
+                    store.format_version()
+                ),
+            }
+        }
+        Ok(())
     }
 
     fn list(options: &Options) -> Result<(), Sysexits> {
-        let mut store = open_store(false)?;
-        for blob in IndexedBlobStoreIterator::new(store.deref_mut()) {
-            let blob_hash = encode_hash(blob.hash);
-            if options.verbose || options.debug {
-                let blob_data = blob.data.unwrap();
-                let mut blob_data = blob_data.borrow_mut();
-                let blob_type = blob_data.mime_type()?.unwrap_or(DEFAULT_MIME_TYPE);
-                println!("{}\t{}\t{}", blob_hash, blob.size, blob_type);
+        let store = open_store(false)?;
+        for entry in BlobStoreIterator::new(store.as_ref()) {
+            let (blob_hash, blob_size) = entry?;
+            if !(options.verbose || options.debug) {
+                println!("{}", encode_hash(blob_hash));
+                continue;
+            }
+            // Blobs stored before metadata was recorded have none, so their
+            // type is detected from their data instead:
+            let metadata = match store.get_metadata(blob_hash)? {
+                Some(metadata) => metadata,
+                None => {
+                    let mut metadata = BlobMetadata::new();
+                    if let Some(Blob {
+                        data: Some(blob_data),
+                        ..
+                    }) = store.get_by_hash(blob_hash)?
+                    {
+                        let mime_type = blob_data.lock().unwrap().mime_type()?;
+                        metadata.mime_type = mime_type.map(|mime_type| mime_type.to_string());
+                    }
+                    metadata
+                }
+            };
+            let blob_type = metadata.mime_type.as_deref().unwrap_or(DEFAULT_MIME_TYPE);
+            if options.debug {
+                let created = metadata
+                    .created
+                    .and_then(|created| created.duration_since(UNIX_EPOCH).ok())
+                    .map(|created| created.as_secs().to_string())
+                    .unwrap_or_default();
+                let name = metadata.name.unwrap_or_default();
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    encode_hash(blob_hash),
+                    blob_size,
+                    blob_type,
+                    created,
+                    name
+                );
             } else {
-                println!("{}", blob_hash);
+                println!("{}\t{}\t{}", encode_hash(blob_hash), blob_size, blob_type);
             }
         }
         Ok(())
@@ -295,40 +554,25 @@ impl Commands {
         chunk_size: &usize,
         options: &Options,
     ) -> Result<(), Sysexits> {
+        let chunker_options =
+            ChunkerOptions::with_avg_size((*chunk_size).try_into().unwrap_or(u32::MAX));
+        if let Err(err) = chunker_options.validate() {
+            eprintln!("blobary: {}", err);
+            return Err(Sysexits::EX_USAGE);
+        }
+
         let mut store = open_store(!options.read_only)?;
         let input_paths = list_inputs(input_paths)?;
-
-        let mut chunks = Vec::<Blob>::new();
-        let mut buffer = vec![0u8; *chunk_size];
-
         for input_path in input_paths {
             let input_file = &mut std::fs::File::open(input_path)?;
-            loop {
-                match input_file.read_exact(&mut buffer[..]) {
-                    Ok(_) => {
-                        let (_, blob) = store.put_bytes(&mut buffer[..])?;
-                        if options.verbose || options.debug {
-                            println!("{}", encode_hash(blob.hash));
-                        }
-                        assert_eq!(blob.size, *chunk_size as u64);
-                        chunks.push(blob);
-                    }
-                    Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
-                        let input_offset = input_file.stream_position()?;
-                        let chunk_size = input_offset as usize % buffer.len();
-                        if chunk_size != 0 {
-                            let (_, blob) = store.put_bytes(&buffer[0..chunk_size])?;
-                            if options.verbose || options.debug {
-                                println!("{}", encode_hash(blob.hash));
-                            }
-                            assert_eq!(blob.size, chunk_size as u64);
-                            chunks.push(blob);
-                        }
-                        break;
-                    }
-                    Err(err) => {
-                        eprintln!("blobary: {}", err);
-                        return Err(Sysexits::EX_IOERR);
+            match store.put_chunked(input_file, &chunker_options) {
+                Err(err) => {
+                    eprintln!("blobary: {}", err);
+                    return Err(Sysexits::EX_IOERR);
+                }
+                Ok((_created, manifest)) => {
+                    if options.verbose || options.debug {
+                        println!("{}", encode_hash(manifest.hash));
                     }
                 }
             }
@@ -341,16 +585,19 @@ impl Commands {
         let mut store = open_store(!options.read_only)?;
         let input_paths = list_inputs(input_paths)?;
 
-        let temp_dir = TempDir::new(ambient_authority())?;
-        let mut buffer_file = TempFile::new(temp_dir.deref())?;
-
+        // Store each input as is, and the concatenation as a manifest of them:
+        let mut manifest = Manifest::new();
         for input_path in input_paths {
-            let input_file = &mut std::fs::File::open(input_path)?;
-            std::io::copy(input_file, &mut buffer_file)?;
+            match store.put_file(input_path) {
+                Err(err) => {
+                    eprintln!("blobary: {}", err);
+                    return Err(Sysexits::EX_IOERR);
+                }
+                Ok((_created, blob)) => manifest.push(blob.hash, blob.size),
+            }
         }
 
-        buffer_file.rewind()?;
-        match store.put(&mut buffer_file) {
+        match store.put_bytes(manifest.to_bytes()) {
             Err(err) => {
                 eprintln!("blobary: {}", err);
                 Err(Sysexits::EX_IOERR)
@@ -380,16 +627,91 @@ impl Commands {
         Ok(())
     }
 
-    fn get(blob_hashes: &Vec<BlobHash>, _options: &Options) -> Result<(), Sysexits> {
+    fn get(
+        blob_hashes: &Vec<BlobHash>,
+        raw: bool,
+        range: Option<Range<u64>>,
+        _options: &Options,
+    ) -> Result<(), Sysexits> {
         let store = open_store(false)?;
+        if let Some(range) = range {
+            return Commands::get_range(&*store, blob_hashes, raw, range);
+        }
         for blob_hash in blob_hashes {
             match store.get_by_hash(*blob_hash)? {
                 None => return Err(Sysexits::EX_NOINPUT),
                 Some(blob) => {
                     let blob_data = blob.data.unwrap();
-                    let mut blob_data = blob_data.borrow_mut();
+                    let mut blob_data = blob_data.lock().unwrap();
                     let mut stdout = stdout().lock();
-                    std::io::copy(blob_data.deref_mut(), &mut stdout).unwrap();
+
+                    // Stream the concatenated content of manifest blobs:
+                    let mut prefix = Vec::with_capacity(MANIFEST_MAGIC.len());
+                    blob_data
+                        .deref_mut()
+                        .take(MANIFEST_MAGIC.len() as u64)
+                        .read_to_end(&mut prefix)?;
+                    blob_data.rewind()?;
+                    if !raw && Manifest::is_manifest(&prefix) {
+                        match Manifest::read_from(blob_data.deref_mut()) {
+                            Ok(manifest) => {
+                                let mut concat_data = store.get_concat(&manifest)?;
+                                std::io::copy(&mut concat_data, &mut stdout)?;
+                                continue;
+                            }
+                            // Not a manifest after all, just data starting with its magic:
+                            Err(err) if err.kind() == ErrorKind::InvalidData => {
+                                blob_data.rewind()?;
+                            }
+                            Err(err) => return Err(err.into()),
+                        }
+                    }
+                    std::io::copy(blob_data.deref_mut(), &mut stdout)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn get_range(
+        store: &(dyn IndexedBlobStore + 'static),
+        blob_hashes: &Vec<BlobHash>,
+        raw: bool,
+        range: Range<u64>,
+    ) -> Result<(), Sysexits> {
+        let len = range.end.saturating_sub(range.start);
+        for blob_hash in blob_hashes {
+            let mut stdout = stdout().lock();
+
+            // Read ranges of manifest blobs from the concatenated content:
+            if !raw {
+                let prefix = store.get_range(*blob_hash, 0, MANIFEST_MAGIC.len() as u64)?;
+                if Manifest::is_manifest(&prefix.ok_or(Sysexits::EX_NOINPUT)?) {
+                    match store.get_manifest(*blob_hash) {
+                        Ok(manifest) => {
+                            let mut concat_data = store.get_concat(&manifest.unwrap())?;
+                            concat_data.seek(SeekFrom::Start(range.start))?;
+                            std::io::copy(&mut concat_data.take(len), &mut stdout)?;
+                            continue;
+                        }
+                        Err(BlobStoreError::IO(err)) if err.kind() == ErrorKind::InvalidData => {}
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+            }
+
+            // Fetch the range from the store a piece at a time, rather than
+            // buffering it all:
+            let mut offset = range.start;
+            loop {
+                let piece_len = range.end.saturating_sub(offset).min(RANGE_PIECE_SIZE);
+                let piece = store
+                    .get_range(*blob_hash, offset, piece_len)?
+                    .ok_or(Sysexits::EX_NOINPUT)?;
+                stdout.write_all(&piece)?;
+                offset += piece_len;
+                if (piece.len() as u64) < piece_len || offset >= range.end {
+                    break; // at the end of the blob or the range
                 }
             }
         }
@@ -460,7 +782,7 @@ impl Commands {
         let mut tarball = tar::Builder::new(output);
         for blob in IndexedBlobStoreIterator::new(store.deref_mut()) {
             let blob_data = blob.data.unwrap();
-            let mut blob_data = blob_data.borrow_mut();
+            let mut blob_data = blob_data.lock().unwrap();
             let mut file_head = Header::new_ustar();
             file_head.set_entry_type(EntryType::Regular);
             file_head.set_path(blob.hash.to_hex().as_str())?; // only base-16 supported here
@@ -471,7 +793,7 @@ impl Commands {
             file_head.set_groupname("root")?;
             file_head.set_cksum();
             blob_data.seek(std::io::SeekFrom::Start(0))?;
-            tarball.append(&file_head, &mut blob_data.deref_mut())?;
+            tarball.append(&file_head, blob_data.deref_mut())?;
         }
         tarball.finish()?;
         Ok(())
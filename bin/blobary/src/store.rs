@@ -2,11 +2,53 @@
 
 use crate::sysexits::Sysexits;
 use blobary::{
-    BlobStoreOptions, DirectoryBlobStore, EphemeralBlobStore, GzipCompressor, IndexedBlobStore,
+    BlobStoreOptions, DirectoryBlobStore, EphemeralBlobStore, FileBlobStore, IndexedBlobStore,
 };
 use std::env::VarError;
 use url::Url;
 
+/// Returns the store options, with the encryption key configured by
+/// `BLOBARY_KEY` (hex-encoded) or `BLOBARY_KEY_FILE`, if any, as a filter.
+pub fn store_options(writable: bool) -> Result<BlobStoreOptions, Sysexits> {
+    let config = BlobStoreOptions::new().writable(writable);
+    #[cfg(feature = "encrypt")]
+    if let Some(key) = load_key()? {
+        let encryptor = blobary::encrypt::Encryptor::new(key);
+        return Ok(config.filter(Box::new(encryptor)));
+    }
+    Ok(config)
+}
+
+#[cfg(feature = "encrypt")]
+fn load_key() -> Result<Option<blobary::encrypt::EncryptionKey>, Sysexits> {
+    use blobary::encrypt::EncryptionKey;
+    use zeroize::Zeroizing;
+    let key_hex = match std::env::var("BLOBARY_KEY") {
+        Ok(key_hex) if !key_hex.is_empty() => Zeroizing::new(key_hex),
+        Err(VarError::NotUnicode(_)) => {
+            eprintln!("blobary: BLOBARY_KEY contains invalid UTF-8");
+            return Err(Sysexits::EX_DATAERR);
+        }
+        _ => match std::env::var_os("BLOBARY_KEY_FILE") {
+            Some(key_path) if !key_path.is_empty() => match std::fs::read_to_string(&key_path) {
+                Ok(key_hex) => Zeroizing::new(key_hex),
+                Err(err) => {
+                    eprintln!("blobary: BLOBARY_KEY_FILE can't be read: {}", err);
+                    return Err(Sysexits::EX_NOINPUT);
+                }
+            },
+            _ => return Ok(None),
+        },
+    };
+    match EncryptionKey::from_hex(key_hex.trim()) {
+        Some(key) => Ok(Some(key)),
+        None => {
+            eprintln!("blobary: the encryption key is not 64 hexadecimal digits");
+            Err(Sysexits::EX_DATAERR)
+        }
+    }
+}
+
 pub fn open_store(writable: bool) -> Result<Box<dyn IndexedBlobStore>, Sysexits> {
     match std::env::var("BLOBARY_URL") {
         Err(VarError::NotPresent) => open_store_in_cwd(writable),
@@ -24,11 +66,40 @@ pub fn open_store(writable: bool) -> Result<Box<dyn IndexedBlobStore>, Sysexits>
     }
 }
 
+/// Opens the directory store in the current directory or at `BLOBARY_URL`.
+pub fn open_directory_store(writable: bool) -> Result<DirectoryBlobStore, Sysexits> {
+    open_directory_store_with(store_options(writable)?)
+}
+
+/// Opens the directory store as above, with the given options.
+pub fn open_directory_store_with(config: BlobStoreOptions) -> Result<DirectoryBlobStore, Sysexits> {
+    let result = match std::env::var("BLOBARY_URL") {
+        Ok(url) if !url.is_empty() => {
+            let path = match Url::parse(&url).ok().filter(|url| url.scheme() == "file") {
+                None => {
+                    eprintln!("blobary: BLOBARY_URL is not a directory store");
+                    return Err(Sysexits::EX_USAGE);
+                }
+                Some(url) => match url.to_file_path() {
+                    Ok(path) if path.is_dir() => path,
+                    _ => {
+                        eprintln!("blobary: BLOBARY_URL is not a directory store");
+                        return Err(Sysexits::EX_USAGE);
+                    }
+                },
+            };
+            DirectoryBlobStore::open_path(path, config)
+        }
+        _ => DirectoryBlobStore::open_in_cwd(config),
+    };
+    result.map_err(|err| {
+        eprintln!("blobary: {}", err);
+        Sysexits::EX_IOERR
+    })
+}
+
 fn open_store_in_cwd(writable: bool) -> Result<Box<dyn IndexedBlobStore>, Sysexits> {
-    match DirectoryBlobStore::open_in_cwd(BlobStoreOptions {
-        writable,
-        filters: vec![],
-    }) {
+    match DirectoryBlobStore::open_in_cwd(store_options(writable)?) {
         Ok(store) => Ok(Box::new(store)),
         Err(err) => {
             eprintln!("blobary: {}", err);
@@ -67,24 +138,30 @@ fn open_store_from_file_url(
     url: Url,
     writable: bool,
 ) -> Result<Box<dyn IndexedBlobStore>, Sysexits> {
-    match url.to_file_path() {
+    let path = match url.to_file_path() {
         Err(_) => {
             eprintln!("blobary: BLOBARY_URL contains an invalid path: {}", url);
-            Err(Sysexits::EX_DATAERR)
+            return Err(Sysexits::EX_DATAERR);
+        }
+        Ok(path) => path,
+    };
+    let config = store_options(writable)?;
+    // Existing regular files and new `*.blobary` paths are single-file stores:
+    let is_packed = match path.metadata() {
+        Ok(metadata) => metadata.is_file(),
+        Err(_) => path.extension().is_some_and(|ext| ext == "blobary"),
+    };
+    let result: blobary::Result<Box<dyn IndexedBlobStore>> = if is_packed {
+        FileBlobStore::open_path(path, config).map(|store| Box::new(store) as _)
+    } else {
+        DirectoryBlobStore::open_path(path, config).map(|store| Box::new(store) as _)
+    };
+    match result {
+        Ok(store) => Ok(store),
+        Err(err) => {
+            eprintln!("blobary: {}", err);
+            Err(Sysexits::EX_IOERR)
         }
-        Ok(path) => match DirectoryBlobStore::open_path(
-            path,
-            BlobStoreOptions {
-                writable,
-                filters: vec![],
-            },
-        ) {
-            Ok(store) => Ok(Box::new(store)),
-            Err(err) => {
-                eprintln!("blobary: {}", err);
-                Err(Sysexits::EX_IOERR)
-            }
-        },
     }
 }
 
@@ -92,10 +169,7 @@ fn open_store_from_memory_url(
     _url: Url,
     writable: bool,
 ) -> Result<Box<dyn IndexedBlobStore>, Sysexits> {
-    Ok(Box::new(EphemeralBlobStore::new(BlobStoreOptions {
-        writable,
-        filters: vec![],
-    })))
+    Ok(Box::new(EphemeralBlobStore::new(store_options(writable)?)?))
 }
 
 #[cfg(feature = "redis")]
@@ -103,13 +177,7 @@ fn open_store_from_redis_url(
     url: Url,
     writable: bool,
 ) -> Result<Box<dyn IndexedBlobStore>, Sysexits> {
-    match blobary::redis::RedisBlobStore::open(
-        url,
-        BlobStoreOptions {
-            writable,
-            filters: vec![],
-        },
-    ) {
+    match blobary::redis::RedisBlobStore::open(url, store_options(writable)?) {
         Ok(store) => Ok(Box::new(store)),
         Err(err) => {
             eprintln!("blobary: {}", err);
@@ -120,6 +188,7 @@ fn open_store_from_redis_url(
 
 #[cfg(feature = "s3")]
 fn open_store_from_s3_url(url: Url, writable: bool) -> Result<Box<dyn IndexedBlobStore>, Sysexits> {
+    use blobary::s3::S3BlobStore;
     let url_path = url.path();
     let bucket_name = url.host_str().unwrap();
     let bucket_prefix = match url_path.chars().last() {
@@ -127,13 +196,18 @@ fn open_store_from_s3_url(url: Url, writable: bool) -> Result<Box<dyn IndexedBlo
         Some('/') => &url_path[..url_path.len() - 1],
         _ => url_path,
     };
-    match blobary::s3::S3BlobStore::open(
+    let s3_options = match parse_s3_options(&url) {
+        Ok(s3_options) => s3_options,
+        Err(message) => {
+            eprintln!("blobary: {}", message);
+            return Err(Sysexits::EX_DATAERR);
+        }
+    };
+    match S3BlobStore::open_with_options(
         bucket_name,
         bucket_prefix,
-        BlobStoreOptions {
-            writable,
-            filters: vec![],
-        },
+        s3_options,
+        store_options(writable)?,
     ) {
         Ok(store) => Ok(Box::new(store)),
         Err(err) => {
@@ -143,10 +217,129 @@ fn open_store_from_s3_url(url: Url, writable: bool) -> Result<Box<dyn IndexedBlo
     }
 }
 
+/// Parses the S3 settings of a URL: the credentials in its userinfo, and the
+/// `region`, `endpoint`, `path_style`, `profile`, `credentials`, and
+/// `session_token` query parameters.
+#[cfg(feature = "s3")]
+fn parse_s3_options(url: &Url) -> Result<blobary::s3::S3Options, String> {
+    use blobary::s3::{S3Credentials, S3Options};
+    use percent_encoding::percent_decode_str;
+    let mut s3_options = S3Options::new();
+    if !url.username().is_empty() {
+        // s3://ACCESS_KEY:SECRET_KEY@bucket/prefix
+        let decode = |input: &str| percent_decode_str(input).decode_utf8_lossy().to_string();
+        s3_options.credentials = S3Credentials::Static {
+            access_key: decode(url.username()),
+            secret_key: decode(url.password().unwrap_or_default()),
+            session_token: None,
+        };
+    }
+    let mut session_token = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "region" => s3_options.region = Some(value.to_string()),
+            "endpoint" => s3_options.endpoint = Some(value.to_string()),
+            "path_style" | "path-style" => s3_options.path_style = value != "false" && value != "0",
+            "profile" => s3_options.credentials = S3Credentials::Profile(Some(value.to_string())),
+            "credentials" => match value.as_ref() {
+                "default" => s3_options.credentials = S3Credentials::Default,
+                "env" => s3_options.credentials = S3Credentials::Environment,
+                "profile" => s3_options.credentials = S3Credentials::Profile(None),
+                "anonymous" => s3_options.credentials = S3Credentials::Anonymous,
+                _ => return Err(format!("BLOBARY_URL has unknown credentials: {}", value)),
+            },
+            "session_token" => session_token = Some(value.to_string()),
+            _ => return Err(format!("BLOBARY_URL has an unknown parameter: {}", key)),
+        }
+    }
+    if let Some(value) = session_token {
+        // Session tokens only go with the access keys they were issued for:
+        match &mut s3_options.credentials {
+            S3Credentials::Static { session_token, .. } => *session_token = Some(value),
+            _ => return Err("BLOBARY_URL has a session_token without access keys".to_string()),
+        }
+    }
+    Ok(s3_options)
+}
+
 #[cfg(feature = "sqlite")]
 fn open_store_from_sqlite_url(
-    _url: Url,
-    _writable: bool,
+    url: Url,
+    writable: bool,
 ) -> Result<Box<dyn IndexedBlobStore>, Sysexits> {
-    todo!("SQLite support not implemented yet") // TODO
+    match url.to_file_path() {
+        Err(_) => {
+            eprintln!("blobary: BLOBARY_URL contains an invalid path: {}", url);
+            Err(Sysexits::EX_DATAERR)
+        }
+        Ok(path) => {
+            match blobary::sqlite::SQLiteBlobStore::open_path(path, store_options(writable)?) {
+                Ok(store) => Ok(Box::new(store)),
+                Err(err) => {
+                    eprintln!("blobary: {}", err);
+                    Err(Sysexits::EX_IOERR)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "s3"))]
+mod test {
+    use super::*;
+    use blobary::s3::S3Credentials;
+
+    #[test]
+    fn test_parse_s3_options() {
+        let parse = |url: &str| parse_s3_options(&Url::parse(url).unwrap());
+
+        let s3_options = parse("s3://bucket/prefix").unwrap();
+        assert_eq!(s3_options.region, None);
+        assert_eq!(s3_options.endpoint, None);
+        assert!(!s3_options.path_style);
+        assert_eq!(s3_options.credentials, S3Credentials::Default);
+
+        let s3_options = parse(
+            "s3://AKID:se%2Fcret@bucket/prefix?region=eu-west-1&endpoint=http://localhost:9000&path_style=1&session_token=TOKEN",
+        )
+        .unwrap();
+        assert_eq!(s3_options.region.as_deref(), Some("eu-west-1"));
+        assert_eq!(
+            s3_options.endpoint.as_deref(),
+            Some("http://localhost:9000")
+        );
+        assert!(s3_options.path_style);
+        assert_eq!(
+            s3_options.credentials,
+            S3Credentials::Static {
+                access_key: "AKID".to_string(),
+                secret_key: "se/cret".to_string(),
+                session_token: Some("TOKEN".to_string()),
+            }
+        );
+        assert!(!parse("s3://bucket?path-style=false").unwrap().path_style);
+
+        let credentials = |url: &str| parse(url).unwrap().credentials;
+        assert_eq!(
+            credentials("s3://bucket?profile=work"),
+            S3Credentials::Profile(Some("work".to_string()))
+        );
+        assert_eq!(
+            credentials("s3://bucket?credentials=profile"),
+            S3Credentials::Profile(None)
+        );
+        assert_eq!(
+            credentials("s3://bucket?credentials=env"),
+            S3Credentials::Environment
+        );
+        assert_eq!(
+            credentials("s3://bucket?credentials=anonymous"),
+            S3Credentials::Anonymous
+        );
+
+        assert!(parse("s3://bucket?credentials=other").is_err());
+        assert!(parse("s3://bucket?unknown=1").is_err());
+        assert!(parse("s3://bucket?session_token=TOKEN").is_err());
+        assert!(parse("s3://AKID:SECRET@bucket?credentials=env&session_token=TOKEN").is_err());
+    }
 }
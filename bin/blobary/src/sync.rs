@@ -1,7 +1,7 @@
 // This is free and unencumbered software released into the public domain.
 
 use crate::{hash::encode_hash, sysexits::Sysexits, Options};
-use blobary::{IndexedBlobStore, IndexedBlobStoreIterator};
+use blobary::{BlobStoreIterator, IndexedBlobStore, IndexedBlobStoreIterator};
 use std::ops::DerefMut;
 
 pub fn copy_blobs(
@@ -11,9 +11,28 @@ pub fn copy_blobs(
 ) -> Result<usize, Sysexits> {
     let mutate_count: usize = 0;
 
+    if !source_store.is_indexed() {
+        for entry in BlobStoreIterator::new(source_store.as_ref()) {
+            let (blob_hash, _) = entry?;
+            if target_store.contains_hash(blob_hash)? {
+                continue;
+            }
+            let Some(blob) = source_store.get_by_hash(blob_hash)? else {
+                continue; // removed concurrently
+            };
+            let blob_data = blob.data.unwrap();
+            let mut blob_data = blob_data.lock().unwrap();
+            let (created, _) = target_store.put(&mut blob_data.deref_mut())?;
+            if created && (options.verbose || options.debug) {
+                println!("{}", encode_hash(blob.hash));
+            }
+        }
+        return Ok(mutate_count);
+    }
+
     for blob in IndexedBlobStoreIterator::new(source_store.deref_mut()) {
         let blob_data = blob.data.unwrap();
-        let mut blob_data = blob_data.borrow_mut();
+        let mut blob_data = blob_data.lock().unwrap();
 
         if target_store.contains_hash(blob.hash)? {
             continue;
@@ -27,3 +46,93 @@ pub fn copy_blobs(
 
     Ok(mutate_count)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use blobary::{
+        Blob, BlobHash, BlobID, BlobPage, BlobStore, BlobStoreError, BlobStoreExt,
+        BlobStoreOptions, EphemeralBlobStore, LIST_PAGE_SIZE,
+    };
+    use std::io::Read;
+
+    /// A store without lookups by store ID, like the S3 store.
+    struct UnindexedBlobStore(EphemeralBlobStore);
+
+    impl BlobStore for UnindexedBlobStore {
+        fn count(&self) -> blobary::Result<usize> {
+            self.0.count()
+        }
+
+        fn contains_hash(&self, blob_hash: BlobHash) -> blobary::Result<bool> {
+            self.0.contains_hash(blob_hash)
+        }
+
+        fn get_by_hash(&self, blob_hash: BlobHash) -> blobary::Result<Option<Blob>> {
+            self.0.get_by_hash(blob_hash)
+        }
+
+        fn put(&mut self, blob_data: &mut dyn Read) -> blobary::Result<(bool, Blob)> {
+            self.0.put(blob_data)
+        }
+
+        fn remove(&mut self, blob_hash: BlobHash) -> blobary::Result<bool> {
+            self.0.remove(blob_hash)
+        }
+
+        fn list(&self, token: Option<&str>) -> blobary::Result<BlobPage> {
+            self.0.list(token)
+        }
+    }
+
+    impl IndexedBlobStore for UnindexedBlobStore {
+        fn hash_to_id(&self, _blob_hash: BlobHash) -> blobary::Result<Option<BlobID>> {
+            Err(BlobStoreError::Unsupported)
+        }
+
+        fn id_to_hash(&self, _blob_id: BlobID) -> blobary::Result<Option<BlobHash>> {
+            Err(BlobStoreError::Unsupported)
+        }
+
+        fn get_by_id(&self, _blob_id: BlobID) -> blobary::Result<Option<Blob>> {
+            Err(BlobStoreError::Unsupported)
+        }
+
+        fn is_indexed(&self) -> bool {
+            false
+        }
+    }
+
+    impl BlobStoreExt for UnindexedBlobStore {}
+
+    #[test]
+    fn test_unindexed() {
+        let options = Options {
+            debug: false,
+            license: false,
+            verbose: false,
+            version: false,
+            read_only: false,
+            command: None,
+        };
+        let config = || BlobStoreOptions::default();
+        let mut source = UnindexedBlobStore(EphemeralBlobStore::new(config()).unwrap());
+        for n in 0..LIST_PAGE_SIZE + 1 {
+            source.put_string(n.to_string()).unwrap();
+        }
+        let mut source_store: Box<dyn IndexedBlobStore> = Box::new(source);
+        let mut target = EphemeralBlobStore::new(config()).unwrap();
+        target.put_string("0").unwrap();
+        let mut target_store: Box<dyn IndexedBlobStore> = Box::new(target);
+
+        // The blobs are copied page by page, skipping those already present:
+        assert!(copy_blobs(&mut source_store, &mut target_store, &options).is_ok());
+        assert_eq!(target_store.count().unwrap(), LIST_PAGE_SIZE + 1);
+        for entry in BlobStoreIterator::new(source_store.as_ref()) {
+            let (blob_hash, _) = entry.unwrap();
+            assert!(target_store.contains_hash(blob_hash).unwrap());
+        }
+        assert!(copy_blobs(&mut source_store, &mut target_store, &options).is_ok());
+        assert_eq!(target_store.count().unwrap(), LIST_PAGE_SIZE + 1);
+    }
+}
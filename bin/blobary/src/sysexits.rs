@@ -71,8 +71,9 @@ impl From<BlobStoreError> for Sysexits {
             Unimplemented(_) => Sysexits::EX_SOFTWARE,
             Unexpected => Sysexits::EX_TEMPFAIL,
             Removed => Sysexits::EX_DATAERR,
+            TooLarge(_) => Sysexits::EX_DATAERR,
             IO(err) => err.into(),
-            Other(err) => err.into(),
+            Other(err) => (err as Box<dyn std::error::Error>).into(),
         }
     }
 }
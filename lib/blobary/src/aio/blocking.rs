@@ -0,0 +1,333 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::{
+    AsyncBlob, AsyncBlobStore, Blob, BlobData, BlobHash, BlobMetadata, BlobPage, BlobStore,
+    BlobStoreError, Result, SharedBlobStore, SyncBlobStore,
+};
+use async_trait::async_trait;
+use cap_std::{ambient_authority, fs::Dir};
+use cap_tempfile::TempFile;
+use std::{
+    future::Future,
+    io::{self, Seek, SeekFrom},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncWriteExt, ReadBuf},
+    task::{spawn_blocking, JoinHandle},
+};
+
+/// The most bytes read from the blocking blob data per task.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Adapts a blocking blob store for async use by running its operations on
+/// the Tokio blocking thread pool.
+///
+/// This is how the directory, file, and SQLite stores are used from async
+/// code. Redis and S3 have native async stores instead.
+pub struct BlockingBlobStore<S> {
+    store: SharedBlobStore<S>,
+    max_blob_size: Option<u64>,
+}
+
+impl<S: SyncBlobStore + 'static> BlockingBlobStore<S> {
+    pub fn new(store: S) -> Self {
+        Self::from_shared(SharedBlobStore::new(store))
+    }
+
+    /// Wraps a store that is also used through its blocking interface.
+    pub fn from_shared(store: SharedBlobStore<S>) -> Self {
+        let max_blob_size = store.max_blob_size();
+        Self {
+            store,
+            max_blob_size,
+        }
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(SharedBlobStore<S>) -> Result<T> + Send + 'static,
+    {
+        let store = self.store.clone();
+        spawn_blocking(move || f(store))
+            .await
+            .map_err(|err| BlobStoreError::Other(Box::new(err)))?
+    }
+}
+
+#[async_trait]
+impl<S: SyncBlobStore + 'static> AsyncBlobStore for BlockingBlobStore<S> {
+    async fn count(&self) -> Result<usize> {
+        self.run(|store| store.count()).await
+    }
+
+    async fn contains_hash(&self, blob_hash: BlobHash) -> Result<bool> {
+        self.run(move |store| store.contains_hash(blob_hash)).await
+    }
+
+    async fn get_by_hash(&self, blob_hash: BlobHash) -> Result<Option<AsyncBlob>> {
+        let blob = self.run(move |store| store.get_by_hash(blob_hash)).await?;
+        match blob {
+            None => Ok(None),
+            Some(Blob {
+                id,
+                hash,
+                size,
+                data: Some(data),
+                metadata,
+            }) => Ok(Some(AsyncBlob {
+                id,
+                hash,
+                size,
+                data: Box::new(BlockingBlobData::new(data)),
+                metadata,
+            })),
+            Some(_) => Err(BlobStoreError::Unexpected),
+        }
+    }
+
+    async fn put(&self, blob_data: &mut (dyn AsyncRead + Send + Unpin)) -> Result<(bool, Blob)> {
+        self.put_with_metadata(blob_data, BlobMetadata::new()).await
+    }
+
+    async fn put_with_metadata(
+        &self,
+        blob_data: &mut (dyn AsyncRead + Send + Unpin),
+        metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
+        // Spool the blob data to a temporary file that the blocking store
+        // can read from, refusing blobs larger than the store accepts:
+        let temp_file = spawn_blocking(|| -> io::Result<std::fs::File> {
+            let temp_dir = Dir::open_ambient_dir(std::env::temp_dir(), ambient_authority())?;
+            Ok(TempFile::new_anonymous(&temp_dir)?.into_std())
+        })
+        .await
+        .map_err(|err| BlobStoreError::Other(Box::new(err)))??;
+        let mut temp_file = tokio::fs::File::from_std(temp_file);
+        let limit = self
+            .max_blob_size
+            .map_or(u64::MAX, |max_size| max_size.saturating_add(1));
+        let blob_size = tokio::io::copy(&mut blob_data.take(limit), &mut temp_file).await?;
+        if let Some(max_size) = self.max_blob_size.filter(|&max_size| blob_size > max_size) {
+            return Err(BlobStoreError::TooLarge(max_size));
+        }
+        temp_file.flush().await?;
+        let mut temp_file = temp_file.into_std().await;
+
+        self.run(move |mut store| {
+            temp_file.rewind()?;
+            store.put_with_metadata(&mut temp_file, metadata)
+        })
+        .await
+    }
+
+    async fn remove(&self, blob_hash: BlobHash) -> Result<bool> {
+        self.run(move |mut store| store.remove(blob_hash)).await
+    }
+
+    async fn list(&self, token: Option<&str>) -> Result<BlobPage> {
+        let token = token.map(|token| token.to_string());
+        self.run(move |store| store.list(token.as_deref())).await
+    }
+
+    async fn get_range(
+        &self,
+        blob_hash: BlobHash,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        self.run(move |store| store.get_range(blob_hash, offset, len))
+            .await
+    }
+
+    async fn get_metadata(&self, blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        self.run(move |store| store.get_metadata(blob_hash)).await
+    }
+}
+
+/// Reads blocking blob data asynchronously, one blocking task at a time.
+pub struct BlockingBlobData {
+    data: Arc<Mutex<dyn BlobData>>,
+    pending: Option<JoinHandle<Operation>>,
+    buffer: Vec<u8>, // read ahead of `pos`
+    pos: u64,
+}
+
+enum Operation {
+    Read(io::Result<Vec<u8>>),
+    Seek(io::Result<u64>),
+}
+
+impl BlockingBlobData {
+    pub fn new(data: Arc<Mutex<dyn BlobData>>) -> Self {
+        Self {
+            data,
+            pending: None,
+            buffer: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Waits for the pending operation, if any, to finish.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<Operation>>> {
+        let Some(pending) = self.pending.as_mut() else {
+            return Poll::Ready(Ok(None));
+        };
+        let result = ready!(Pin::new(pending).poll(cx));
+        self.pending = None;
+        Poll::Ready(
+            result
+                .map(Some)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err)),
+        )
+    }
+}
+
+impl AsyncRead for BlockingBlobData {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.buffer.is_empty() {
+                let len = buf.remaining().min(this.buffer.len());
+                buf.put_slice(&this.buffer[..len]);
+                this.buffer.drain(..len);
+                this.pos += len as u64;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.poll_pending(cx))? {
+                Some(Operation::Read(result)) => {
+                    this.buffer = result?;
+                    if this.buffer.is_empty() {
+                        return Poll::Ready(Ok(())); // end of blob
+                    }
+                }
+                Some(Operation::Seek(result)) => this.pos = result?,
+                None => {
+                    let data = this.data.clone();
+                    let len = buf.remaining().min(READ_SIZE);
+                    this.pending = Some(spawn_blocking(move || {
+                        let mut buffer = vec![0u8; len];
+                        let result = data.lock().unwrap().read(&mut buffer);
+                        Operation::Read(result.map(|len| {
+                            buffer.truncate(len);
+                            buffer
+                        }))
+                    }));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for BlockingBlobData {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        if this.pending.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "other blob data operation is pending",
+            ));
+        }
+        // The position accounts for data read ahead into the buffer:
+        let position = match position {
+            SeekFrom::Current(offset) => match this.pos.checked_add_signed(offset) {
+                Some(pos) => SeekFrom::Start(pos),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "invalid seek to a negative position",
+                    ))
+                }
+            },
+            position => position,
+        };
+        this.buffer.clear();
+        let data = this.data.clone();
+        this.pending = Some(spawn_blocking(move || {
+            Operation::Seek(data.lock().unwrap().seek(position))
+        }));
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        loop {
+            match ready!(this.poll_pending(cx))? {
+                None => return Poll::Ready(Ok(this.pos)),
+                Some(Operation::Seek(result)) => {
+                    this.pos = result?;
+                    return Poll::Ready(Ok(this.pos));
+                }
+                Some(Operation::Read(result)) => this.buffer = result?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BlobStoreOptions, DirectoryBlobStore};
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    #[test]
+    fn test() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+            let store =
+                DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+            let store = BlockingBlobStore::new(store);
+
+            let text = "Foo".repeat(100_000);
+            let metadata = BlobMetadata::new().name("foo.txt");
+            let (created, foo) = store
+                .put_with_metadata(&mut text.as_bytes(), metadata)
+                .await
+                .unwrap();
+            assert!(created);
+            assert_eq!(foo.size, text.len() as u64);
+            assert_eq!(store.count().await.unwrap(), 1);
+            assert!(store.contains_hash(foo.hash).await.unwrap());
+
+            let mut blob = store.get_by_hash(foo.hash).await.unwrap().unwrap();
+            let metadata = blob.metadata.take().unwrap();
+            assert_eq!(metadata.name.as_deref(), Some("foo.txt"));
+            assert_eq!(store.get_metadata(foo.hash).await.unwrap(), Some(metadata));
+            let mut buffer = String::new();
+            blob.data.read_to_string(&mut buffer).await.unwrap();
+            assert_eq!(buffer, text);
+            blob.data.seek(SeekFrom::Start(1)).await.unwrap();
+            let mut buffer = [0u8; 3];
+            blob.data.read_exact(&mut buffer).await.unwrap();
+            assert_eq!(&buffer, b"ooF");
+            assert_eq!(blob.data.stream_position().await.unwrap(), 4);
+
+            let range = store.get_range(foo.hash, 2, 4).await.unwrap();
+            assert_eq!(range.unwrap(), b"oFoo");
+            assert_eq!(store.list(None).await.unwrap().blobs.len(), 1);
+            assert!(store.remove(foo.hash).await.unwrap());
+            assert!(store.get_by_hash(foo.hash).await.unwrap().is_none());
+
+            // Oversized blobs are refused while they are spooled:
+            let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+            let config = BlobStoreOptions::default().max_blob_size(Some(3));
+            let store = DirectoryBlobStore::open_tempdir(&temp_dir, config).unwrap();
+            let store = BlockingBlobStore::new(store);
+            assert!(matches!(
+                store.put(&mut text.as_bytes()).await,
+                Err(BlobStoreError::TooLarge(3))
+            ));
+            assert!(store.put(&mut &b"Foo"[..]).await.unwrap().0);
+        });
+    }
+}
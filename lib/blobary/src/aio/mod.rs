@@ -0,0 +1,7 @@
+// This is free and unencumbered software released into the public domain.
+
+mod blocking;
+mod store;
+
+pub use blocking::*;
+pub use store::*;
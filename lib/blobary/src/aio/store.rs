@@ -0,0 +1,84 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::{Blob, BlobHash, BlobID, BlobMetadata, BlobPage, Result};
+use async_trait::async_trait;
+use std::io::SeekFrom;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+/// Blob data that can be read and seeked asynchronously.
+pub trait AsyncBlobData: AsyncRead + AsyncSeek + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncSeek + Send + Unpin + ?Sized> AsyncBlobData for T {}
+
+/// A blob fetched from an [`AsyncBlobStore`].
+pub struct AsyncBlob {
+    /// The blob's store ID, or [`NO_BLOB_ID`](crate::NO_BLOB_ID) if the store
+    /// doesn't assign IDs.
+    pub id: BlobID,
+    pub hash: BlobHash,
+    pub size: u64,
+    pub data: Box<dyn AsyncBlobData>,
+    /// The metadata recorded when the blob was stored, if the store keeps it.
+    pub metadata: Option<BlobMetadata>,
+}
+
+/// The asynchronous counterpart of [`BlobStore`](crate::BlobStore).
+///
+/// Unlike the blocking trait, writes take `&self` as well, so that a single
+/// store can be shared between tasks.
+#[async_trait]
+pub trait AsyncBlobStore: Send + Sync {
+    /// Returns the number of blobs in this store.
+    async fn count(&self) -> Result<usize>;
+
+    /// Determines if the store contains a blob with the given BLAKE3 hash.
+    async fn contains_hash(&self, blob_hash: BlobHash) -> Result<bool>;
+
+    /// Fetches a blob by its BLAKE3 hash.
+    async fn get_by_hash(&self, blob_hash: BlobHash) -> Result<Option<AsyncBlob>>;
+
+    /// Stores a blob and returns its metadata.
+    async fn put(&self, blob_data: &mut (dyn AsyncRead + Send + Unpin)) -> Result<(bool, Blob)>;
+
+    /// Stores a blob along with a metadata record, if the store keeps them.
+    /// See [`BlobStore::put_with_metadata`](crate::BlobStore::put_with_metadata).
+    async fn put_with_metadata(
+        &self,
+        blob_data: &mut (dyn AsyncRead + Send + Unpin),
+        _metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
+        self.put(blob_data).await
+    }
+
+    /// Removes a blob by its BLAKE3 hash.
+    async fn remove(&self, blob_hash: BlobHash) -> Result<bool>;
+
+    /// Lists the hashes and sizes of blobs in the store, a page at a time,
+    /// starting from the given continuation token. Stores that list by
+    /// scanning, such as Redis, may list a blob more than once.
+    async fn list(&self, token: Option<&str>) -> Result<BlobPage>;
+
+    /// Reads up to `len` bytes of a blob starting at `offset`, stopping at
+    /// the end of the blob.
+    async fn get_range(
+        &self,
+        blob_hash: BlobHash,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        match self.get_by_hash(blob_hash).await? {
+            None => Ok(None),
+            Some(mut blob) => {
+                let mut buffer = Vec::new();
+                blob.data.seek(SeekFrom::Start(offset)).await?;
+                (&mut blob.data).take(len).read_to_end(&mut buffer).await?;
+                Ok(Some(buffer))
+            }
+        }
+    }
+
+    /// Fetches the metadata record kept for a blob, if the store keeps them.
+    async fn get_metadata(&self, _blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        Ok(None)
+    }
+}
@@ -1,10 +1,9 @@
 // This is free and unencumbered software released into the public domain.
 
-use crate::{error::BlobHashError, BlobHasher};
+use crate::{error::BlobHashError, BlobHasher, BlobMetadata, MIME_SNIFF_SIZE};
 use std::{
-    cell::RefCell,
     io::{Cursor, Read, Result, Seek, SeekFrom, Write},
-    rc::Rc,
+    sync::{Arc, Mutex},
 };
 
 pub const BLOB_HASH_LEN: usize = 32;
@@ -14,6 +13,11 @@ pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
 #[allow(unused)]
 pub type BlobID = usize;
 
+/// The ID of blobs in stores that don't assign IDs, for which
+/// [`is_indexed`](crate::IndexedBlobStore::is_indexed) is false. Assigned
+/// IDs start at 1.
+pub const NO_BLOB_ID: BlobID = 0;
+
 /// The blob's globally-unique cryptographic BLAKE3 hash.
 #[allow(unused)]
 #[derive(Clone, Copy, Eq, Hash, PartialEq)]
@@ -109,14 +113,19 @@ impl std::fmt::Display for BlobHash {
 /// TODO
 #[derive(Clone)]
 pub struct Blob {
+    /// The blob's store ID, or [`NO_BLOB_ID`] if the store doesn't assign IDs.
     pub id: BlobID,
     pub hash: BlobHash,
     pub size: u64,
-    pub data: Option<Rc<RefCell<dyn BlobData>>>,
+    pub data: Option<Arc<Mutex<dyn BlobData>>>,
+    /// The metadata recorded when the blob was stored, if the store keeps it.
+    pub metadata: Option<BlobMetadata>,
 }
 
 /// A blob is a unique byte sequence of data.
-pub trait BlobData: Seek + Read {
+///
+/// Blob data is `Send`, so that blobs can be handed across threads.
+pub trait BlobData: Seek + Read + Send {
     /// Returns the blob's byte size.
     #[allow(clippy::seek_from_current)]
     fn size(&mut self) -> Result<u64> {
@@ -137,9 +146,9 @@ pub trait BlobData: Seek + Read {
         if pos != 0 {
             self.seek(std::io::SeekFrom::Start(0))?;
         }
-        self.take(1024).read_to_end(&mut buffer)?;
+        self.take(MIME_SNIFF_SIZE as u64).read_to_end(&mut buffer)?;
         self.seek(std::io::SeekFrom::Start(pos))?;
-        Ok(infer::get(&buffer).map(|t| t.mime_type()))
+        Ok(BlobMetadata::detect_mime_type(&buffer))
     }
 
     /// Returns the blob's hash.
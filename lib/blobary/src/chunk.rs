@@ -0,0 +1,142 @@
+// This is free and unencumbered software released into the public domain.
+
+use fastcdc::v2020::{self, StreamCDC};
+use std::io::{self, Read, Result};
+
+/// Content-defined chunking settings.
+///
+/// Chunk boundaries are found with FastCDC, so inserting or removing bytes
+/// only changes the chunks around the edit, and the remaining chunks of
+/// similar content deduplicate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkerOptions {
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+impl ChunkerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns settings with the given average chunk size, a minimum of a
+    /// quarter of that, and a maximum of four times that.
+    pub fn with_avg_size(avg_size: u32) -> Self {
+        Self {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size.saturating_mul(4),
+        }
+    }
+
+    pub fn min_size(mut self, min_size: u32) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn avg_size(mut self, avg_size: u32) -> Self {
+        self.avg_size = avg_size;
+        self
+    }
+
+    pub fn max_size(mut self, max_size: u32) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Checks the sizes are ordered and within the supported bounds.
+    pub fn validate(&self) -> Result<()> {
+        let valid = (v2020::MINIMUM_MIN..=v2020::MINIMUM_MAX).contains(&self.min_size)
+            && (v2020::AVERAGE_MIN..=v2020::AVERAGE_MAX).contains(&self.avg_size)
+            && (v2020::MAXIMUM_MIN..=v2020::MAXIMUM_MAX).contains(&self.max_size)
+            && self.min_size <= self.avg_size
+            && self.avg_size <= self.max_size;
+        match valid {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid chunk sizes: min {}, avg {}, max {}",
+                    self.min_size, self.avg_size, self.max_size
+                ),
+            )),
+        }
+    }
+}
+
+impl Default for ChunkerOptions {
+    fn default() -> Self {
+        Self::with_avg_size(64 * 1024)
+    }
+}
+
+/// Splits the input into content-defined chunks.
+pub struct Chunker<'a> {
+    inner: StreamCDC<&'a mut dyn Read>,
+}
+
+impl<'a> Chunker<'a> {
+    pub fn new(input: &'a mut dyn Read, options: &ChunkerOptions) -> Result<Self> {
+        options.validate()?;
+        Ok(Self {
+            inner: StreamCDC::new(input, options.min_size, options.avg_size, options.max_size),
+        })
+    }
+}
+
+impl<'a> Iterator for Chunker<'a> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.inner.next()? {
+            Ok(chunk) => Some(Ok(chunk.data)),
+            Err(v2020::Error::Empty) => None,
+            Err(v2020::Error::IoError(err)) => Some(Err(err)),
+            Err(v2020::Error::Other(err)) => Some(Err(io::Error::new(io::ErrorKind::Other, err))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BlobStore, BlobStoreExt, BlobStoreOptions, EphemeralBlobStore, Manifest};
+
+    #[test]
+    fn test() {
+        let mut store = EphemeralBlobStore::new(BlobStoreOptions::default()).unwrap();
+        let options = ChunkerOptions::with_avg_size(1024);
+
+        // Pseudorandom content, so that chunk boundaries are content-defined:
+        let mut state = 0x2545F491u32;
+        let data: Vec<u8> = (0..64 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let (_, manifest_blob) = store.put_chunked(&mut &data[..], &options).unwrap();
+        let chunk_count = store.count().unwrap() - 1;
+        assert!(chunk_count > 1);
+
+        let mut output = Vec::new();
+        let size = store.get_chunked(manifest_blob.hash, &mut output).unwrap();
+        assert_eq!(size, Some(data.len() as u64));
+        assert_eq!(output, data);
+
+        // Inserting a byte only changes the chunks around it:
+        let mut edited = data.clone();
+        edited.insert(data.len() / 2, 42);
+        let (_, edited_blob) = store.put_chunked(&mut &edited[..], &options).unwrap();
+        let new_chunk_count = store.count().unwrap() - 2 - chunk_count;
+        assert!(new_chunk_count <= 2);
+
+        let edited_blob = store.get_by_hash(edited_blob.hash).unwrap().unwrap();
+        let edited_data = edited_blob.data.unwrap();
+        let manifest = Manifest::read_from(&mut *edited_data.lock().unwrap()).unwrap();
+        assert_eq!(manifest.size(), edited.len() as u64);
+    }
+}
@@ -1,6 +1,6 @@
 // This is free and unencumbered software released into the public domain.
 
-use crate::Filter;
+use crate::{Filter, FilterID};
 use std::io::{self, Read, Result, Write};
 
 #[cfg(feature = "gzip")]
@@ -8,6 +8,10 @@ pub struct GzipCompressor {}
 
 #[cfg(feature = "gzip")]
 impl Filter for GzipCompressor {
+    fn id(&self) -> FilterID {
+        FilterID::GZIP
+    }
+
     fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<u64> {
         use libflate::gzip;
         let header = gzip::HeaderBuilder::new().finish(); // TODO: set header options
@@ -31,6 +35,10 @@ pub struct Lz4Compressor {}
 
 #[cfg(feature = "lz4")]
 impl Filter for Lz4Compressor {
+    fn id(&self) -> FilterID {
+        FilterID::LZ4
+    }
+
     fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<u64> {
         use lz4_flex::frame;
         let mut encoder = frame::FrameEncoder::new(output);
@@ -46,3 +54,125 @@ impl Filter for Lz4Compressor {
         Ok(result)
     }
 }
+
+/// A Zstandard compressor, optionally using a dictionary trained on samples
+/// of similar blobs to improve the compression of small blobs.
+#[cfg(feature = "zstd")]
+pub struct ZstdCompressor {
+    level: i32,
+    dictionary: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdCompressor {
+    pub const DEFAULT_LEVEL: i32 = 3;
+
+    pub const fn new() -> Self {
+        Self {
+            level: Self::DEFAULT_LEVEL,
+            dictionary: None,
+        }
+    }
+
+    /// Sets the compression level, from 1 (fastest) to 22 (smallest).
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn dictionary(mut self, dictionary: Vec<u8>) -> Self {
+        self.dictionary = Some(dictionary);
+        self
+    }
+
+    /// Trains a dictionary of up to `max_size` bytes on the given samples.
+    pub fn train_dictionary(samples: &[impl AsRef<[u8]>], max_size: usize) -> Result<Vec<u8>> {
+        zstd::dict::from_samples(samples, max_size)
+    }
+
+    /// Returns the ID of the dictionary, if any, as found in its header.
+    pub fn dictionary_id(&self) -> Option<u32> {
+        match self.dictionary.as_deref() {
+            Some([0x37, 0xA4, 0x30, 0xEC, id @ ..]) if id.len() >= 4 => {
+                Some(u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Default for ZstdCompressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Filter for ZstdCompressor {
+    fn id(&self) -> FilterID {
+        FilterID::ZSTD
+    }
+
+    fn params(&self) -> Vec<u8> {
+        match self.dictionary_id() {
+            None => Vec::new(),
+            Some(dictionary_id) => dictionary_id.to_be_bytes().to_vec(),
+        }
+    }
+
+    fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<u64> {
+        use zstd::stream::write::Encoder;
+        let dictionary = self.dictionary.as_deref().unwrap_or_default();
+        let mut encoder = Encoder::with_dictionary(output, self.level, dictionary)?;
+        let result = io::copy(input, &mut encoder)?;
+        let _ = encoder.finish()?;
+        Ok(result)
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<u64> {
+        use zstd::stream::read::Decoder;
+        let dictionary = self.dictionary.as_deref().unwrap_or_default();
+        let mut decoder = Decoder::with_dictionary(io::BufReader::new(input), dictionary)?;
+        let result = io::copy(&mut decoder, output)?;
+        Ok(result)
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let samples: Vec<String> = (0..1000)
+            .map(|i| {
+                format!(
+                    r#"{{"id":{},"level":"info","message":"request {}"}}"#,
+                    i,
+                    i * 7
+                )
+            })
+            .collect();
+        let dictionary = ZstdCompressor::train_dictionary(&samples, 4096).unwrap();
+        let plain = ZstdCompressor::new().level(19);
+        let trained = ZstdCompressor::new().dictionary(dictionary);
+        assert!(plain.params().is_empty());
+        assert_eq!(trained.params().len(), 4);
+
+        let input = samples[42].as_bytes();
+        let mut plain_output = Vec::new();
+        plain.encode(&mut &input[..], &mut plain_output).unwrap();
+        let mut trained_output = Vec::new();
+        trained
+            .encode(&mut &input[..], &mut trained_output)
+            .unwrap();
+        assert!(trained_output.len() < plain_output.len());
+
+        for (filter, encoded) in [(plain, plain_output), (trained, trained_output)] {
+            let mut decoded = Vec::new();
+            filter.decode(&mut &encoded[..], &mut decoded).unwrap();
+            assert_eq!(decoded, input);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::{BlobData, BlobHash, BlobStore, BlobStoreError, Manifest};
+use std::{
+    io::{Error, ErrorKind, Read, Result, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+};
+
+/// A seekable view of the concatenation of several blobs, as described by a
+/// [`Manifest`], without copying their data.
+///
+/// Parts are fetched from the store only once a read reaches them, and only
+/// the part being read is kept open.
+pub struct ConcatBlobData<'a, S: BlobStore + ?Sized> {
+    store: &'a S,
+    parts: Vec<(BlobHash, u64)>,
+    offsets: Vec<u64>,
+    size: u64,
+    pos: u64,
+    current: Option<(usize, Arc<Mutex<dyn BlobData>>)>,
+}
+
+impl<'a, S: BlobStore + ?Sized> ConcatBlobData<'a, S> {
+    /// Constructs the concatenation of the blobs listed in the manifest.
+    pub fn new(store: &'a S, manifest: &Manifest) -> Self {
+        let mut offsets = Vec::with_capacity(manifest.parts.len());
+        let mut size = 0u64;
+        for (_, part_size) in &manifest.parts {
+            offsets.push(size);
+            size += part_size;
+        }
+        Self {
+            store,
+            parts: manifest.parts.clone(),
+            offsets,
+            size,
+            pos: 0,
+            current: None,
+        }
+    }
+
+    /// Returns the index of the part containing the given offset.
+    fn part_at(&self, pos: u64) -> usize {
+        self.offsets.partition_point(|&offset| offset <= pos) - 1
+    }
+
+    /// Fetches the given part from the store, unless it's already open.
+    fn open_part(&mut self, index: usize) -> Result<Arc<Mutex<dyn BlobData>>> {
+        if let Some((current_index, part)) = &self.current {
+            if *current_index == index {
+                return Ok(part.clone());
+            }
+        }
+        self.current = None;
+        let (blob_hash, blob_size) = self.parts[index];
+        let blob = self
+            .store
+            .get_by_hash(blob_hash)
+            .map_err(store_error)?
+            .ok_or_else(|| {
+                Error::new(ErrorKind::NotFound, format!("missing part {}", blob_hash))
+            })?;
+        if blob.size != blob_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("part {} has an unexpected size", blob_hash),
+            ));
+        }
+        let part = blob.data.unwrap();
+        self.current = Some((index, part.clone()));
+        Ok(part)
+    }
+}
+
+impl<'a, S: BlobStore + ?Sized> Read for ConcatBlobData<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.part_at(self.pos);
+        let part_pos = self.pos - self.offsets[index];
+        let remaining = self.parts[index].1 - part_pos;
+        let len = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+
+        let part = self.open_part(index)?;
+        let mut part = part.lock().unwrap();
+        part.seek(SeekFrom::Start(part_pos))?;
+        let len = part.read(&mut buf[..len])?;
+        if len == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "concatenated blob part is shorter than expected",
+            ));
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'a, S: BlobStore + ?Sized> Seek for ConcatBlobData<'a, S> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+impl<'a, S: BlobStore + Sync + ?Sized> BlobData for ConcatBlobData<'a, S> {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.size)
+    }
+}
+
+/// Passes a store error through a reader, to be unwrapped by the caller.
+fn store_error(error: BlobStoreError) -> Error {
+    match error {
+        BlobStoreError::IO(error) => error,
+        error => Error::new(ErrorKind::Other, error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{hash, BlobStoreExt, BlobStoreOptions, EphemeralBlobStore};
+
+    #[test]
+    fn test() {
+        let mut store = EphemeralBlobStore::new(BlobStoreOptions::default()).unwrap();
+        let mut manifest = Manifest::new();
+        for part in ["Foo", "", "Bar", "Baz"] {
+            let (_, blob) = store.put_string(part).unwrap();
+            manifest.push(blob.hash, blob.size);
+        }
+        let mut data = ConcatBlobData::new(&store, &manifest);
+        assert_eq!(data.size().unwrap(), 9);
+        assert_eq!(data.hash().unwrap(), hash("FooBarBaz"));
+
+        let mut buffer = String::new();
+        data.read_to_string(&mut buffer).unwrap();
+        assert_eq!(buffer, "FooBarBaz");
+
+        data.seek(SeekFrom::Start(4)).unwrap();
+        let mut buffer = [0u8; 4];
+        data.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"arBa");
+
+        data.seek(SeekFrom::End(-1)).unwrap();
+        let mut buffer = String::new();
+        data.read_to_string(&mut buffer).unwrap();
+        assert_eq!(buffer, "z");
+
+        // Parts are only fetched once reached, so a missing part only fails
+        // the reads that need it:
+        manifest.push(hash("Qux"), 3);
+        let mut data = ConcatBlobData::new(&store, &manifest);
+        let mut buffer = [0u8; 9];
+        data.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"FooBarBaz");
+        assert_eq!(
+            data.read(&mut buffer).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,221 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::{
+    walk_dir, BlobHash, BlobHasher, BlobID, BlobStore, BlobStoreError, DirectoryBlobStore, Result,
+    OUTBOARD_EXTENSION,
+};
+use rayon::prelude::*;
+use std::{
+    io::ErrorKind::NotFound,
+    path::{Path, PathBuf},
+};
+
+const QUARANTINE_DIR_NAME: &str = ".quarantine";
+
+/// A problem found by [`DirectoryBlobStore::check`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckIssue {
+    /// The file of an indexed blob is missing.
+    Missing { id: BlobID, hash: BlobHash },
+    /// The blob file can't be decoded with the configured filters.
+    Undecodable { id: BlobID, hash: BlobHash },
+    /// The blob's content differs in size from its index record.
+    SizeMismatch {
+        id: BlobID,
+        hash: BlobHash,
+        expected: u64,
+        actual: u64,
+    },
+    /// The blob's content doesn't match its hash.
+    HashMismatch { id: BlobID, hash: BlobHash },
+    /// A file that isn't accounted for by the index.
+    Unindexed { path: PathBuf },
+}
+
+/// What [`DirectoryBlobStore::check`] found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// The number of indexed blobs verified.
+    pub checked: usize,
+    /// The problems found, those of blobs in order of ID.
+    pub issues: Vec<CheckIssue>,
+}
+
+impl DirectoryBlobStore {
+    /// Verifies the integrity of the store, re-hashing every indexed blob in
+    /// parallel, and looking for files the index doesn't account for.
+    pub fn check(&self) -> Result<CheckReport> {
+        let blob_ids: Vec<BlobID> = (1..=self.record_count)
+            .filter(|blob_id| !self.removed_ids.contains(blob_id))
+            .collect();
+        let blob_issues = blob_ids
+            .par_iter()
+            .map(|blob_id| self.check_blob(*blob_id))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut issues: Vec<CheckIssue> = blob_issues.into_iter().flatten().collect();
+        for path in walk_dir(&self.dir, PathBuf::new())? {
+            if !self.is_indexed_path(&path) {
+                issues.push(CheckIssue::Unindexed { path });
+            }
+        }
+        Ok(CheckReport {
+            checked: blob_ids.len(),
+            issues,
+        })
+    }
+
+    fn check_blob(&self, blob_id: BlobID) -> Result<Option<CheckIssue>> {
+        let Some(record) = self.read_record(blob_id)? else {
+            return Ok(None);
+        };
+        let (id, hash) = (blob_id, BlobHash::from(record.0));
+        let blob_file = match self.dir.open(self.layout().encode_path(hash)) {
+            Ok(blob_file) => blob_file,
+            Err(err) if err.kind() == NotFound => {
+                return Ok(Some(CheckIssue::Missing { id, hash }))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let (actual, blob_data) = match self.decode_blob(blob_file) {
+            Ok(decoded) => decoded,
+            Err(BlobStoreError::IO(err)) if err.kind() != NotFound => {
+                return Ok(Some(CheckIssue::Undecodable { id, hash }))
+            }
+            Err(err) => return Err(err),
+        };
+        let expected = record.1.get();
+        if actual != expected {
+            return Ok(Some(CheckIssue::SizeMismatch {
+                id,
+                hash,
+                expected,
+                actual,
+            }));
+        }
+        let mut hasher = BlobHasher::new();
+        let mut blob_data = blob_data.lock().unwrap();
+        blob_data.rewind()?;
+        std::io::copy(&mut *blob_data, &mut hasher)?;
+        match hasher.finalize() == hash {
+            true => Ok(None),
+            false => Ok(Some(CheckIssue::HashMismatch { id, hash })),
+        }
+    }
+
+    /// Returns whether the file is that of a stored blob, or its outboard
+    /// file, in its place in the layout.
+    fn is_indexed_path(&self, path: &Path) -> bool {
+        let Some(blob_hash) = path
+            .file_stem()
+            .and_then(|stem| BlobHash::from_hex(stem.to_string_lossy().as_bytes()).ok())
+        else {
+            return false;
+        };
+        if self.lookup(blob_hash).is_none() {
+            return false;
+        }
+        let layout = self.layout();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            None => path == layout.encode_path(blob_hash),
+            Some(OUTBOARD_EXTENSION) => path == Self::outboard_path(layout, blob_hash),
+            Some(_) => false,
+        }
+    }
+
+    /// Moves the blob file into the quarantine directory, named after its
+    /// hash, and removes the blob from the store, so that it can be stored
+    /// again from an intact copy.
+    pub fn quarantine(&mut self, blob_hash: BlobHash) -> Result<bool> {
+        if !self.config.writable {
+            return Err(BlobStoreError::NotWritable);
+        }
+        if self.lookup(blob_hash).is_none() {
+            return Ok(false);
+        }
+        self.dir.create_dir_all(QUARANTINE_DIR_NAME)?;
+        let quarantine_path = Path::new(QUARANTINE_DIR_NAME).join(blob_hash.to_hex().as_str());
+        let blob_path = self.layout().encode_path(blob_hash);
+        match self.dir.rename(blob_path, &self.dir, quarantine_path) {
+            Ok(()) => (),
+            Err(err) if err.kind() == NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+        self.remove(blob_hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BlobStoreExt, BlobStoreOptions, DirectoryLayout};
+    use cap_std::ambient_authority;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let dir = temp_dir.open_dir(".").unwrap();
+        let config = BlobStoreOptions::default().outboard(true);
+        let mut store =
+            DirectoryBlobStore::open_dir_with_layout(dir, DirectoryLayout::FLAT, config).unwrap();
+        let (_, foo) = store.put_string("Foo").unwrap();
+        let (_, bar) = store.put_string("Bar").unwrap();
+        let (_, baz) = store.put_string("Baz").unwrap();
+        let (_, quux) = store.put_string("Quux").unwrap();
+        let report = store.check().unwrap();
+        assert_eq!(
+            report,
+            CheckReport {
+                checked: 4,
+                issues: vec![]
+            }
+        );
+
+        // Damage the blob files:
+        let path = |blob_hash: BlobHash| blob_hash.to_hex().to_string();
+        let writable = cap_std::fs::Permissions::from_std(std::fs::Permissions::from_mode(0o644));
+        for (blob_hash, data) in [(bar.hash, "Bad"), (baz.hash, "Bazz")] {
+            temp_dir
+                .set_permissions(path(blob_hash), writable.clone())
+                .unwrap();
+            temp_dir.write(path(blob_hash), data).unwrap();
+        }
+        temp_dir.remove_file(path(quux.hash)).unwrap();
+        temp_dir.write("stray", "Stray").unwrap();
+
+        let report = store.check().unwrap();
+        assert_eq!(report.checked, 4);
+        assert_eq!(
+            report.issues,
+            vec![
+                CheckIssue::HashMismatch {
+                    id: 2,
+                    hash: bar.hash
+                },
+                CheckIssue::SizeMismatch {
+                    id: 3,
+                    hash: baz.hash,
+                    expected: 3,
+                    actual: 4
+                },
+                CheckIssue::Missing {
+                    id: 4,
+                    hash: quux.hash
+                },
+                CheckIssue::Unindexed {
+                    path: PathBuf::from("stray")
+                },
+            ]
+        );
+
+        // Corrupt blobs can be quarantined and stored again:
+        assert!(store.quarantine(bar.hash).unwrap());
+        assert!(!store.contains_hash(bar.hash).unwrap());
+        assert!(temp_dir.exists(Path::new(QUARANTINE_DIR_NAME).join(path(bar.hash))));
+        let (created, _) = store.put_string("Bar").unwrap();
+        assert!(created);
+        assert!(store.get_by_hash(foo.hash).unwrap().is_some());
+        assert_eq!(store.check().unwrap().checked, 4);
+    }
+}
@@ -0,0 +1,308 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::{
+    BlobHash, BlobID, LookupHeader, LookupSlot, Result, LOOKUP_HEADER_SIZE, LOOKUP_MAGIC,
+    LOOKUP_SLOT_SIZE, LOOKUP_VERSION,
+};
+use cap_std::fs::Dir;
+use cap_tempfile::TempFile;
+use memmap2::{Mmap, MmapMut};
+use std::io::ErrorKind::NotFound;
+use zerocopy::{AsBytes, FromBytes};
+
+pub(crate) const LOOKUP_FILE_NAME: &str = ".lookup";
+
+/// The number of slots in a new lookup table.
+const MIN_SLOT_COUNT: u64 = 1024;
+
+/// The most changed slots to flush one by one, beyond which the whole table
+/// is flushed instead, as when rebuilding it.
+const MAX_DIRTY_SLOTS: usize = 64;
+
+/// A persistent hash table mapping blob hashes to store IDs, so that opening
+/// a directory store doesn't require reading its whole index.
+///
+/// The table is open-addressed with linear probing and kept at most half
+/// full, doubling in size as needed. It records how many index records have
+/// been entered into it, so that records appended by a process that died
+/// before updating the table are replayed when the store is next opened.
+///
+/// Read-only stores whose table is missing or behind the index work on a
+/// copy in memory instead.
+pub(crate) struct LookupTable {
+    map: LookupMap,
+    /// The offsets of slots changed since the table was last flushed.
+    dirty: Vec<usize>,
+}
+
+enum LookupMap {
+    Mapped(Mmap),
+    MappedMut(MmapMut),
+    Memory(Vec<u8>),
+}
+
+impl LookupMap {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Self::Mapped(map) => map,
+            Self::MappedMut(map) => map,
+            Self::Memory(buffer) => buffer,
+        }
+    }
+
+    /// Returns the table for writing, copying a read-only mapping into
+    /// memory first.
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        if let Self::Mapped(map) = self {
+            *self = Self::Memory(map.to_vec());
+        }
+        match self {
+            Self::Mapped(_) => unreachable!(),
+            Self::MappedMut(map) => map,
+            Self::Memory(buffer) => buffer,
+        }
+    }
+
+    /// Flushes the header and the slots at the given offsets, or the whole
+    /// table if there are many of them.
+    fn flush(&self, slot_offsets: &[usize]) -> Result<()> {
+        let Self::MappedMut(map) = self else {
+            return Ok(());
+        };
+        if slot_offsets.len() > MAX_DIRTY_SLOTS {
+            map.flush()?;
+            return Ok(());
+        }
+        map.flush_range(0, LOOKUP_HEADER_SIZE)?;
+        for &offset in slot_offsets {
+            map.flush_range(offset, LOOKUP_SLOT_SIZE)?;
+        }
+        Ok(())
+    }
+}
+
+impl LookupTable {
+    /// Opens the table file, returning `None` if it is missing or invalid,
+    /// in which case it needs to be rebuilt from the index.
+    pub fn open(dir: &Dir, writable: bool) -> Result<Option<Self>> {
+        let mut options = cap_std::fs::File::options();
+        let options = options.read(true).write(writable);
+        let file = match dir.open_with(LOOKUP_FILE_NAME, options) {
+            Ok(file) => file,
+            Err(err) if err.kind() == NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        // SAFETY: Like the rest of the directory store, the table assumes a
+        // single writer. The file is never truncated, but replaced by a new
+        // file when the table grows, so existing mappings stay valid.
+        let map = match writable {
+            true => LookupMap::MappedMut(unsafe { MmapMut::map_mut(&file)? }),
+            false => LookupMap::Mapped(unsafe { Mmap::map(&file)? }),
+        };
+        let table = Self {
+            map,
+            dirty: Vec::new(),
+        };
+        Ok(table.is_valid().then_some(table))
+    }
+
+    /// Creates an empty table, stored in the given directory, or else kept
+    /// in memory.
+    pub fn create(dir: Option<&Dir>) -> Result<Self> {
+        Self::with_slot_count(dir, MIN_SLOT_COUNT)
+    }
+
+    fn with_slot_count(dir: Option<&Dir>, slot_count: u64) -> Result<Self> {
+        let len = LOOKUP_HEADER_SIZE + slot_count as usize * LOOKUP_SLOT_SIZE;
+        let map = match dir {
+            None => LookupMap::Memory(vec![0u8; len]),
+            Some(dir) => {
+                let file = TempFile::new(dir)?;
+                file.as_file().set_len(len as u64)?;
+                // SAFETY: As above. Until its header is written, the new
+                // file is invalid and would be rebuilt.
+                let map = unsafe { MmapMut::map_mut(file.as_file())? };
+                file.replace(LOOKUP_FILE_NAME)?;
+                LookupMap::MappedMut(map)
+            }
+        };
+        let mut table = Self {
+            map,
+            dirty: Vec::new(),
+        };
+        table.set_header(LookupHeader {
+            magic: LOOKUP_MAGIC,
+            version: LOOKUP_VERSION.into(),
+            padding: [0u8; 4],
+            slot_count: slot_count.into(),
+            entry_count: 0.into(),
+            record_count: 0.into(),
+            reserved: [0u8; 24],
+        });
+        Ok(table)
+    }
+
+    fn is_valid(&self) -> bool {
+        let bytes = self.map.bytes();
+        if bytes.len() < LOOKUP_HEADER_SIZE {
+            return false;
+        }
+        let header = self.header();
+        let slot_count = header.slot_count.get();
+        header.magic == LOOKUP_MAGIC
+            && header.version.get() == LOOKUP_VERSION
+            && slot_count.is_power_of_two()
+            && slot_count
+                .checked_mul(LOOKUP_SLOT_SIZE as u64)
+                .and_then(|len| len.checked_add(LOOKUP_HEADER_SIZE as u64))
+                == Some(bytes.len() as u64)
+    }
+
+    fn header(&self) -> LookupHeader {
+        LookupHeader::read_from(&self.map.bytes()[..LOOKUP_HEADER_SIZE]).unwrap()
+    }
+
+    fn set_header(&mut self, header: LookupHeader) {
+        self.map.bytes_mut()[..LOOKUP_HEADER_SIZE].copy_from_slice(header.as_bytes());
+    }
+
+    fn slot(&self, index: u64) -> LookupSlot {
+        let offset = LOOKUP_HEADER_SIZE + index as usize * LOOKUP_SLOT_SIZE;
+        LookupSlot::read_from(&self.map.bytes()[offset..offset + LOOKUP_SLOT_SIZE]).unwrap()
+    }
+
+    fn set_slot(&mut self, index: u64, slot: LookupSlot) {
+        let offset = LOOKUP_HEADER_SIZE + index as usize * LOOKUP_SLOT_SIZE;
+        self.map.bytes_mut()[offset..offset + LOOKUP_SLOT_SIZE].copy_from_slice(slot.as_bytes());
+        if matches!(self.map, LookupMap::MappedMut(_)) && self.dirty.len() <= MAX_DIRTY_SLOTS {
+            self.dirty.push(offset);
+        }
+    }
+
+    /// Returns the slot where probing for the hash starts. BLAKE3 hashes are
+    /// uniformly distributed, so their leading bytes serve as is.
+    fn home_slot(blob_hash: BlobHash, slot_count: u64) -> u64 {
+        let hash_bytes: [u8; 32] = blob_hash.into();
+        u64::from_le_bytes(hash_bytes[..8].try_into().unwrap()) & (slot_count - 1)
+    }
+
+    /// Returns the number of index records entered into the table.
+    pub fn record_count(&self) -> usize {
+        self.header().record_count.get() as usize
+    }
+
+    /// Records the number of index records entered into the table, once the
+    /// entries themselves have been written out.
+    pub fn set_record_count(&mut self, record_count: usize) -> Result<()> {
+        self.map.flush(&self.dirty)?;
+        self.dirty.clear();
+        let mut header = self.header();
+        header.record_count = (record_count as u64).into();
+        self.set_header(header);
+        Ok(())
+    }
+
+    /// Looks up the store ID most recently entered for the hash.
+    pub fn get(&self, blob_hash: BlobHash) -> Option<BlobID> {
+        let slot_count = self.header().slot_count.get();
+        let hash_bytes: [u8; 32] = blob_hash.into();
+        let mut index = Self::home_slot(blob_hash, slot_count);
+        for _ in 0..slot_count {
+            let slot = self.slot(index);
+            match slot.1.get() {
+                0 => return None,
+                blob_id if slot.0 == hash_bytes => return Some(blob_id as BlobID),
+                _ => index = (index + 1) & (slot_count - 1),
+            }
+        }
+        None
+    }
+
+    /// Enters the store ID for the hash, replacing any previous ID, such as
+    /// that of a removed blob.
+    pub fn insert(&mut self, dir: &Dir, blob_hash: BlobHash, blob_id: BlobID) -> Result<()> {
+        let mut header = self.header();
+        if (header.entry_count.get() + 1) * 2 > header.slot_count.get() {
+            self.grow(dir)?;
+            header = self.header();
+        }
+        let slot_count = header.slot_count.get();
+        let hash_bytes: [u8; 32] = blob_hash.into();
+        let mut index = Self::home_slot(blob_hash, slot_count);
+        loop {
+            let slot = self.slot(index);
+            if slot.1.get() == 0 {
+                header.entry_count = (header.entry_count.get() + 1).into();
+                break;
+            }
+            if slot.0 == hash_bytes {
+                break;
+            }
+            index = (index + 1) & (slot_count - 1);
+        }
+        self.set_slot(index, LookupSlot(hash_bytes, (blob_id as u64).into()));
+        self.set_header(header);
+        Ok(())
+    }
+
+    /// Rehashes the entries into a table twice the size.
+    fn grow(&mut self, dir: &Dir) -> Result<()> {
+        let header = self.header();
+        let persistent = matches!(self.map, LookupMap::MappedMut(_));
+        let mut table =
+            Self::with_slot_count(persistent.then_some(dir), header.slot_count.get() * 2)?;
+        for index in 0..header.slot_count.get() {
+            let slot = self.slot(index);
+            if slot.1.get() != 0 {
+                table.insert(dir, BlobHash::from_bytes(slot.0), slot.1.get() as BlobID)?;
+            }
+        }
+        table.set_record_count(header.record_count.get() as usize)?;
+        *self = table;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hash;
+    use cap_std::ambient_authority;
+
+    #[test]
+    fn test() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        assert!(LookupTable::open(&temp_dir, true).unwrap().is_none());
+        let mut table = LookupTable::create(Some(&temp_dir)).unwrap();
+        let count = MIN_SLOT_COUNT as usize * 2;
+        for blob_id in 1..=count {
+            table
+                .insert(&temp_dir, hash(blob_id.to_string()), blob_id)
+                .unwrap();
+        }
+        table.insert(&temp_dir, hash("1"), count + 1).unwrap();
+        table.set_record_count(count + 1).unwrap();
+        drop(table);
+
+        let table = LookupTable::open(&temp_dir, false).unwrap().unwrap();
+        assert_eq!(table.record_count(), count + 1);
+        assert_eq!(table.header().entry_count.get(), count as u64);
+        assert_eq!(table.get(hash("1")), Some(count + 1));
+        assert_eq!(table.get(hash("2")), Some(2));
+        assert_eq!(table.get(hash(count.to_string())), Some(count));
+        assert_eq!(table.get(hash("Foo")), None);
+        drop(table);
+
+        let mut header = LookupHeader::read_from(
+            &temp_dir.read(LOOKUP_FILE_NAME).unwrap()[..LOOKUP_HEADER_SIZE],
+        )
+        .unwrap();
+        header.slot_count = (1u64 << 63).into();
+        let mut file = temp_dir
+            .open_with(LOOKUP_FILE_NAME, cap_std::fs::File::options().write(true))
+            .unwrap();
+        std::io::Write::write_all(&mut file, header.as_bytes()).unwrap();
+        drop(file);
+        assert!(LookupTable::open(&temp_dir, false).unwrap().is_none());
+    }
+}
@@ -0,0 +1,196 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::{
+    BlobHash, BlobID, BlobMetadata, BlobStoreError, MetadataEntryHeader, MetadataSlot, Result,
+    METADATA_ENTRY_HEADER_SIZE, METADATA_SLOT_SIZE,
+};
+use cap_std::fs::{Dir, File};
+use cap_tempfile::TempFile;
+use std::{
+    collections::HashMap,
+    io::{
+        ErrorKind::{NotFound, UnexpectedEof},
+        Write,
+    },
+    os::unix::prelude::FileExt,
+};
+use zerocopy::{AsBytes, FromBytes};
+
+pub(crate) const METADATA_FILE_NAME: &str = ".metadata";
+pub(crate) const METADATA_TABLE_FILE_NAME: &str = ".metaindex";
+
+/// The metadata records of a directory store: an append-only log of records
+/// tagged with their blob hashes, and a table of their offsets in the log
+/// keyed by store ID.
+///
+/// A record is appended to the log before its table slot is written, and
+/// the slot before the blob is indexed, so a crash leaves at worst a slot
+/// pointing at the record of another blob. Entries are therefore checked
+/// against the blob hash when read. Records of removed blobs remain in the
+/// log until the store is compacted.
+pub(crate) struct MetadataLog {
+    log_file: Option<File>,   // .metadata
+    table_file: Option<File>, // .metaindex
+}
+
+impl MetadataLog {
+    /// Opens the log and table files, creating them if the store is
+    /// writable. Read-only stores without them have no metadata.
+    pub fn open(dir: &Dir, writable: bool) -> Result<Self> {
+        Ok(Self {
+            log_file: open_file(dir, METADATA_FILE_NAME, writable)?,
+            table_file: open_file(dir, METADATA_TABLE_FILE_NAME, writable)?,
+        })
+    }
+
+    /// Reads the metadata recorded for the blob, if any.
+    pub fn get(&self, blob_id: BlobID, blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        match self.read_entry(blob_id, blob_hash)? {
+            None => Ok(None),
+            Some(entry) => Ok(Some(BlobMetadata::from_bytes(
+                &entry[METADATA_ENTRY_HEADER_SIZE..],
+            )?)),
+        }
+    }
+
+    /// Appends the blob's metadata to the log and records its offset under
+    /// the blob's store ID.
+    pub fn insert(
+        &mut self,
+        blob_id: BlobID,
+        blob_hash: BlobHash,
+        metadata: &BlobMetadata,
+    ) -> Result<()> {
+        let Some(log_file) = &self.log_file else {
+            return Err(BlobStoreError::NotWritable);
+        };
+        let record = metadata.to_bytes();
+        let record_len = u32::try_from(record.len()).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "blob metadata record too large",
+            )
+        })?;
+        let mut entry = Vec::with_capacity(METADATA_ENTRY_HEADER_SIZE + record.len());
+        entry
+            .extend_from_slice(MetadataEntryHeader(blob_hash.into(), record_len.into()).as_bytes());
+        entry.extend_from_slice(&record);
+
+        let offset = log_file.metadata()?.len();
+        log_file.write_all_at(&entry, offset)?;
+        log_file.sync_data()?;
+        self.set_offset(blob_id, Some(offset))
+    }
+
+    /// Records the offset of the blob's entry in the log, or that it has
+    /// none.
+    pub fn set_offset(&mut self, blob_id: BlobID, offset: Option<u64>) -> Result<()> {
+        let Some(table_file) = &self.table_file else {
+            return Err(BlobStoreError::NotWritable);
+        };
+        let slot = MetadataSlot(offset.map(|offset| offset + 1).unwrap_or(0).into());
+        table_file.write_all_at(slot.as_bytes(), slot_offset(blob_id))?;
+        table_file.sync_data()?;
+        Ok(())
+    }
+
+    /// Scans the log for the offset of each blob's latest entry, so that
+    /// the table can be rebuilt after the index is.
+    pub fn scan(&self) -> Result<HashMap<BlobHash, u64>> {
+        let mut offsets = HashMap::new();
+        let Some(log_file) = &self.log_file else {
+            return Ok(offsets);
+        };
+        let log_size = log_file.metadata()?.len();
+        let mut offset = 0;
+        let mut buffer = [0u8; METADATA_ENTRY_HEADER_SIZE];
+        while offset + METADATA_ENTRY_HEADER_SIZE as u64 <= log_size {
+            log_file.read_exact_at(&mut buffer, offset)?;
+            let header = MetadataEntryHeader::read_from(&buffer[..]).unwrap();
+            let next_offset = offset + METADATA_ENTRY_HEADER_SIZE as u64 + header.1.get() as u64;
+            if next_offset > log_size {
+                break; // torn by a crash
+            }
+            offsets.insert(header.0.into(), offset);
+            offset = next_offset;
+        }
+        Ok(offsets)
+    }
+
+    /// Replaces the log and table with ones holding only the entries of the
+    /// given blobs, renumbered in order, as when the index is compacted.
+    pub fn rewrite(&mut self, dir: &Dir, blobs: &[(BlobID, BlobHash)]) -> Result<()> {
+        let mut log_file = TempFile::new(dir)?;
+        let mut table_file = TempFile::new(dir)?;
+        let mut log_size = 0u64;
+        for (old_id, blob_hash) in blobs {
+            let slot = match self.read_entry(*old_id, *blob_hash)? {
+                None => MetadataSlot(0.into()),
+                Some(entry) => {
+                    log_file.write_all(&entry)?;
+                    log_size += entry.len() as u64;
+                    MetadataSlot((log_size - entry.len() as u64 + 1).into())
+                }
+            };
+            table_file.write_all(slot.as_bytes())?;
+        }
+        log_file.as_file().sync_all()?;
+        table_file.as_file().sync_all()?;
+        log_file.replace(METADATA_FILE_NAME)?;
+        table_file.replace(METADATA_TABLE_FILE_NAME)?;
+        *self = Self::open(dir, true)?;
+        Ok(())
+    }
+
+    /// Reads the blob's whole log entry, checking that it belongs to the
+    /// blob.
+    fn read_entry(&self, blob_id: BlobID, blob_hash: BlobHash) -> Result<Option<Vec<u8>>> {
+        let (Some(log_file), Some(table_file)) = (&self.log_file, &self.table_file) else {
+            return Ok(None);
+        };
+        if blob_id == 0 {
+            return Ok(None);
+        }
+        let mut buffer = [0u8; METADATA_SLOT_SIZE];
+        match table_file.read_exact_at(&mut buffer, slot_offset(blob_id)) {
+            Ok(()) => (),
+            Err(err) if err.kind() == UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let offset = match MetadataSlot::read_from(&buffer[..]).unwrap().0.get() {
+            0 => return Ok(None),
+            offset => offset - 1,
+        };
+
+        let mut entry = vec![0u8; METADATA_ENTRY_HEADER_SIZE];
+        match log_file.read_exact_at(&mut entry, offset) {
+            Ok(()) => (),
+            Err(err) if err.kind() == UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let header = MetadataEntryHeader::read_from(&entry[..]).unwrap();
+        if BlobHash::from(header.0) != blob_hash {
+            return Ok(None); // left behind by an interrupted put
+        }
+        entry.resize(METADATA_ENTRY_HEADER_SIZE + header.1.get() as usize, 0);
+        log_file.read_exact_at(
+            &mut entry[METADATA_ENTRY_HEADER_SIZE..],
+            offset + METADATA_ENTRY_HEADER_SIZE as u64,
+        )?;
+        Ok(Some(entry))
+    }
+}
+
+fn open_file(dir: &Dir, name: &str, writable: bool) -> Result<Option<File>> {
+    let mut options = File::options();
+    let options = options.read(true).write(writable).create(writable);
+    match dir.open_with(name, options) {
+        Ok(file) => Ok(Some(file)),
+        Err(err) if err.kind() == NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn slot_offset(blob_id: BlobID) -> u64 {
+    (blob_id as u64 - 1) * METADATA_SLOT_SIZE as u64
+}
@@ -1,11 +1,17 @@
 // This is free and unencumbered software released into the public domain.
 
+mod check;
 mod file;
+mod lookup;
+mod metadata;
 mod path;
 mod record;
 mod store;
 
+pub use check::*;
 pub use file::*;
+pub(crate) use lookup::*;
+pub(crate) use metadata::*;
 pub use path::*;
 pub use record::*;
 pub use store::*;
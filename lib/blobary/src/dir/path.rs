@@ -1,8 +1,94 @@
 // This is free and unencumbered software released into the public domain.
 
 use crate::BlobHash;
-use std::path::PathBuf;
+use std::{
+    fmt::{self, Display, Formatter},
+    path::PathBuf,
+    str::FromStr,
+};
 
 pub fn encode_into_path(blob_hash: BlobHash) -> PathBuf {
     PathBuf::from(blob_hash.to_hex().as_str())
 }
+
+/// How blob files are arranged within a directory store.
+///
+/// A fan-out layout nests each blob file under `levels` subdirectories named
+/// after successive `width`-character prefixes of its hex hash, so that with
+/// `levels = 2` and `width = 2` the blob `abcd…` is stored at `ab/cd/abcd…`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DirectoryLayout {
+    pub levels: u8,
+    pub width: u8,
+}
+
+impl DirectoryLayout {
+    /// All blob files directly in the store directory.
+    pub const FLAT: Self = Self {
+        levels: 0,
+        width: 0,
+    };
+
+    /// Returns a fan-out layout, or `None` if the prefixes would exceed the
+    /// length of a hex hash.
+    pub fn fanout(levels: u8, width: u8) -> Option<Self> {
+        match levels as usize * width as usize {
+            0 => Some(Self::FLAT),
+            n if n < 64 => Some(Self { levels, width }),
+            _ => None,
+        }
+    }
+
+    pub fn is_flat(&self) -> bool {
+        self.levels == 0 || self.width == 0
+    }
+
+    pub fn encode_path(&self, blob_hash: BlobHash) -> PathBuf {
+        let blob_hash = blob_hash.to_hex();
+        let blob_hash = blob_hash.as_str();
+        let mut path = PathBuf::new();
+        if !self.is_flat() {
+            let width = self.width as usize;
+            for level in 0..self.levels as usize {
+                path.push(&blob_hash[level * width..(level + 1) * width]);
+            }
+        }
+        path.push(blob_hash);
+        path
+    }
+}
+
+impl Default for DirectoryLayout {
+    fn default() -> Self {
+        Self {
+            levels: 2,
+            width: 2,
+        }
+    }
+}
+
+impl Display for DirectoryLayout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.is_flat() {
+            true => write!(f, "flat"),
+            false => write!(f, "fanout {} {}", self.levels, self.width),
+        }
+    }
+}
+
+impl FromStr for DirectoryLayout {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut words = input.split_whitespace();
+        match (words.next(), words.next(), words.next(), words.next()) {
+            (Some("flat"), None, None, None) => Ok(Self::FLAT),
+            (Some("fanout"), Some(levels), Some(width), None) => {
+                let levels = levels.parse().map_err(|_| ())?;
+                let width = width.parse().map_err(|_| ())?;
+                Self::fanout(levels, width).ok_or(())
+            }
+            _ => Err(()),
+        }
+    }
+}
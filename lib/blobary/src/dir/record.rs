@@ -1,11 +1,85 @@
 // This is free and unencumbered software released into the public domain.
 
+use crate::{DirectoryLayout, Filter, FilterID};
 use std::mem::size_of;
-use zerocopy::{byteorder::network_endian::U64, AsBytes};
+use uuid::Uuid;
+use zerocopy::{
+    byteorder::network_endian::{U32, U64},
+    AsBytes,
+};
 use zerocopy_derive::{AsBytes, FromBytes, FromZeroes, Unaligned};
 use zeroize::Zeroize;
 
 pub const RECORD_SIZE: usize = size_of::<PersistentBlobRecord>();
+pub const TOMBSTONE_SIZE: usize = size_of::<TombstoneRecord>();
+pub const INDEX_HEADER_SIZE: usize = size_of::<IndexHeader>();
+
+/// The magic bytes starting every versioned directory store index. Legacy
+/// indexes start directly with the first blob record.
+pub const INDEX_MAGIC: [u8; 8] = *b"\x89BLOBIDX";
+
+/// The current version of the directory store index format.
+pub const INDEX_VERSION: u32 = 1;
+
+/// The most filters recorded in the index header.
+pub const INDEX_MAX_FILTERS: usize = 8;
+
+/// The header of a versioned directory store index: the magic bytes, the
+/// format version, the store UUID, the blob file layout, and the IDs of the
+/// filters configured when the store was created, padded to 64 bytes.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub(crate) struct IndexHeader {
+    pub magic: [u8; 8],
+    pub version: U32,
+    pub uuid: [u8; 16],
+    pub layout_levels: u8,
+    pub layout_width: u8,
+    pub filter_count: u8,
+    pub filters: [u8; INDEX_MAX_FILTERS],
+    pub reserved: [u8; 25],
+}
+
+const _: () = assert!(size_of::<IndexHeader>() == 64, "sizeof(IndexHeader) == 64");
+
+impl IndexHeader {
+    /// Returns a header for a new index, with a fresh random UUID.
+    pub fn new(layout: DirectoryLayout, filters: &[Box<dyn Filter>]) -> Self {
+        let mut header = Self {
+            magic: INDEX_MAGIC,
+            version: INDEX_VERSION.into(),
+            uuid: *Uuid::new_v4().as_bytes(),
+            layout_levels: 0,
+            layout_width: 0,
+            filter_count: filters.len().min(INDEX_MAX_FILTERS) as u8,
+            filters: [0u8; INDEX_MAX_FILTERS],
+            reserved: [0u8; 25],
+        };
+        for (slot, filter) in header.filters.iter_mut().zip(filters) {
+            *slot = filter.id().0;
+        }
+        header.set_layout(layout);
+        header
+    }
+
+    /// Returns the recorded layout, or `None` if it is invalid.
+    pub fn layout(&self) -> Option<DirectoryLayout> {
+        DirectoryLayout::fanout(self.layout_levels, self.layout_width)
+    }
+
+    pub fn set_layout(&mut self, layout: DirectoryLayout) {
+        self.layout_levels = layout.levels;
+        self.layout_width = layout.width;
+    }
+
+    pub fn filter_ids(&self) -> Vec<FilterID> {
+        let filter_count = (self.filter_count as usize).min(INDEX_MAX_FILTERS);
+        self.filters[..filter_count]
+            .iter()
+            .map(|id| FilterID(*id))
+            .collect()
+    }
+}
 
 #[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
@@ -21,3 +95,72 @@ impl Zeroize for PersistentBlobRecord {
         self.as_bytes_mut().zeroize();
     }
 }
+
+/// A removal marker: the store ID of a removed blob.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub(crate) struct TombstoneRecord(pub U64);
+
+const _: () = assert!(
+    size_of::<TombstoneRecord>() == 8,
+    "sizeof(TombstoneRecord) == 8"
+);
+
+pub const LOOKUP_HEADER_SIZE: usize = size_of::<LookupHeader>();
+pub const LOOKUP_SLOT_SIZE: usize = size_of::<LookupSlot>();
+
+/// The magic bytes starting every directory store lookup table.
+pub const LOOKUP_MAGIC: [u8; 8] = *b"\x89BLOBLKP";
+
+/// The current version of the lookup table format.
+pub const LOOKUP_VERSION: u32 = 1;
+
+/// The header of a lookup table: the magic bytes, the format version, the
+/// number of slots and of occupied slots, and the number of index records
+/// entered into the table, padded to 64 bytes.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub(crate) struct LookupHeader {
+    pub magic: [u8; 8],
+    pub version: U32,
+    pub padding: [u8; 4],
+    pub slot_count: U64,
+    pub entry_count: U64,
+    pub record_count: U64,
+    pub reserved: [u8; 24],
+}
+
+const _: () = assert!(
+    size_of::<LookupHeader>() == 64,
+    "sizeof(LookupHeader) == 64"
+);
+
+/// A lookup table slot: a blob hash and its store ID, which is zero for an
+/// empty slot.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub(crate) struct LookupSlot(pub [u8; 32], pub U64);
+
+const _: () = assert!(size_of::<LookupSlot>() == 40, "sizeof(LookupSlot) == 40");
+
+pub const METADATA_ENTRY_HEADER_SIZE: usize = size_of::<MetadataEntryHeader>();
+pub const METADATA_SLOT_SIZE: usize = size_of::<MetadataSlot>();
+
+/// The header of a metadata log entry: the blob hash, and the length of the
+/// metadata record that follows.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub(crate) struct MetadataEntryHeader(pub [u8; 32], pub U32);
+
+const _: () = assert!(
+    size_of::<MetadataEntryHeader>() == 36,
+    "sizeof(MetadataEntryHeader) == 36"
+);
+
+/// A metadata table slot: one more than the offset of a blob's entry in the
+/// metadata log, or zero if the blob has none.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub(crate) struct MetadataSlot(pub U64);
+
+const _: () = assert!(size_of::<MetadataSlot>() == 8, "sizeof(MetadataSlot) == 8");
@@ -1,8 +1,12 @@
 // This is free and unencumbered software released into the public domain.
 
 use crate::{
-    encode_into_path, Blob, BlobHash, BlobHasher, BlobID, BlobStore, BlobStoreError, BlobStoreExt,
-    BlobStoreOptions, File, IndexedBlobStore, PersistentBlobRecord, Result, RECORD_SIZE,
+    builtin_filter, decode_into_tempfile, encode_into_tempfile, parse_id_token, read_range, Blob,
+    BlobData, BlobHash, BlobHasher, BlobID, BlobMetadata, BlobPage, BlobStore, BlobStoreError,
+    BlobStoreExt, BlobStoreOptions, DirectoryLayout, Envelope, File, FilterID, HashingReader,
+    IndexHeader, IndexedBlobStore, LookupTable, MetadataLog, Outboard, PersistentBlobRecord,
+    Result, TombstoneRecord, ENVELOPE_MAGIC, INDEX_HEADER_SIZE, INDEX_MAGIC, INDEX_VERSION,
+    LIST_PAGE_SIZE, LOOKUP_FILE_NAME, RECORD_SIZE, TOMBSTONE_SIZE,
 };
 use cap_std::{
     ambient_authority,
@@ -10,24 +14,72 @@ use cap_std::{
 };
 use cap_tempfile::{TempDir, TempFile};
 use std::{
-    cell::RefCell,
-    collections::HashMap,
+    collections::HashSet,
     fs::create_dir_all,
-    io::{Cursor, ErrorKind::UnexpectedEof, Read, Seek, SeekFrom, Write},
-    os::unix::prelude::PermissionsExt,
-    path::Path,
-    rc::Rc,
+    io::{
+        ErrorKind::{NotFound, UnexpectedEof},
+        Read, Seek, SeekFrom, Write,
+    },
+    os::unix::prelude::{FileExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
+use uuid::Uuid;
 use zerocopy::{AsBytes, FromBytes};
 
 const STORE_DIR_NAME: &str = ".blobary";
 const INDEX_FILE_NAME: &str = ".index";
+const LAYOUT_FILE_NAME: &str = ".layout";
+const TOMBSTONE_FILE_NAME: &str = ".removed";
+const COMPACT_FILE_NAME: &str = ".compacting";
+pub(crate) const OUTBOARD_EXTENSION: &str = "obao";
+
+/// What [`DirectoryBlobStore::repair`] found and fixed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// The blobs whose files were missing from the index, in their new order.
+    pub reindexed: Vec<BlobHash>,
+    /// Whether the index was empty or lost, and has been rebuilt.
+    pub rebuilt_index: bool,
+    /// The number of leftover files of removed or unstored blobs deleted.
+    pub removed_files: usize,
+    /// The number of abandoned temporary files deleted.
+    pub removed_temp_files: usize,
+    /// Files named after a hash that their content doesn't match, which
+    /// are left in place.
+    pub corrupt_files: Vec<PathBuf>,
+}
+
+/// What [`DirectoryBlobStore::compact`] did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactReport {
+    /// The old and new IDs of the blobs that were renumbered, in order.
+    pub id_map: Vec<(BlobID, BlobID)>,
+    /// The number of blob files re-encoded with the configured filters.
+    pub reencoded: usize,
+    /// The combined size of the rewritten files before compaction.
+    pub old_size: u64,
+    /// The combined size of the rewritten files after compaction.
+    pub new_size: u64,
+}
+
+impl CompactReport {
+    /// Returns the number of bytes reclaimed, if any.
+    pub fn reclaimed(&self) -> u64 {
+        self.old_size.saturating_sub(self.new_size)
+    }
+}
 
 pub struct DirectoryBlobStore {
     pub(crate) config: BlobStoreOptions,
-    dir: Dir,                           // .blobary
-    index_file: RefCell<Box<dyn File>>, // .blobary/index
-    lookup_id: HashMap<BlobHash, BlobID>,
+    pub(crate) dir: Dir,                     // .blobary
+    index_file: Mutex<Box<dyn File>>,        // .blobary/index
+    header: Option<IndexHeader>,             // none for legacy indexes
+    layout: DirectoryLayout,                 // .blobary/.layout for legacy indexes
+    lookup: LookupTable,                     // .blobary/.lookup
+    metadata: MetadataLog,                   // .blobary/.metadata
+    pub(crate) removed_ids: HashSet<BlobID>, // .blobary/.removed
+    pub(crate) record_count: usize,
 }
 
 impl DirectoryBlobStore {
@@ -47,45 +99,592 @@ impl DirectoryBlobStore {
     }
 
     pub fn open_dir(dir: Dir, config: BlobStoreOptions) -> Result<Self> {
-        let mut index_options = cap_std::fs::File::options();
-        let index_options = index_options
-            .create(config.writable)
-            .read(true)
-            .append(true);
-
-        // Open the index file:
-        let mut index_file = dir.open_with(INDEX_FILE_NAME, index_options)?;
-        index_file.set_permissions(Permissions::from_std(std::fs::Permissions::from_mode(
-            0o644,
-        )))?;
-
-        // Load the hash-to-ID lookup map from the index file:
-        let mut lookup_id = HashMap::new();
-        let mut buffer = [0u8; RECORD_SIZE];
-        index_file.rewind()?;
-        loop {
-            match index_file.read_exact(&mut buffer) {
-                Ok(_) => (),
-                Err(err) if err.kind() == UnexpectedEof => break,
-                Err(err) => return Err(err.into()),
+        Self::open_dir_with_layout(dir, DirectoryLayout::default(), config)
+    }
+
+    /// Opens a store, using the given layout if the store is new. Existing
+    /// stores keep the layout recorded when they were created, or the flat
+    /// layout if they predate layouts.
+    ///
+    /// New stores get a versioned index, while legacy indexes without a
+    /// header are read as is until [`upgrade`](Self::upgrade)d.
+    pub fn open_dir_with_layout(
+        dir: Dir,
+        layout: DirectoryLayout,
+        config: BlobStoreOptions,
+    ) -> Result<Self> {
+        let is_new = match dir.metadata(INDEX_FILE_NAME) {
+            Ok(metadata) => metadata.len() == 0,
+            Err(err) if err.kind() == NotFound => true,
+            Err(err) => return Err(err.into()),
+        };
+        if is_new && config.writable {
+            // An empty store created by an older version may have recorded
+            // its layout already:
+            let layout = read_layout(&dir)?.unwrap_or(layout);
+            let header = IndexHeader::new(layout, &config.filters);
+            write_index(&dir, Some(&header), &mut std::io::empty())?;
+            remove_layout(&dir)?;
+        }
+
+        // Open the index file and read its header, if any:
+        let index_file = open_index(&dir, config.writable)?;
+        let mut buffer = [0u8; INDEX_HEADER_SIZE];
+        let header = match index_file.read_exact_at(&mut buffer, 0) {
+            Ok(()) if buffer.starts_with(&INDEX_MAGIC) => IndexHeader::read_from(&buffer),
+            Ok(()) => None,
+            Err(err) if err.kind() == UnexpectedEof => None,
+            Err(err) => return Err(err.into()),
+        };
+        let layout = match &header {
+            Some(header) => {
+                if header.version.get() > INDEX_VERSION {
+                    return Err(invalid_data(&format!(
+                        "unsupported blob store index version: {}",
+                        header.version.get()
+                    )));
+                }
+                // Blobs may have been encoded with any of the recorded
+                // filters, so they must all be available for decoding:
+                for filter_id in header.filter_ids() {
+                    if builtin_filter(filter_id).is_none()
+                        && !config.filters.iter().any(|filter| filter.id() == filter_id)
+                    {
+                        return Err(invalid_data(&format!(
+                            "blob store requires the unconfigured filter {}",
+                            filter_id
+                        )));
+                    }
+                }
+                header
+                    .layout()
+                    .ok_or_else(|| invalid_data("invalid blob store layout"))?
+            }
+            None => match read_layout(&dir)? {
+                Some(layout) => layout,
+                None if is_new => layout,
+                None => DirectoryLayout::FLAT,
+            },
+        };
+
+        // Truncate a record torn by a crash while appending to the index, so
+        // that later records are appended at a record boundary:
+        let records_size = index_file
+            .metadata()?
+            .len()
+            .saturating_sub(records_offset(&header));
+        let record_count = (records_size / RECORD_SIZE as u64) as usize;
+        if config.writable && records_size % RECORD_SIZE as u64 != 0 {
+            index_file.set_len(records_offset(&header) + (record_count * RECORD_SIZE) as u64)?;
+        }
+        let mut index_file: Box<dyn File> = Box::new(index_file);
+
+        // A compaction interrupted after replacing the index leaves behind
+        // tombstones that would apply to the renumbered blobs:
+        let mut stale_tombstones = false;
+        match dir.read_to_string(COMPACT_FILE_NAME) {
+            Ok(input) => {
+                let index_size = records_offset(&header) + records_size;
+                stale_tombstones = input.trim().parse() == Ok(index_size);
+                if config.writable {
+                    if stale_tombstones {
+                        remove_file(&dir, TOMBSTONE_FILE_NAME)?;
+                    }
+                    remove_file(&dir, COMPACT_FILE_NAME)?;
+                }
+            }
+            Err(err) if err.kind() == NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        // Load the IDs of removed blobs from the tombstone file:
+        let mut removed_ids = HashSet::new();
+        match dir.open(TOMBSTONE_FILE_NAME) {
+            Ok(_) if stale_tombstones => (),
+            Ok(mut tombstone_file) => {
+                let mut buffer = [0u8; TOMBSTONE_SIZE];
+                loop {
+                    match tombstone_file.read_exact(&mut buffer) {
+                        Ok(_) => (),
+                        Err(err) if err.kind() == UnexpectedEof => break,
+                        Err(err) => return Err(err.into()),
+                    }
+                    let record = TombstoneRecord::read_from(&buffer).unwrap();
+                    removed_ids.insert(record.0.get() as BlobID);
+                }
+                // Likewise truncate a torn tombstone:
+                let tombstone_size = tombstone_file.metadata()?.len();
+                if config.writable && tombstone_size % TOMBSTONE_SIZE as u64 != 0 {
+                    dir.open_with(
+                        TOMBSTONE_FILE_NAME,
+                        cap_std::fs::File::options().write(true),
+                    )?
+                    .set_len(tombstone_size - tombstone_size % TOMBSTONE_SIZE as u64)?;
+                }
             }
-            let record = PersistentBlobRecord::read_from(&buffer).unwrap();
-            lookup_id.insert(record.0.into(), lookup_id.len() + 1);
+            Err(err) if err.kind() == NotFound => (),
+            Err(err) => return Err(err.into()),
         }
+        removed_ids.retain(|blob_id| *blob_id <= record_count);
+
+        // Open the hash-to-ID lookup table, entering any index records it
+        // lacks, or rebuilding it from scratch if it is missing:
+        let mut lookup = match LookupTable::open(&dir, config.writable)? {
+            Some(lookup) if lookup.record_count() <= record_count => lookup,
+            _ => LookupTable::create(config.writable.then_some(&dir))?,
+        };
+        if lookup.record_count() < record_count {
+            let mut buffer = vec![0u8; RECORD_SIZE * 1024];
+            let mut blob_id = lookup.record_count();
+            index_file.seek(SeekFrom::Start(
+                records_offset(&header) + (blob_id * RECORD_SIZE) as u64,
+            ))?;
+            while blob_id < record_count {
+                let batch_len = (record_count - blob_id).min(1024) * RECORD_SIZE;
+                index_file.read_exact(&mut buffer[..batch_len])?;
+                for record in buffer[..batch_len].chunks_exact(RECORD_SIZE) {
+                    let record = PersistentBlobRecord::read_from(record).unwrap();
+                    blob_id += 1;
+                    lookup.insert(&dir, record.0.into(), blob_id)?;
+                }
+            }
+            lookup.set_record_count(record_count)?;
+        }
+
+        let metadata = MetadataLog::open(&dir, config.writable)?;
 
         Ok(Self {
             config,
             dir,
-            index_file: RefCell::new(Box::new(index_file)),
-            lookup_id,
+            index_file: Mutex::new(index_file),
+            header,
+            layout,
+            lookup,
+            metadata,
+            removed_ids,
+            record_count,
         })
     }
 
+    /// Undoes the filters recorded in the blob's envelope, if any, returning
+    /// the blob's original size and content.
+    pub(crate) fn decode_blob(
+        &self,
+        mut blob_file: cap_std::fs::File,
+    ) -> Result<(u64, Arc<Mutex<dyn BlobData>>)> {
+        if let Some(envelope) = Envelope::read_from(&mut blob_file)? {
+            let filters = envelope.resolve(&self.config.filters)?;
+            blob_file = decode_into_tempfile(&filters, &mut blob_file)?;
+        }
+        Ok((stream_len(&mut blob_file)?, Arc::new(Mutex::new(blob_file))))
+    }
+
+    pub fn layout(&self) -> DirectoryLayout {
+        self.layout
+    }
+
+    /// Returns the version of the index format, or 0 for a legacy index.
+    pub fn format_version(&self) -> u32 {
+        self.header.map(|header| header.version.get()).unwrap_or(0)
+    }
+
+    /// Returns the UUID assigned to the store when it was created or
+    /// upgraded, or `None` for a legacy index.
+    pub fn uuid(&self) -> Option<Uuid> {
+        self.header.map(|header| Uuid::from_bytes(header.uuid))
+    }
+
+    /// Returns the IDs of the filters configured when the store was created
+    /// or upgraded.
+    pub fn recorded_filters(&self) -> Vec<FilterID> {
+        match self.header {
+            None => Vec::new(),
+            Some(header) => header.filter_ids(),
+        }
+    }
+
+    /// Migrates a legacy index to the current format, returning whether the
+    /// index was upgraded.
+    ///
+    /// The index is copied to a temporary file that then replaces it, so an
+    /// interrupted upgrade leaves the legacy index in place.
+    pub fn upgrade(&mut self) -> Result<bool> {
+        if !self.config.writable {
+            return Err(crate::BlobStoreError::NotWritable);
+        }
+        if self.header.is_some() {
+            return Ok(false);
+        }
+
+        let header = IndexHeader::new(self.layout, &self.config.filters);
+        let mut index_file = self.index_file.lock().unwrap();
+        index_file.rewind()?;
+        let records_size = (self.record_count * RECORD_SIZE) as u64; // drop a torn write
+        let mut records = (&mut *index_file).take(records_size);
+        if write_index(&self.dir, Some(&header), &mut records)? != records_size {
+            return Err(invalid_data("blob store index truncated during upgrade"));
+        }
+        *index_file = Box::new(open_index(&self.dir, true)?);
+        remove_layout(&self.dir)?;
+        self.header = Some(header);
+        Ok(true)
+    }
+
+    /// Returns the path of the blob's outboard file, next to the blob file.
+    pub(crate) fn outboard_path(layout: DirectoryLayout, blob_hash: BlobHash) -> PathBuf {
+        let mut outboard_path = layout.encode_path(blob_hash);
+        outboard_path.set_extension(OUTBOARD_EXTENSION);
+        outboard_path
+    }
+
+    /// Moves all blob files into the given layout, returning the number of
+    /// blob files moved.
+    ///
+    /// If interrupted, blobs may be unreadable until this is run again with
+    /// the same layout, which resumes where it left off.
+    pub fn relayout(&mut self, layout: DirectoryLayout) -> Result<usize> {
+        if !self.config.writable {
+            return Err(crate::BlobStoreError::NotWritable);
+        }
+
+        let mut moved = 0;
+        let mut old_dirs = HashSet::new();
+        for blob_id in 1..=self.record_count {
+            if self.removed_ids.contains(&blob_id) {
+                continue;
+            }
+            let Some(record) = self.read_record(blob_id)? else {
+                continue;
+            };
+            let blob_hash = &BlobHash::from(record.0);
+            let old_path = self.layout.encode_path(*blob_hash);
+            let new_path = layout.encode_path(*blob_hash);
+            if old_path == new_path {
+                continue;
+            }
+            if let Some(new_dir) = new_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                self.dir.create_dir_all(new_dir)?;
+            }
+            match self.dir.rename(&old_path, &self.dir, &new_path) {
+                Ok(()) => moved += 1,
+                Err(err) if err.kind() == NotFound && self.dir.exists(&new_path) => (),
+                Err(err) if err.kind() == NotFound => (), // removed blob
+                Err(err) => return Err(err.into()),
+            }
+            // Move the outboard too, even if kept from when the store was
+            // opened with outboards enabled:
+            match self.dir.rename(
+                Self::outboard_path(self.layout, *blob_hash),
+                &self.dir,
+                Self::outboard_path(layout, *blob_hash),
+            ) {
+                Ok(()) => (),
+                Err(err) if err.kind() == NotFound => (),
+                Err(err) => return Err(err.into()),
+            }
+            old_dirs.extend(old_path.ancestors().skip(1).map(|dir| dir.to_path_buf()));
+        }
+        match self.header {
+            None => write_layout(&self.dir, layout)?,
+            Some(mut header) => {
+                header.set_layout(layout);
+                self.rewrite_header(header)?;
+            }
+        }
+        self.layout = layout;
+
+        // Clean up the now-empty directories of the old layout, deepest first:
+        let mut old_dirs: Vec<_> = old_dirs
+            .into_iter()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect();
+        old_dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+        for old_dir in old_dirs {
+            let _ = self.dir.remove_dir(old_dir); // fails if not empty
+        }
+        Ok(moved)
+    }
+
+    /// Repairs what interrupted writes leave behind, returning what was done.
+    ///
+    /// Blob files missing from the index, as left by a crash between storing
+    /// a blob file and indexing it, are verified and indexed in order of
+    /// modification time, which also rebuilds a lost index. Leftover files of
+    /// removed blobs and abandoned temporary files are deleted. This assumes
+    /// no other process is writing to the store.
+    pub fn repair(&mut self) -> Result<RepairReport> {
+        if !self.config.writable {
+            return Err(crate::BlobStoreError::NotWritable);
+        }
+
+        let mut report = RepairReport {
+            rebuilt_index: self.record_count == 0,
+            ..Default::default()
+        };
+        if report.rebuilt_index {
+            // Tombstones of a lost index refer to IDs about to be reassigned:
+            remove_file(&self.dir, TOMBSTONE_FILE_NAME)?;
+        }
+
+        let mut blob_files = Vec::new();
+        let mut sidecar_files = Vec::new();
+        for path in walk_dir(&self.dir, PathBuf::new())? {
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            if path.parent() == Some(Path::new("")) && Uuid::parse_str(&file_name).is_ok() {
+                self.dir.remove_file(&path)?;
+                report.removed_temp_files += 1;
+                continue;
+            }
+            let Some(file_stem) = path.file_stem().map(|stem| stem.to_string_lossy()) else {
+                continue;
+            };
+            let Ok(blob_hash) = BlobHash::from_hex(file_stem.as_bytes()) else {
+                continue; // not ours
+            };
+            match path.extension().and_then(|ext| ext.to_str()) {
+                None => {
+                    let modified = self.dir.metadata(&path)?.modified()?;
+                    blob_files.push((modified, blob_hash, path));
+                }
+                Some(OUTBOARD_EXTENSION) => {
+                    sidecar_files.push((blob_hash, path));
+                }
+                Some(_) => (),
+            }
+        }
+
+        blob_files.sort_by(|a, b| (a.0, &a.2).cmp(&(b.0, &b.2)));
+        let mut metadata_offsets = None;
+        for (_, blob_hash, path) in blob_files {
+            if self.lookup(blob_hash).is_some() {
+                continue;
+            }
+            if self.lookup.get(blob_hash).is_some() {
+                self.dir.remove_file(&path)?; // interrupted removal
+                report.removed_files += 1;
+                continue;
+            }
+
+            // Make sure the file is complete before indexing it:
+            let (blob_size, blob_data) = self.decode_blob(self.dir.open(&path)?)?;
+            let mut hasher = BlobHasher::new();
+            {
+                let mut blob_data = blob_data.lock().unwrap();
+                blob_data.rewind()?;
+                std::io::copy(&mut *blob_data, &mut hasher)?;
+            }
+            if hasher.finalize() != blob_hash {
+                report.corrupt_files.push(path);
+                continue;
+            }
+
+            // Move files found outside the layout, as after a lost index:
+            let blob_path = self.layout.encode_path(blob_hash);
+            if path != blob_path {
+                if let Some(blob_dir) = blob_path.parent().filter(|dir| !dir.as_os_str().is_empty())
+                {
+                    self.dir.create_dir_all(blob_dir)?;
+                }
+                self.dir.rename(&path, &self.dir, &blob_path)?;
+            }
+            // Recover the blob's metadata, if recorded before the crash:
+            let metadata_offsets = match &mut metadata_offsets {
+                Some(metadata_offsets) => metadata_offsets,
+                None => metadata_offsets.insert(self.metadata.scan()?),
+            };
+            let blob_id = self.record_count + 1;
+            self.metadata
+                .set_offset(blob_id, metadata_offsets.get(&blob_hash).copied())?;
+            self.append_record(blob_hash, blob_size)?;
+            report.reindexed.push(blob_hash);
+        }
+
+        for (blob_hash, path) in sidecar_files {
+            if self.lookup(blob_hash).is_none() {
+                self.dir.remove_file(&path)?; // of a removed or unstored blob
+                report.removed_files += 1;
+                continue;
+            }
+            let mut sidecar_path = self.layout.encode_path(blob_hash);
+            sidecar_path.set_extension(path.extension().unwrap());
+            if path != sidecar_path && !self.dir.exists(&sidecar_path) {
+                self.dir.rename(&path, &self.dir, &sidecar_path)?;
+            }
+        }
+
+        report.rebuilt_index &= !report.reindexed.is_empty();
+        Ok(report)
+    }
+
+    /// Rewrites the index without the records of removed blobs, renumbering
+    /// the remaining blobs, and optionally re-encodes the blob files whose
+    /// filters differ from the configured ones, returning what was done.
+    ///
+    /// The new index replaces the old one atomically. Until the tombstones
+    /// are deleted, a marker file records the size of the new index, so that
+    /// an interrupted compaction is completed when the store is next opened.
+    pub fn compact(&mut self, reencode: bool) -> Result<CompactReport> {
+        if !self.config.writable {
+            return Err(crate::BlobStoreError::NotWritable);
+        }
+
+        let mut report = CompactReport::default();
+        let live_ids: Vec<BlobID> = (1..=self.record_count)
+            .filter(|blob_id| !self.removed_ids.contains(blob_id))
+            .collect();
+
+        if reencode {
+            let envelope = Envelope::new(&self.config.filters);
+            for blob_id in &live_ids {
+                let Some(record) = self.read_record(*blob_id)? else {
+                    continue;
+                };
+                let blob_path = self.layout.encode_path(record.0.into());
+                let mut blob_file = self.dir.open(&blob_path)?;
+                if Envelope::read_from(&mut blob_file)?.unwrap_or_default() == envelope {
+                    continue;
+                }
+                let old_size = blob_file.metadata()?.len();
+                blob_file.rewind()?;
+                let (_, blob_data) = self.decode_blob(blob_file)?;
+
+                let mut data_file = TempFile::new(&self.dir)?;
+                data_file.as_file().set_permissions(Permissions::from_std(
+                    std::fs::Permissions::from_mode(0o444),
+                ))?;
+                {
+                    let mut blob_data = blob_data.lock().unwrap();
+                    blob_data.rewind()?;
+                    std::io::copy(&mut *blob_data, &mut data_file)?;
+                }
+                self.encode_blob(&mut data_file)?;
+                data_file.flush()?;
+                data_file.as_file().sync_all()?;
+                let new_size = data_file.as_file().metadata()?.len();
+                data_file.replace(&blob_path)?;
+
+                report.reencoded += 1;
+                report.old_size += old_size;
+                report.new_size += new_size;
+            }
+            if let Some(header) = self.header {
+                let mut new_header = IndexHeader::new(self.layout, &self.config.filters);
+                new_header.uuid = header.uuid;
+                if new_header.filter_ids() != header.filter_ids() {
+                    self.rewrite_header(new_header)?;
+                }
+            }
+        }
+
+        if live_ids.len() == self.record_count {
+            return Ok(report); // nothing removed
+        }
+
+        let mut records = Vec::with_capacity(live_ids.len() * RECORD_SIZE);
+        let mut live_blobs = Vec::with_capacity(live_ids.len());
+        for (index, old_id) in live_ids.iter().enumerate() {
+            let record = self
+                .read_record(*old_id)?
+                .ok_or_else(|| invalid_data("truncated index"))?;
+            records.extend_from_slice(record.as_bytes());
+            live_blobs.push((*old_id, BlobHash::from(record.0)));
+            if index + 1 != *old_id {
+                report.id_map.push((*old_id, index + 1));
+            }
+        }
+        let old_index_size = self.dir.metadata(INDEX_FILE_NAME)?.len();
+        let tombstone_size = match self.dir.metadata(TOMBSTONE_FILE_NAME) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        let new_index_size = records_offset(&self.header) + records.len() as u64;
+
+        let mut marker_file = TempFile::new(&self.dir)?;
+        writeln!(marker_file, "{}", new_index_size)?;
+        marker_file.as_file().sync_all()?;
+        marker_file.replace(COMPACT_FILE_NAME)?;
+        remove_file(&self.dir, LOOKUP_FILE_NAME)?; // rebuilt if interrupted
+        self.metadata.rewrite(&self.dir, &live_blobs)?;
+        write_index(&self.dir, self.header.as_ref(), &mut records.as_slice())?;
+        remove_file(&self.dir, TOMBSTONE_FILE_NAME)?;
+        remove_file(&self.dir, COMPACT_FILE_NAME)?;
+
+        *self.index_file.lock().unwrap() = Box::new(open_index(&self.dir, true)?);
+        self.record_count = live_ids.len();
+        self.removed_ids.clear();
+        let mut lookup = LookupTable::create(Some(&self.dir))?;
+        for (index, (_, blob_hash)) in live_blobs.into_iter().enumerate() {
+            lookup.insert(&self.dir, blob_hash, index + 1)?;
+        }
+        lookup.set_record_count(self.record_count)?;
+        self.lookup = lookup;
+
+        report.old_size += old_index_size + tombstone_size;
+        report.new_size += new_index_size;
+        Ok(report)
+    }
+
+    /// Appends a record for the blob to the index, returning its new ID.
+    fn append_record(&mut self, blob_hash: BlobHash, blob_size: u64) -> Result<BlobID> {
+        let blob_id: BlobID = self.record_count + 1;
+        let blob_record = PersistentBlobRecord(blob_hash.into(), blob_size.into());
+
+        let mut index_file = self.index_file.lock().unwrap();
+        index_file.seek(std::io::SeekFrom::End(0))?;
+        index_file.write_all(blob_record.as_bytes())?;
+        index_file.sync_all()?;
+        self.record_count = blob_id;
+        self.lookup.insert(&self.dir, blob_hash, blob_id)?;
+        self.lookup.set_record_count(blob_id)?;
+        Ok(blob_id)
+    }
+
+    /// Applies the configured filters to the blob data in the file, recording
+    /// them in an envelope preceding the encoded data.
+    fn encode_blob(&self, data_file: &mut TempFile<'_>) -> Result<()> {
+        let mut data_prefix = Vec::with_capacity(ENVELOPE_MAGIC.len());
+        data_file.rewind()?;
+        (&mut *data_file)
+            .take(ENVELOPE_MAGIC.len() as u64)
+            .read_to_end(&mut data_prefix)?;
+        if Envelope::is_required(&self.config.filters, &data_prefix) {
+            data_file.rewind()?;
+            let mut encoded_file = encode_into_tempfile(&self.config.filters, data_file)?;
+            data_file.rewind()?;
+            data_file.as_file().set_len(0)?;
+            std::io::copy(&mut encoded_file, data_file)?;
+        }
+        Ok(())
+    }
+
+    /// Overwrites the index header in place.
+    fn rewrite_header(&mut self, header: IndexHeader) -> Result<()> {
+        let index_file = self
+            .dir
+            .open_with(INDEX_FILE_NAME, cap_std::fs::File::options().write(true))?;
+        index_file.write_all_at(header.as_bytes(), 0)?;
+        index_file.sync_all()?;
+        self.header = Some(header);
+        Ok(())
+    }
+
+    /// Looks up the store ID of a blob that hasn't been removed.
+    pub(crate) fn lookup(&self, blob_hash: BlobHash) -> Option<BlobID> {
+        self.lookup
+            .get(blob_hash)
+            .filter(|blob_id| !self.removed_ids.contains(blob_id))
+    }
+
     pub(crate) fn read_record(&self, blob_id: BlobID) -> Result<Option<PersistentBlobRecord>> {
-        let index_file = self.index_file.borrow();
+        if blob_id == 0 {
+            return Ok(None);
+        }
+        let index_file = self.index_file.lock().unwrap();
         let record_id: usize = blob_id - 1;
+        let record_offset = records_offset(&self.header) + (record_id * RECORD_SIZE) as u64;
         let mut buffer = [0u8; RECORD_SIZE];
-        match index_file.read_exact_at(&mut buffer, (record_id * RECORD_SIZE) as u64) {
+        match index_file.read_exact_at(&mut buffer, record_offset) {
             Ok(()) => (),
             Err(err) if err.kind() == UnexpectedEof => return Ok(None),
             Err(err) => return Err(err.into()),
@@ -96,95 +695,94 @@ impl DirectoryBlobStore {
 
 impl BlobStore for DirectoryBlobStore {
     fn count(&self) -> Result<BlobID> {
-        let mut stream = self.index_file.borrow_mut();
-        Ok(stream_len(stream.as_mut()).unwrap() as BlobID / RECORD_SIZE as BlobID)
+        Ok(self.record_count - self.removed_ids.len())
     }
 
     fn contains_hash(&self, blob_hash: BlobHash) -> Result<bool> {
-        Ok(self.lookup_id.contains_key(&blob_hash))
+        Ok(self.lookup(blob_hash).is_some())
     }
 
     fn get_by_hash(&self, blob_hash: BlobHash) -> Result<Option<Blob>> {
-        match self.lookup_id.get(&blob_hash) {
+        match self.lookup(blob_hash) {
             None => Ok(None),
             Some(blob_id) => {
-                let blob_path = encode_into_path(blob_hash);
-                let mut blob_file = self.dir.open(blob_path)?;
+                let blob_path = self.layout.encode_path(blob_hash);
+                let blob_file = self.dir.open(blob_path)?;
+                let (blob_size, blob_data) = self.decode_blob(blob_file)?;
                 Ok(Some(Blob {
-                    id: *blob_id,
+                    id: blob_id,
                     hash: blob_hash,
-                    size: stream_len(&mut blob_file)?,
-                    data: Some(Rc::new(RefCell::new(blob_file))),
+                    size: blob_size,
+                    data: Some(blob_data),
+                    metadata: self.metadata.get(blob_id, blob_hash)?,
                 }))
             }
         }
     }
 
     fn put(&mut self, blob_data: &mut dyn Read) -> Result<(bool, Blob)> {
+        self.put_with_metadata(blob_data, BlobMetadata::new())
+    }
+
+    fn put_with_metadata(
+        &mut self,
+        blob_data: &mut dyn Read,
+        metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
         if !self.config.writable {
-            return Err(crate::BlobStoreError::NotWritable.into());
+            return Err(crate::BlobStoreError::NotWritable);
         }
 
-        // Buffer the blob data in a temporary file:
+        // Buffer the blob data in a temporary file, hashing it on the way:
         let mut data_file = TempFile::new(&self.dir)?;
         data_file.as_file().set_permissions(Permissions::from_std(
             std::fs::Permissions::from_mode(0o444),
         ))?;
-        let blob_size = std::io::copy(blob_data, &mut data_file)?;
-
-        // Compute the BLAKE3 hash for the blob data:
-        let mut blob_hasher = BlobHasher::new();
-        data_file.rewind()?;
-        std::io::copy(&mut data_file, &mut blob_hasher)?;
+        let mut data_reader = HashingReader::with_max_size(blob_data, self.config.max_blob_size);
+        std::io::copy(&mut data_reader, &mut data_file)?;
+        let metadata = metadata.complete(data_reader.prefix());
+        let (blob_hash, blob_size) = data_reader.finalize();
 
         // Check if the blob is already in the store:
-        let blob_hash = blob_hasher.finalize();
-        if let Some(blob_id) = self.lookup_id.get(&blob_hash) {
+        if let Some(blob_id) = self.lookup(blob_hash) {
             return Ok((
                 false,
                 Blob {
-                    id: *blob_id,
+                    id: blob_id,
                     hash: blob_hash,
                     size: blob_size,
                     data: None,
+                    metadata: self.metadata.get(blob_id, blob_hash)?,
                 },
             ));
         }
 
-        // Apply the configured filters to the blob data:
-        if !self.config.filters.is_empty() {
-            let mut input_buffer: Vec<u8> = Vec::new();
-            let mut output_buffer: Vec<u8> = Vec::new();
-            data_file.rewind()?;
-            data_file.read_to_end(&mut output_buffer)?;
-
-            for filter in &self.config.filters {
-                std::mem::swap(&mut input_buffer, &mut output_buffer);
-                let mut input_cursor = Cursor::new(&mut input_buffer);
-                filter.encode(&mut input_cursor, &mut output_buffer)?;
-            }
+        let blob_path = self.layout.encode_path(blob_hash);
+        if let Some(blob_dir) = blob_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            self.dir.create_dir_all(blob_dir)?;
+        }
 
+        // Compute the Bao outboard for the unfiltered blob data:
+        if self.config.outboard {
             data_file.rewind()?;
-            data_file
-                .as_file()
-                .set_len(output_buffer.len().try_into().unwrap())?;
-            data_file.write_all(output_buffer.as_bytes())?;
+            let (_, outboard) = Outboard::encode(&mut data_file, blob_size)?;
+            let mut outboard_file = TempFile::new(&self.dir)?;
+            outboard_file.write_all(&outboard.to_bytes())?;
+            outboard_file.as_file().sync_all()?;
+            outboard_file.replace(Self::outboard_path(self.layout, blob_hash))?;
         }
 
+        // Record the blob metadata under the blob's upcoming ID:
+        self.metadata
+            .insert(self.record_count + 1, blob_hash, &metadata)?;
+
+        self.encode_blob(&mut data_file)?;
+
         // Rename the temporary file to its final name:
-        let blob_path = encode_into_path(blob_hash);
         data_file.flush()?;
         data_file.replace(blob_path)?;
 
-        let blob_id: BlobID = self.lookup_id.len() + 1;
-        let blob_record = PersistentBlobRecord(blob_hash.into(), blob_size.into());
-
-        // Write the blob metadata to the index:
-        let mut index_file = self.index_file.borrow_mut();
-        index_file.seek(std::io::SeekFrom::End(0))?;
-        index_file.write_all(blob_record.as_bytes())?;
-        index_file.sync_all()?;
-        self.lookup_id.insert(blob_hash, blob_id);
+        let blob_id = self.append_record(blob_hash, blob_size)?;
 
         Ok((
             true,
@@ -193,32 +791,127 @@ impl BlobStore for DirectoryBlobStore {
                 hash: blob_hash,
                 size: blob_size,
                 data: None,
+                metadata: Some(metadata),
             },
         ))
     }
 
+    fn max_blob_size(&self) -> Option<u64> {
+        self.config.max_blob_size
+    }
+
     fn remove(&mut self, blob_hash: BlobHash) -> Result<bool> {
         if !self.config.writable {
-            return Err(crate::BlobStoreError::NotWritable.into());
+            return Err(crate::BlobStoreError::NotWritable);
         }
 
-        match self.lookup_id.remove(&blob_hash) {
-            None => Ok(false), // not found
-            Some(_blob_id) => {
-                let blob_path = encode_into_path(blob_hash);
-                match self.dir.remove_file(blob_path) {
-                    Ok(_) => Ok(true),
-                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
-                    Err(err) => Err(err.into()),
-                }
+        let blob_id = match self.lookup(blob_hash) {
+            None => return Ok(false), // not found
+            Some(blob_id) => blob_id,
+        };
+
+        // Persist the removal before deleting the blob file, so that a crash
+        // in between merely leaves behind an unreferenced file:
+        let mut tombstone_options = cap_std::fs::File::options();
+        let tombstone_options = tombstone_options.create(true).append(true);
+        let mut tombstone_file = self.dir.open_with(TOMBSTONE_FILE_NAME, tombstone_options)?;
+        let tombstone = TombstoneRecord((blob_id as u64).into());
+        tombstone_file.write_all(tombstone.as_bytes())?;
+        tombstone_file.sync_all()?;
+        self.removed_ids.insert(blob_id);
+
+        match self
+            .dir
+            .remove_file(Self::outboard_path(self.layout, blob_hash))
+        {
+            Ok(_) => (),
+            Err(err) if err.kind() == NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+        let blob_path = self.layout.encode_path(blob_hash);
+        match self.dir.remove_file(blob_path) {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == NotFound => Ok(true),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn list(&self, token: Option<&str>) -> Result<BlobPage> {
+        let record_count = self.record_count;
+        let mut blob_id = parse_id_token(token)?;
+        let mut blobs = Vec::new();
+        while blob_id < record_count && blobs.len() < LIST_PAGE_SIZE {
+            blob_id += 1;
+            if self.removed_ids.contains(&blob_id) {
+                continue;
             }
+            if let Some(record) = self.read_record(blob_id)? {
+                blobs.push((record.0.into(), record.1.get()));
+            }
+        }
+        Ok(BlobPage {
+            blobs,
+            next: (blob_id < record_count).then(|| blob_id.to_string()),
+        })
+    }
+
+    fn get_range(&self, blob_hash: BlobHash, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        if self.lookup(blob_hash).is_none() {
+            return Ok(None);
+        }
+        let blob_path = self.layout.encode_path(blob_hash);
+        let blob_file = self.dir.open(blob_path)?;
+
+        // Filtered blobs must be decoded before the range can be located:
+        let mut data_prefix = [0u8; ENVELOPE_MAGIC.len()];
+        let prefix_len = blob_file.read_at(&mut data_prefix, 0)?;
+        if data_prefix[..prefix_len].starts_with(&ENVELOPE_MAGIC) {
+            let (_, blob_data) = self.decode_blob(blob_file)?;
+            let mut blob_data = blob_data.lock().unwrap();
+            return Ok(Some(read_range(&mut *blob_data, offset, len)?));
+        }
+
+        let blob_size = blob_file.metadata()?.len();
+        let start = offset.min(blob_size);
+        let end = offset.saturating_add(len).min(blob_size);
+        let mut buffer = vec![0u8; (end - start) as usize];
+        blob_file.read_exact_at(&mut buffer, start)?;
+        Ok(Some(buffer))
+    }
+
+    fn get_outboard_range(
+        &self,
+        blob_hash: BlobHash,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        if self.lookup(blob_hash).is_none() {
+            return Ok(None);
+        }
+        let outboard_file = match self.dir.open(Self::outboard_path(self.layout, blob_hash)) {
+            Ok(outboard_file) => outboard_file,
+            Err(err) if err.kind() == NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let outboard_size = outboard_file.metadata()?.len();
+        let start = offset.min(outboard_size);
+        let end = offset.saturating_add(len).min(outboard_size);
+        let mut buffer = vec![0u8; (end - start) as usize];
+        outboard_file.read_exact_at(&mut buffer, start)?;
+        Ok(Some(buffer))
+    }
+
+    fn get_metadata(&self, blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        match self.lookup(blob_hash) {
+            None => Ok(None),
+            Some(blob_id) => self.metadata.get(blob_id, blob_hash),
         }
     }
 }
 
 impl IndexedBlobStore for DirectoryBlobStore {
     fn hash_to_id(&self, blob_hash: BlobHash) -> Result<Option<BlobID>> {
-        Ok(self.lookup_id.get(&blob_hash).copied())
+        Ok(self.lookup(blob_hash))
     }
 
     fn id_to_hash(&self, blob_id: BlobID) -> Result<Option<BlobHash>> {
@@ -228,11 +921,14 @@ impl IndexedBlobStore for DirectoryBlobStore {
     }
 
     fn get_by_id(&self, blob_id: BlobID) -> Result<Option<Blob>> {
+        if self.removed_ids.contains(&blob_id) {
+            return Err(BlobStoreError::Removed);
+        }
         match self.id_to_hash(blob_id)? {
             None => Ok(None),
             Some(blob_hash) => {
-                let blob_path = encode_into_path(blob_hash);
-                let mut blob_file = match self.dir.open(blob_path) {
+                let blob_path = self.layout.encode_path(blob_hash);
+                let blob_file = match self.dir.open(blob_path) {
                     Ok(blob_file) => blob_file,
                     Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
                         // The index entry remains, but the actual blob file has been removed:
@@ -240,11 +936,13 @@ impl IndexedBlobStore for DirectoryBlobStore {
                     }
                     Err(err) => return Err(err.into()),
                 };
+                let (blob_size, blob_data) = self.decode_blob(blob_file)?;
                 Ok(Some(Blob {
                     id: blob_id,
                     hash: blob_hash,
-                    size: stream_len(&mut blob_file)?,
-                    data: Some(Rc::new(RefCell::new(blob_file))),
+                    size: blob_size,
+                    data: Some(blob_data),
+                    metadata: self.metadata.get(blob_id, blob_hash)?,
                 }))
             }
         }
@@ -253,9 +951,116 @@ impl IndexedBlobStore for DirectoryBlobStore {
 
 impl BlobStoreExt for DirectoryBlobStore {}
 
+/// Lists the files in the store directory recursively, skipping the store's
+/// own dotfiles.
+pub(crate) fn walk_dir(dir: &Dir, path: PathBuf) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let entries = match path.as_os_str().is_empty() {
+        true => dir.entries()?,
+        false => dir.read_dir(&path)?,
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let entry_path = path.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            paths.extend(walk_dir(dir, entry_path)?);
+        } else if file_type.is_file() {
+            paths.push(entry_path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Opens the index file for reading and appending.
+fn open_index(dir: &Dir, writable: bool) -> Result<cap_std::fs::File> {
+    let mut index_options = cap_std::fs::File::options();
+    let index_options = index_options.create(writable).read(true).append(true);
+    let index_file = dir.open_with(INDEX_FILE_NAME, index_options)?;
+    index_file.set_permissions(Permissions::from_std(std::fs::Permissions::from_mode(
+        0o644,
+    )))?;
+    Ok(index_file)
+}
+
+/// Atomically replaces the index with the given header, if any, and the
+/// records read from the input, returning the size of the records.
+fn write_index(dir: &Dir, header: Option<&IndexHeader>, records: &mut dyn Read) -> Result<u64> {
+    let mut index_file = TempFile::new(dir)?;
+    if let Some(header) = header {
+        index_file.write_all(header.as_bytes())?;
+    }
+    let records_size = std::io::copy(records, &mut index_file)?;
+    index_file.as_file().sync_all()?;
+    index_file.replace(INDEX_FILE_NAME)?;
+    Ok(records_size)
+}
+
+/// Returns the offset of the first blob record in the index.
+fn records_offset(header: &Option<IndexHeader>) -> u64 {
+    match header {
+        None => 0,
+        Some(_) => INDEX_HEADER_SIZE as u64,
+    }
+}
+
+/// Reads the layout recorded for a legacy index, if any.
+fn read_layout(dir: &Dir) -> Result<Option<DirectoryLayout>> {
+    match dir.read_to_string(LAYOUT_FILE_NAME) {
+        Ok(input) => Ok(Some(
+            input
+                .parse()
+                .map_err(|_| invalid_data("invalid blob store layout"))?,
+        )),
+        Err(err) if err.kind() == NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Removes the layout file, which the index header supersedes.
+fn remove_layout(dir: &Dir) -> Result<()> {
+    remove_file(dir, LAYOUT_FILE_NAME)
+}
+
+/// Removes the file, if it exists.
+fn remove_file(dir: &Dir, path: impl AsRef<Path>) -> Result<()> {
+    match dir.remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Atomically records the store layout of a legacy index.
+fn write_layout(dir: &Dir, layout: DirectoryLayout) -> Result<()> {
+    let mut layout_file = TempFile::new(dir)?;
+    writeln!(layout_file, "{}", layout)?;
+    layout_file.as_file().sync_all()?;
+    layout_file.replace(LAYOUT_FILE_NAME)?;
+    Ok(())
+}
+
+fn invalid_data(message: &str) -> BlobStoreError {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message).into()
+}
+
+#[allow(clippy::seek_from_current)]
+fn stream_len<T: Seek + ?Sized>(stream: &mut T) -> Result<u64> {
+    let old_pos = stream.seek(SeekFrom::Current(0))?;
+    let len = stream.seek(SeekFrom::End(0))?;
+    if old_pos != len {
+        stream.seek(SeekFrom::Start(old_pos))?;
+    }
+    Ok(len)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::encode_into_path;
 
     #[test]
     fn test() {
@@ -279,14 +1084,428 @@ mod test {
         // eprintln!("{}", std::env::temp_dir().to_str().unwrap());
         // std::process::exit(0); // leave `temp_dir`` around for inspection
     }
-}
 
-#[allow(clippy::seek_from_current)]
-fn stream_len<T: Seek + ?Sized>(stream: &mut T) -> Result<u64> {
-    let old_pos = stream.seek(SeekFrom::Current(0))?;
-    let len = stream.seek(SeekFrom::End(0))?;
-    if old_pos != len {
-        stream.seek(SeekFrom::Start(old_pos))?;
+    #[test]
+    fn test_remove() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        let (_, foo) = store.put_string("Foo").unwrap();
+        let (_, bar) = store.put_string("Bar").unwrap();
+
+        // Removals persist across reopening, and re-adding assigns a new ID:
+        assert!(store.remove(foo.hash).unwrap());
+        assert!(!store.remove(foo.hash).unwrap());
+        assert_eq!(store.count().unwrap(), 1);
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.count().unwrap(), 1);
+        assert!(!store.contains_hash(foo.hash).unwrap());
+        assert!(matches!(store.get_by_id(1), Err(BlobStoreError::Removed)));
+        let (created, foo3) = store.put_string("Foo").unwrap();
+        assert!(created);
+        assert_eq!(foo3.id, 3);
+        assert_eq!(store.count().unwrap(), 2);
+        assert!(matches!(store.get_by_id(1), Err(BlobStoreError::Removed)));
+        let page = store.list(None).unwrap();
+        assert_eq!(page.blobs, vec![(bar.hash, 3), (foo.hash, 3)]);
+    }
+
+    #[test]
+    fn test_get_range() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        let (_, foo) = store.put_string("Foo").unwrap();
+        let (_, bar) = store.put_string("Bar").unwrap();
+
+        assert_eq!(store.get_range(bar.hash, 1, 10).unwrap().unwrap(), b"ar");
+        assert_eq!(store.get_range(bar.hash, 0, 0).unwrap().unwrap(), b"");
+        assert_eq!(store.get_range(bar.hash, 5, 10).unwrap().unwrap(), b"");
+        assert_eq!(store.get_range(BlobHash::zero(), 0, 1).unwrap(), None);
+
+        // Removed blobs have no ranges to read:
+        assert!(store.remove(foo.hash).unwrap());
+        assert_eq!(store.get_range(foo.hash, 0, 1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_max_blob_size() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let config = BlobStoreOptions::default().max_blob_size(Some(3));
+        let mut store = DirectoryBlobStore::open_tempdir(&temp_dir, config).unwrap();
+        assert_eq!(store.max_blob_size(), Some(3));
+
+        // Oversized blobs are rejected while streaming, leaving nothing behind:
+        assert!(store.put_string("Foo").unwrap().0);
+        assert!(matches!(
+            store.put_string("Quux"),
+            Err(BlobStoreError::TooLarge(3))
+        ));
+        assert_eq!(store.count().unwrap(), 1);
+        assert!(!store.contains_hash(crate::hash("Quux")).unwrap());
+        let entries = temp_dir.entries().unwrap().count();
+        assert!(store.put_string("Quux").is_err());
+        assert_eq!(temp_dir.entries().unwrap().count(), entries);
+    }
+
+    #[test]
+    fn test_relayout() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        let (_, foo) = store.put_string("Foo").unwrap();
+        let (_, bar) = store.put_string("Bar").unwrap();
+        assert_eq!(store.layout(), DirectoryLayout::default());
+        assert!(temp_dir.exists(DirectoryLayout::default().encode_path(bar.hash)));
+
+        // Migrate to the flat layout and back:
+        assert_eq!(store.relayout(DirectoryLayout::FLAT).unwrap(), 2);
+        assert!(temp_dir.exists(encode_into_path(bar.hash)));
+        assert_eq!(store.relayout(DirectoryLayout::FLAT).unwrap(), 0);
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.layout(), DirectoryLayout::FLAT);
+        assert_eq!(store.relayout(DirectoryLayout::default()).unwrap(), 2);
+        assert!(!temp_dir.exists(encode_into_path(bar.hash)));
+        assert!(store.get_by_hash(foo.hash).unwrap().is_some());
+        let store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.layout(), DirectoryLayout::default());
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.format_version(), INDEX_VERSION);
+        let (_, foo) = store.put_string("Foo").unwrap();
+        let (_, bar) = store.put_string("Bar").unwrap();
+        drop(store);
+
+        // Strip the header to get a legacy index:
+        let index = temp_dir.read(INDEX_FILE_NAME).unwrap();
+        temp_dir
+            .write(INDEX_FILE_NAME, &index[INDEX_HEADER_SIZE..])
+            .unwrap();
+        write_layout(&temp_dir, DirectoryLayout::default()).unwrap();
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.format_version(), 0);
+        assert_eq!(store.uuid(), None);
+        assert_eq!(store.get_by_id(2).unwrap().unwrap().hash, bar.hash);
+
+        assert!(store.upgrade().unwrap());
+        assert!(!store.upgrade().unwrap());
+        let uuid = store.uuid().unwrap();
+        assert_eq!(store.get_by_id(1).unwrap().unwrap().hash, foo.hash);
+        assert!(!temp_dir.exists(LAYOUT_FILE_NAME));
+        let store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.format_version(), INDEX_VERSION);
+        assert_eq!(store.uuid(), Some(uuid));
+        assert_eq!(store.layout(), DirectoryLayout::default());
+        assert_eq!(store.get_by_id(2).unwrap().unwrap().hash, bar.hash);
+        drop(store);
+
+        // Indexes from future versions are rejected:
+        let mut index = temp_dir.read(INDEX_FILE_NAME).unwrap();
+        index[11] = INDEX_VERSION as u8 + 1;
+        temp_dir.write(INDEX_FILE_NAME, index).unwrap();
+        assert!(DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).is_err());
+
+        // Stores recorded with filters that need a configuration, such as a
+        // key, can only be opened with them configured:
+        #[cfg(feature = "encrypt")]
+        {
+            let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+            let key = crate::encrypt::EncryptionKey::generate();
+            let config = || {
+                let key = crate::encrypt::EncryptionKey::from_bytes(*key.as_bytes());
+                BlobStoreOptions::default().filter(Box::new(crate::encrypt::Encryptor::new(key)))
+            };
+            drop(DirectoryBlobStore::open_tempdir(&temp_dir, config()).unwrap());
+            assert!(
+                DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).is_err()
+            );
+            let store = DirectoryBlobStore::open_tempdir(&temp_dir, config()).unwrap();
+            assert_eq!(store.recorded_filters(), vec![FilterID::ENCRYPT]);
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        let (_, foo) = store.put_string("Foo").unwrap();
+        let lookup = temp_dir.read(LOOKUP_FILE_NAME).unwrap();
+        let (_, bar) = store.put_string("Bar").unwrap();
+        drop(store);
+
+        // A table that is behind the index catches up on opening:
+        temp_dir.write(LOOKUP_FILE_NAME, lookup).unwrap();
+        let store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.hash_to_id(bar.hash).unwrap(), Some(bar.id));
+        drop(store);
+
+        // A missing table is rebuilt from the index:
+        temp_dir.remove_file(LOOKUP_FILE_NAME).unwrap();
+        let store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.count().unwrap(), 2);
+        assert_eq!(store.hash_to_id(foo.hash).unwrap(), Some(foo.id));
+        assert!(temp_dir.exists(LOOKUP_FILE_NAME));
+    }
+
+    #[test]
+    fn test_repair() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        let (_, foo) = store.put_string("Foo").unwrap();
+        let (_, bar) = store.put_string("Bar").unwrap();
+        let (_, baz) = store.put_string("Baz").unwrap();
+        let foo_path = store.layout().encode_path(foo.hash);
+        let foo_data = temp_dir.read(&foo_path).unwrap();
+        assert!(store.remove(foo.hash).unwrap());
+        drop(store);
+
+        // Lose the last record to a torn write, and leave behind the file of
+        // the removed blob, a temporary file, and a stray outboard file:
+        let mut index = temp_dir.read(INDEX_FILE_NAME).unwrap();
+        index.truncate(index.len() - RECORD_SIZE / 2);
+        temp_dir.write(INDEX_FILE_NAME, &index).unwrap();
+        temp_dir.write(&foo_path, foo_data).unwrap();
+        temp_dir.write(Uuid::new_v4().to_string(), "Temp").unwrap();
+        let quux_hash = crate::hash("Quux");
+        temp_dir
+            .write(
+                DirectoryBlobStore::outboard_path(DirectoryLayout::FLAT, quux_hash),
+                [],
+            )
+            .unwrap();
+        temp_dir
+            .write(quux_hash.to_hex().as_str(), "Corrupt")
+            .unwrap();
+
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        let index_len = temp_dir.metadata(INDEX_FILE_NAME).unwrap().len() as usize;
+        assert_eq!(index_len, INDEX_HEADER_SIZE + 2 * RECORD_SIZE);
+        assert_eq!(store.count().unwrap(), 1);
+        let report = store.repair().unwrap();
+        assert_eq!(report.reindexed, vec![baz.hash]);
+        assert!(!report.rebuilt_index);
+        assert_eq!(report.removed_files, 2);
+        assert_eq!(report.removed_temp_files, 1);
+        assert_eq!(
+            report.corrupt_files,
+            vec![PathBuf::from(quux_hash.to_hex().as_str())]
+        );
+        assert_eq!(store.hash_to_id(baz.hash).unwrap(), Some(3));
+        assert!(!temp_dir.exists(&foo_path));
+        assert_eq!(store.repair().unwrap().reindexed, vec![]);
+        drop(store);
+
+        // A lost index is rebuilt from the blob files:
+        temp_dir.remove_file(INDEX_FILE_NAME).unwrap();
+        let layout = DirectoryLayout::FLAT;
+        let dir = temp_dir.open_dir(".").unwrap();
+        let mut store =
+            DirectoryBlobStore::open_dir_with_layout(dir, layout, BlobStoreOptions::default())
+                .unwrap();
+        let report = store.repair().unwrap();
+        assert!(report.rebuilt_index);
+        assert_eq!(report.reindexed.len(), 2);
+        assert_eq!(store.count().unwrap(), 2);
+        assert!(store.get_by_hash(bar.hash).unwrap().is_some());
+        assert!(temp_dir.exists(encode_into_path(baz.hash)));
+        assert_eq!(store.get_metadata(baz.hash).unwrap(), baz.metadata);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compact() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        let text = "Foo".repeat(1000);
+        let (_, foo) = store.put_string(&text).unwrap();
+        let (_, bar) = store.put_string("Bar").unwrap();
+        let (_, baz) = store.put_string("Baz").unwrap();
+        assert!(store.remove(bar.hash).unwrap());
+        let report = store.compact(false).unwrap();
+        assert_eq!(report.id_map, vec![(3, 2)]);
+        assert_eq!(report.reencoded, 0);
+        assert_eq!(report.reclaimed(), (RECORD_SIZE + TOMBSTONE_SIZE) as u64);
+        assert_eq!(store.count().unwrap(), 2);
+        assert_eq!(store.hash_to_id(baz.hash).unwrap(), Some(2));
+        assert!(!temp_dir.exists(TOMBSTONE_FILE_NAME));
+        assert_eq!(store.compact(false).unwrap(), CompactReport::default());
+        let (_, bar) = store.put_string("Bar").unwrap();
+        assert_eq!(bar.id, 3);
+        drop(store);
+
+        // Re-encode the blobs with the configured filters:
+        let config = BlobStoreOptions::default().filter(Box::new(crate::ZstdCompressor::new()));
+        let mut store = DirectoryBlobStore::open_tempdir(&temp_dir, config).unwrap();
+        let report = store.compact(true).unwrap();
+        assert_eq!(report.reencoded, 3);
+        assert!(report.reclaimed() > 0);
+        assert_eq!(store.recorded_filters(), vec![FilterID::ZSTD]);
+        assert_eq!(store.compact(true).unwrap().reencoded, 0);
+        let mut foo = store.get_by_id(foo.id).unwrap().unwrap();
+        assert_eq!(foo.size, text.len() as u64);
+        drop(store);
+
+        // An interrupted compaction is completed on opening:
+        let store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.get_by_hash(foo.hash).unwrap().unwrap().size, foo.size);
+        drop(store);
+        let index_size = temp_dir.metadata(INDEX_FILE_NAME).unwrap().len();
+        temp_dir
+            .write(COMPACT_FILE_NAME, format!("{}\n", index_size))
+            .unwrap();
+        temp_dir
+            .write(TOMBSTONE_FILE_NAME, TombstoneRecord(1.into()).as_bytes())
+            .unwrap();
+        let store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.count().unwrap(), 3);
+        assert!(!temp_dir.exists(COMPACT_FILE_NAME));
+        assert!(!temp_dir.exists(TOMBSTONE_FILE_NAME));
+        let mut data = String::new();
+        let foo_data = foo.data.take().unwrap();
+        foo_data.lock().unwrap().read_to_string(&mut data).unwrap();
+        assert_eq!(data, text);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+
+        let metadata = BlobMetadata::new()
+            .name("foo.png")
+            .attribute("origin", "test");
+        let (created, foo) = store
+            .put_with_metadata(&mut &b"\x89PNG\r\n\x1a\nFoo"[..], metadata)
+            .unwrap();
+        assert!(created);
+        let metadata = foo.metadata.unwrap();
+        assert_eq!(metadata.mime_type.as_deref(), Some("image/png"));
+        assert_eq!(metadata.name.as_deref(), Some("foo.png"));
+        assert!(metadata.created.is_some());
+
+        // The first record is kept when the blob is stored again:
+        let (created, foo2) = store
+            .put_with_metadata(&mut &b"\x89PNG\r\n\x1a\nFoo"[..], BlobMetadata::new())
+            .unwrap();
+        assert!(!created);
+        assert_eq!(foo2.metadata.as_ref(), Some(&metadata));
+        let foo = store.get_by_id(foo.id).unwrap().unwrap();
+        assert_eq!(foo.metadata.as_ref(), Some(&metadata));
+
+        // Records are kept in one log, unaffected by relayouts, and follow
+        // their blobs when the store is compacted:
+        assert_eq!(store.relayout(DirectoryLayout::FLAT).unwrap(), 1);
+        assert_eq!(store.get_metadata(foo.hash).unwrap(), Some(metadata));
+        let (_, bar) = store
+            .put_with_metadata(&mut &b"Bar"[..], BlobMetadata::new().name("bar.txt"))
+            .unwrap();
+        assert!(store.remove(foo.hash).unwrap());
+        assert_eq!(store.get_metadata(foo.hash).unwrap(), None);
+        store.compact(false).unwrap();
+        drop(store);
+
+        let store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.hash_to_id(bar.hash).unwrap(), Some(1));
+        assert_eq!(store.get_metadata(bar.hash).unwrap(), bar.metadata);
+        assert_eq!(
+            store.get_by_id(1).unwrap().unwrap().metadata.unwrap().name,
+            Some("bar.txt".to_string())
+        );
+    }
+
+    #[test]
+    fn test_outboard() {
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let config = BlobStoreOptions::default().outboard(true);
+        let mut store = DirectoryBlobStore::open_tempdir(&temp_dir, config).unwrap();
+
+        let text = "Foo".repeat(1000);
+        let (_, foo) = store.put_string(&text).unwrap();
+        let header = store.get_outboard_range(foo.hash, 0, 8).unwrap().unwrap();
+        assert_eq!(header, (text.len() as u64).to_le_bytes());
+        let range = store.get_verified_range(foo.hash, 1500, 10).unwrap();
+        assert_eq!(range, store.get_range(foo.hash, 1500, 10).unwrap());
+        assert_eq!(range.unwrap(), &text.as_bytes()[1500..1510]);
+
+        // Outboards follow their blobs when the layout changes, even if the
+        // store is no longer keeping them:
+        assert_eq!(store.relayout(DirectoryLayout::FLAT).unwrap(), 1);
+        assert!(store.get_outboard_range(foo.hash, 0, 0).unwrap().is_some());
+        drop(store);
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.relayout(DirectoryLayout::default()).unwrap(), 1);
+        assert!(temp_dir.exists(DirectoryBlobStore::outboard_path(
+            DirectoryLayout::default(),
+            foo.hash
+        )));
+        assert_eq!(store.relayout(DirectoryLayout::FLAT).unwrap(), 1);
+        assert!(store.remove(foo.hash).unwrap());
+        assert!(!temp_dir.exists(DirectoryBlobStore::outboard_path(
+            DirectoryLayout::FLAT,
+            foo.hash
+        )));
+    }
+
+    #[cfg(all(feature = "gzip", feature = "lz4"))]
+    #[test]
+    fn test_filters() {
+        use crate::{GzipCompressor, Lz4Compressor};
+        let temp_dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        let config = BlobStoreOptions::default()
+            .filter(Box::new(GzipCompressor {}))
+            .filter(Box::new(Lz4Compressor {}));
+        let mut store = DirectoryBlobStore::open_tempdir(&temp_dir, config).unwrap();
+
+        let text = "Foo".repeat(1000);
+        let (_, foo) = store.put_string(&text).unwrap();
+        let foo = store.get_by_id(foo.id).unwrap().unwrap();
+        assert_eq!(foo.size, text.len() as u64);
+        let mut buffer = String::new();
+        let foo_data = foo.data.unwrap();
+        foo_data
+            .lock()
+            .unwrap()
+            .read_to_string(&mut buffer)
+            .unwrap();
+        assert_eq!(buffer, text);
+        let range = store.get_range(foo.hash, 2, 4).unwrap().unwrap();
+        assert_eq!(range, b"oFoo");
+
+        // The envelope selects the decoders even without configured filters:
+        let mut store =
+            DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default()).unwrap();
+        let foo = store.get_by_hash(foo.hash).unwrap().unwrap();
+        assert_eq!(foo.size, text.len() as u64);
+
+        // Unfiltered data that looks like an envelope is escaped:
+        let mut tricky = ENVELOPE_MAGIC.to_vec();
+        tricky.extend_from_slice(b"\x01\x00");
+        let (_, blob) = store.put(&mut &tricky[..]).unwrap();
+        let blob = store.get_by_hash(blob.hash).unwrap().unwrap();
+        let mut buffer = Vec::new();
+        let blob_data = blob.data.unwrap();
+        blob_data.lock().unwrap().read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, tricky);
     }
-    Ok(len)
 }
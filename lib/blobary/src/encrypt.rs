@@ -1 +1,295 @@
 // This is free and unencumbered software released into the public domain.
+
+use crate::{Filter, FilterID};
+use chacha20poly1305::{
+    aead::{
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        OsRng, Payload,
+    },
+    KeyInit, XChaCha20Poly1305,
+};
+use std::{
+    fmt::{self, Debug, Formatter},
+    io::{self, Read, Result, Write},
+    mem::size_of,
+};
+use zerocopy::{AsBytes, FromBytes};
+use zerocopy_derive::{AsBytes, FromBytes, FromZeroes, Unaligned};
+use zeroize::Zeroizing;
+
+pub const KEY_LEN: usize = 32;
+pub const HEADER_SIZE: usize = size_of::<EncryptionHeader>();
+
+const MAGIC: [u8; 4] = *b"BLBE";
+const VERSION: u8 = 1;
+const ALGORITHM_XCHACHA20POLY1305: u8 = 1;
+const CHUNK_SIZE_LOG2: u8 = 16; // 64 KiB plaintext per chunk
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 19; // 24-byte XChaCha20 nonce minus the STREAM counter
+const KEY_ID_CONTEXT: &str = "blobary 2023-11-20 encryption key identifier";
+
+/// The header preceding each encrypted blob. It is authenticated as the
+/// associated data of every chunk, so that it can't be altered either.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct EncryptionHeader {
+    magic: [u8; 4],
+    version: u8,
+    algorithm: u8,
+    chunk_size_log2: u8,
+    reserved: u8,
+    key_id: [u8; 8],
+    nonce: [u8; NONCE_LEN],
+}
+
+const _: () = assert!(
+    size_of::<EncryptionHeader>() == 35,
+    "sizeof(EncryptionHeader) == 35"
+);
+
+/// A symmetric encryption key, wiped from memory when dropped.
+#[derive(Clone)]
+pub struct EncryptionKey(Zeroizing<[u8; KEY_LEN]>);
+
+impl EncryptionKey {
+    /// Generates a new random key.
+    pub fn generate() -> Self {
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        OsRng.fill_bytes(key.as_mut());
+        Self(key)
+    }
+
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Self(Zeroizing::new(bytes))
+    }
+
+    pub fn from_hex(input: impl AsRef<[u8]>) -> Option<Self> {
+        let input = input.as_ref();
+        if input.len() != KEY_LEN * 2 {
+            return None;
+        }
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        for (byte, pair) in key.iter_mut().zip(input.chunks_exact(2)) {
+            let pair = std::str::from_utf8(pair).ok()?;
+            *byte = u8::from_str_radix(pair, 16).ok()?;
+        }
+        Some(Self(key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> Zeroizing<String> {
+        Zeroizing::new(self.0.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    /// Returns a short, non-secret identifier used to detect wrong keys.
+    pub fn id(&self) -> [u8; 8] {
+        let derived = blake3::derive_key(KEY_ID_CONTEXT, self.0.as_ref());
+        derived[..8].try_into().unwrap()
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey {{ ... }}")
+    }
+}
+
+/// A filter that encrypts blobs with XChaCha20-Poly1305 using the STREAM
+/// construction, so that arbitrarily large blobs are encrypted and
+/// authenticated in fixed-size chunks.
+pub struct Encryptor {
+    key: EncryptionKey,
+}
+
+impl Encryptor {
+    pub fn new(key: EncryptionKey) -> Self {
+        Self { key }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(self.key.as_bytes().into())
+    }
+}
+
+impl Filter for Encryptor {
+    fn id(&self) -> FilterID {
+        FilterID::ENCRYPT
+    }
+
+    fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<u64> {
+        let mut header = EncryptionHeader {
+            magic: MAGIC,
+            version: VERSION,
+            algorithm: ALGORITHM_XCHACHA20POLY1305,
+            chunk_size_log2: CHUNK_SIZE_LOG2,
+            reserved: 0,
+            key_id: self.key.id(),
+            nonce: [0u8; NONCE_LEN],
+        };
+        OsRng.fill_bytes(&mut header.nonce);
+        let aad = header.as_bytes();
+        output.write_all(aad)?;
+
+        let mut encryptor = EncryptorBE32::from_aead(self.cipher(), (&header.nonce).into());
+        let chunk_size = 1usize << CHUNK_SIZE_LOG2;
+        let mut chunk = Zeroizing::new(vec![0u8; chunk_size]);
+        let mut next_chunk = Zeroizing::new(vec![0u8; chunk_size]);
+        let mut chunk_len = read_full(input, &mut chunk)?;
+        let mut result = chunk_len as u64;
+        loop {
+            // A short chunk can only be the last one; a full one needs lookahead:
+            let next_len = match chunk_len {
+                n if n < chunk_size => 0,
+                _ => read_full(input, &mut next_chunk)?,
+            };
+            if next_len == 0 {
+                let ciphertext = encryptor
+                    .encrypt_last(Payload {
+                        msg: &chunk[..chunk_len],
+                        aad,
+                    })
+                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+                output.write_all(&ciphertext)?;
+                return Ok(result);
+            }
+            let ciphertext = encryptor
+                .encrypt_next(Payload {
+                    msg: &chunk[..chunk_len],
+                    aad,
+                })
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+            output.write_all(&ciphertext)?;
+            std::mem::swap(&mut chunk, &mut next_chunk);
+            chunk_len = next_len;
+            result += chunk_len as u64;
+        }
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<u64> {
+        let mut buffer = [0u8; HEADER_SIZE];
+        input.read_exact(&mut buffer)?;
+        let header = EncryptionHeader::read_from(&buffer[..]).unwrap();
+        if header.magic != MAGIC {
+            return Err(invalid_data("not an encrypted blob"));
+        }
+        if header.version != VERSION || header.algorithm != ALGORITHM_XCHACHA20POLY1305 {
+            return Err(invalid_data("unsupported blob encryption version"));
+        }
+        if !(10..=24).contains(&header.chunk_size_log2) {
+            return Err(invalid_data("invalid blob encryption chunk size"));
+        }
+        if header.key_id != self.key.id() {
+            return Err(invalid_data("blob was encrypted with a different key"));
+        }
+
+        let aad = &buffer[..];
+        let mut decryptor = DecryptorBE32::from_aead(self.cipher(), (&header.nonce).into());
+        let chunk_size = (1usize << header.chunk_size_log2) + TAG_LEN;
+        let mut chunk = vec![0u8; chunk_size];
+        let mut next_chunk = vec![0u8; chunk_size];
+        let mut chunk_len = read_full(input, &mut chunk)?;
+        let mut result = 0u64;
+        loop {
+            let next_len = match chunk_len {
+                n if n < chunk_size => 0,
+                _ => read_full(input, &mut next_chunk)?,
+            };
+            if next_len == 0 {
+                let plaintext = Zeroizing::new(
+                    decryptor
+                        .decrypt_last(Payload {
+                            msg: &chunk[..chunk_len],
+                            aad,
+                        })
+                        .map_err(|_| invalid_data("encrypted blob failed authentication"))?,
+                );
+                output.write_all(&plaintext)?;
+                return Ok(result + plaintext.len() as u64);
+            }
+            let plaintext = Zeroizing::new(
+                decryptor
+                    .decrypt_next(Payload {
+                        msg: &chunk[..chunk_len],
+                        aad,
+                    })
+                    .map_err(|_| invalid_data("encrypted blob failed authentication"))?,
+            );
+            output.write_all(&plaintext)?;
+            result += plaintext.len() as u64;
+            std::mem::swap(&mut chunk, &mut next_chunk);
+            chunk_len = next_len;
+        }
+    }
+}
+
+/// Reads until the buffer is full or the input is exhausted.
+fn read_full(input: &mut dyn Read, buffer: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match input.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let key = EncryptionKey::generate();
+        assert_eq!(
+            EncryptionKey::from_hex(key.to_hex().as_bytes())
+                .unwrap()
+                .as_bytes(),
+            key.as_bytes()
+        );
+        let filter = Encryptor::new(key.clone());
+
+        for size in [0, 1, 1 << CHUNK_SIZE_LOG2, (3 << CHUNK_SIZE_LOG2) + 7] {
+            let plaintext: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let mut ciphertext = Vec::new();
+            let n = filter.encode(&mut &plaintext[..], &mut ciphertext).unwrap();
+            assert_eq!(n, size as u64);
+
+            let mut decoded = Vec::new();
+            let n = filter.decode(&mut &ciphertext[..], &mut decoded).unwrap();
+            assert_eq!(n, size as u64);
+            assert_eq!(decoded, plaintext);
+
+            // Tampering with any chunk is detected:
+            let mut tampered = ciphertext.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(filter.decode(&mut &tampered[..], &mut Vec::new()).is_err());
+
+            // Truncation is detected:
+            let truncated = &ciphertext[..ciphertext.len() - 1];
+            assert!(filter.decode(&mut &truncated[..], &mut Vec::new()).is_err());
+
+            // Tampering with the header is detected:
+            let mut tampered = ciphertext.clone();
+            tampered[7] ^= 1; // the reserved byte
+            assert!(filter.decode(&mut &tampered[..], &mut Vec::new()).is_err());
+            let mut tampered = ciphertext.clone();
+            tampered[HEADER_SIZE - 1] ^= 1; // the nonce
+            assert!(filter.decode(&mut &tampered[..], &mut Vec::new()).is_err());
+
+            // A different key is detected:
+            let other = Encryptor::new(EncryptionKey::generate());
+            assert!(other.decode(&mut &ciphertext[..], &mut Vec::new()).is_err());
+        }
+    }
+}
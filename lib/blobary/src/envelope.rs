@@ -0,0 +1,164 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::{Filter, FilterID, NoopFilter};
+use std::io::{self, Read, Result, Seek, SeekFrom, Write};
+
+/// The magic bytes starting every blob envelope.
+pub const ENVELOPE_MAGIC: [u8; 8] = *b"\x89BLOBENV";
+
+const ENVELOPE_VERSION: u8 = 1;
+
+/// The header preceding a filtered blob, recording the filter chain that
+/// encoded it so that reads can select the matching decoders.
+///
+/// After the magic bytes come the version, the number of filters, and for
+/// each filter in encoding order its identifier and length-prefixed
+/// parameters.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Envelope {
+    pub filters: Vec<(FilterID, Vec<u8>)>,
+}
+
+impl Envelope {
+    pub fn new(filters: &[Box<dyn Filter>]) -> Self {
+        Self {
+            filters: filters
+                .iter()
+                .map(|filter| (filter.id(), filter.params()))
+                .collect(),
+        }
+    }
+
+    /// Determines if the data must be wrapped in an envelope to be read back
+    /// unambiguously, which is when it's filtered or looks like an envelope.
+    pub fn is_required(filters: &[Box<dyn Filter>], data_prefix: &[u8]) -> bool {
+        !filters.is_empty() || data_prefix.starts_with(&ENVELOPE_MAGIC)
+    }
+
+    pub fn write_to(&self, output: &mut dyn Write) -> Result<()> {
+        let mut buffer = Vec::with_capacity(ENVELOPE_MAGIC.len() + 2);
+        buffer.extend_from_slice(&ENVELOPE_MAGIC);
+        buffer.push(ENVELOPE_VERSION);
+        buffer
+            .push(u8::try_from(self.filters.len()).map_err(|_| invalid_input("too many filters"))?);
+        for (filter_id, params) in &self.filters {
+            buffer.push(filter_id.0);
+            buffer.push(
+                u8::try_from(params.len())
+                    .map_err(|_| invalid_input("filter parameters too long"))?,
+            );
+            buffer.extend_from_slice(params);
+        }
+        output.write_all(&buffer)
+    }
+
+    /// Reads the envelope at the start of the input, leaving the input
+    /// positioned at the blob content. If there is no envelope, the input is
+    /// rewound and `None` is returned.
+    pub fn read_from<R: Read + Seek + ?Sized>(input: &mut R) -> Result<Option<Self>> {
+        let mut magic = [0u8; ENVELOPE_MAGIC.len()];
+        let mut len = 0;
+        while len < magic.len() {
+            match input.read(&mut magic[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        if len < magic.len() || magic != ENVELOPE_MAGIC {
+            input.seek(SeekFrom::Start(0))?;
+            return Ok(None);
+        }
+
+        let mut header = [0u8; 2];
+        input.read_exact(&mut header)?;
+        if header[0] != ENVELOPE_VERSION {
+            return Err(invalid_data("unsupported blob envelope version"));
+        }
+        let mut filters = Vec::with_capacity(header[1] as usize);
+        for _ in 0..header[1] {
+            let mut filter_header = [0u8; 2];
+            input.read_exact(&mut filter_header)?;
+            let mut params = vec![0u8; filter_header[1] as usize];
+            input.read_exact(&mut params)?;
+            filters.push((FilterID(filter_header[0]), params));
+        }
+        Ok(Some(Self { filters }))
+    }
+
+    /// Picks a decoder for each filter in the envelope, preferring the given
+    /// configured filters and falling back to the built-in stateless ones.
+    pub fn resolve<'a>(&self, configured: &'a [Box<dyn Filter>]) -> Result<Vec<&'a dyn Filter>> {
+        self.filters
+            .iter()
+            .map(|(filter_id, params)| {
+                configured
+                    .iter()
+                    .find(|filter| filter.id() == *filter_id && filter.params() == *params)
+                    .or_else(|| configured.iter().find(|filter| filter.id() == *filter_id))
+                    .map(|filter| filter.as_ref())
+                    .or_else(|| builtin_filter(*filter_id))
+                    .ok_or_else(|| {
+                        invalid_data(&format!(
+                            "blob requires the unconfigured filter {}",
+                            filter_id
+                        ))
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Returns a decoder for filters that need no configuration.
+pub(crate) fn builtin_filter(filter_id: FilterID) -> Option<&'static dyn Filter> {
+    static NOOP: NoopFilter = NoopFilter {};
+    #[cfg(feature = "gzip")]
+    static GZIP: crate::GzipCompressor = crate::GzipCompressor {};
+    #[cfg(feature = "lz4")]
+    static LZ4: crate::Lz4Compressor = crate::Lz4Compressor {};
+    #[cfg(feature = "zstd")]
+    static ZSTD: crate::ZstdCompressor = crate::ZstdCompressor::new();
+    match filter_id {
+        FilterID::NOOP => Some(&NOOP),
+        #[cfg(feature = "gzip")]
+        FilterID::GZIP => Some(&GZIP),
+        #[cfg(feature = "lz4")]
+        FilterID::LZ4 => Some(&LZ4),
+        #[cfg(feature = "zstd")]
+        FilterID::ZSTD => Some(&ZSTD),
+        _ => None,
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test() {
+        let envelope = Envelope {
+            filters: vec![(FilterID::GZIP, vec![]), (FilterID(42), vec![1, 2, 3])],
+        };
+        let mut buffer = Vec::new();
+        envelope.write_to(&mut buffer).unwrap();
+        buffer.extend_from_slice(b"data");
+
+        let mut input = Cursor::new(&buffer[..]);
+        assert_eq!(Envelope::read_from(&mut input).unwrap(), Some(envelope));
+        let mut data = Vec::new();
+        input.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"data");
+
+        let mut input = Cursor::new(&b"raw data"[..]);
+        assert_eq!(Envelope::read_from(&mut input).unwrap(), None);
+        assert_eq!(input.position(), 0);
+    }
+}
@@ -24,10 +24,23 @@ pub enum BlobStoreError {
     Unexpected,
     #[error("blob was removed")]
     Removed,
+    #[error("blob exceeds the maximum size of {0} bytes")]
+    TooLarge(u64),
     #[error(transparent)]
-    IO(#[from] std::io::Error),
+    IO(std::io::Error),
     #[error(transparent)]
-    Other(#[from] Box<dyn std::error::Error>),
+    Other(#[from] Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl From<std::io::Error> for BlobStoreError {
+    fn from(error: std::io::Error) -> Self {
+        // Unwrap store errors raised from within readers, such as when a
+        // blob being streamed exceeds the maximum size:
+        if error.get_ref().is_some_and(|inner| inner.is::<Self>()) {
+            return *error.into_inner().unwrap().downcast::<Self>().unwrap();
+        }
+        Self::IO(error)
+    }
 }
 
 #[cfg(feature = "redis")]
@@ -53,8 +66,15 @@ impl From<s3::error::S3Error> for BlobStoreError {
     fn from(error: s3::error::S3Error) -> Self {
         use s3::error::S3Error::*;
         match error {
-            Io(error) => Self::IO(error),
+            Io(error) => error.into(),
             _ => Self::Other(Box::new(error)),
         }
     }
 }
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for BlobStoreError {
+    fn from(error: rusqlite::Error) -> Self {
+        Self::Other(Box::new(error))
+    }
+}
@@ -18,6 +18,10 @@ pub static FEATURES: &[&str] = &[
     "s3",
     #[cfg(feature = "sqlite")]
     "sqlite",
+    #[cfg(feature = "tokio")]
+    "tokio",
     #[cfg(feature = "tracing")]
     "tracing",
+    #[cfg(feature = "zstd")]
+    "zstd",
 ];
@@ -1,5 +1,7 @@
 // This is free and unencumbered software released into the public domain.
 
+mod record;
 mod store;
 
+pub use record::*;
 pub use store::*;
@@ -0,0 +1,64 @@
+// This is free and unencumbered software released into the public domain.
+
+use std::mem::size_of;
+use zerocopy::byteorder::network_endian::U64;
+use zerocopy_derive::{AsBytes, FromBytes, FromZeroes, Unaligned};
+
+pub const PACKED_MAGIC: [u8; 8] = *b"BLOBARY1";
+
+/// The magic bytes ending a footer for records appended since the last
+/// full index.
+pub const PACKED_APPEND_MAGIC: [u8; 8] = *b"BLOBARYA";
+pub const PACKED_RECORD_SIZE: usize = size_of::<PackedBlobRecord>();
+pub const PACKED_FOOTER_SIZE: usize = size_of::<PackedFileFooter>();
+
+/// The offset marking a packed record whose blob has been removed.
+///
+/// Among appended records, it instead marks a removal record, whose size
+/// field holds the ID of the removed blob.
+pub(crate) const REMOVED_OFFSET: u64 = u64::MAX;
+
+/// The flag set in a packed record's offset when the blob body is followed
+/// by its metadata record, prefixed by the record's 32-bit length.
+pub(crate) const METADATA_FLAG: u64 = 1 << 63;
+
+/// An index entry: the blob's hash, its size, and its offset in the file.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub(crate) struct PackedBlobRecord(pub [u8; 32], pub U64, pub U64);
+
+const _: () = assert!(
+    size_of::<PackedBlobRecord>() == 48,
+    "sizeof(PackedBlobRecord) == 48"
+);
+
+impl PackedBlobRecord {
+    pub(crate) fn is_removed(&self) -> bool {
+        self.2.get() == REMOVED_OFFSET
+    }
+
+    /// Returns where the blob body starts in the file.
+    pub(crate) fn offset(&self) -> u64 {
+        self.2.get() & !METADATA_FLAG
+    }
+
+    /// Determines if the blob body is followed by a metadata record.
+    pub(crate) fn has_metadata(&self) -> bool {
+        !self.is_removed() && self.2.get() & METADATA_FLAG != 0
+    }
+}
+
+/// The trailer at the end of the file: the index offset, the record count,
+/// and the magic bytes.
+///
+/// With [`PACKED_APPEND_MAGIC`], the footer instead follows just the records
+/// appended by the latest write, and the offset is where the previous footer
+/// ends, so that the footers form a chain back to the last full index.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub(crate) struct PackedFileFooter(pub U64, pub U64, pub [u8; 8]);
+
+const _: () = assert!(
+    size_of::<PackedFileFooter>() == 24,
+    "sizeof(PackedFileFooter) == 24"
+);
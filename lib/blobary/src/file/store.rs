@@ -1,47 +1,694 @@
 // This is free and unencumbered software released into the public domain.
 
 use crate::{
-    Blob, BlobHash, BlobID, BlobStore, BlobStoreError, BlobStoreExt, IndexedBlobStore, Result,
+    parse_id_token, Blob, BlobData, BlobHash, BlobID, BlobMetadata, BlobPage, BlobStore,
+    BlobStoreError, BlobStoreExt, BlobStoreOptions, HashingReader, IndexedBlobStore,
+    PackedBlobRecord, PackedFileFooter, Result, LIST_PAGE_SIZE, METADATA_FLAG, PACKED_APPEND_MAGIC,
+    PACKED_FOOTER_SIZE, PACKED_MAGIC, PACKED_RECORD_SIZE, REMOVED_OFFSET,
 };
-use std::io::Read;
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    os::unix::prelude::FileExt,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use zerocopy::{AsBytes, FromBytes};
+
+/// The number of records that may be appended before a full index is
+/// written, unless the last full index has more records.
+const MIN_APPEND_COUNT: usize = 1024;
+
+/// A single-file blob store.
+///
+/// The file starts with the magic bytes, followed by the blob bodies in the
+/// order they were added, each followed by its metadata record, its index
+/// record, and a footer chaining back to the previous one, and from time to
+/// time by a full index of packed records and its footer instead. Removals
+/// append a record marking the blob removed, with its own footer, until the
+/// next full index marks the blob's record itself. New data is always
+/// written past the last footer, so that an interrupted write leaves the
+/// previous state intact. Blobs stored before metadata records were kept
+/// have none, which their index records tell apart.
+pub struct FileBlobStore {
+    pub(crate) config: BlobStoreOptions,
+    file: Arc<std::fs::File>,
+    index: Vec<PackedBlobRecord>,
+    end: u64,            // where the last footer ends
+    full_count: usize,   // records in the last full index
+    append_count: usize, // records appended since
+    lookup_id: HashMap<BlobHash, BlobID>,
+}
+
+impl FileBlobStore {
+    pub fn open_path(path: impl AsRef<Path>, config: BlobStoreOptions) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(config.writable)
+            .create(config.writable)
+            .truncate(false)
+            .open(path)?;
+        Self::open_file(file, config)
+    }
+
+    pub fn open_file(file: std::fs::File, config: BlobStoreOptions) -> Result<Self> {
+        config.check_unfiltered("single-file")?;
+        let file_size = file.metadata()?.len();
+
+        // Initialize a new, empty store:
+        if file_size == 0 {
+            let mut store = Self {
+                config,
+                file: Arc::new(file),
+                index: Vec::new(),
+                end: PACKED_MAGIC.len() as u64,
+                full_count: 0,
+                append_count: 0,
+                lookup_id: HashMap::new(),
+            };
+            if store.config.writable {
+                store.file.write_all_at(&PACKED_MAGIC, 0)?;
+                store.write_index()?;
+            }
+            return Ok(store);
+        }
+
+        // Verify the header, and read the index ending with the last footer,
+        // or else with the last intact one if a write was torn:
+        let mut magic = [0u8; 8];
+        file.read_exact_at(&mut magic, 0)?;
+        if magic != PACKED_MAGIC || file_size < (magic.len() + PACKED_FOOTER_SIZE) as u64 {
+            return Err(invalid_data("not a packed blob store"));
+        }
+        let (end, (index, full_count, append_count)) = match read_index(&file, file_size)? {
+            Some(index) => (file_size, index),
+            None => recover_index(&file, file_size)?
+                .ok_or_else(|| invalid_data("missing packed blob store footer"))?,
+        };
+        if end < file_size && config.writable {
+            file.set_len(end)?;
+        }
+
+        let mut lookup_id = HashMap::with_capacity(index.len());
+        for (record_id, record) in index.iter().enumerate() {
+            if !record.is_removed() {
+                lookup_id.insert(record.0.into(), record_id + 1);
+            }
+        }
+
+        Ok(Self {
+            config,
+            file: Arc::new(file),
+            full_count,
+            append_count,
+            index,
+            end,
+            lookup_id,
+        })
+    }
+
+    /// Writes out the full index and its footer past the last footer.
+    fn write_index(&mut self) -> Result<()> {
+        let mut buffer = Vec::with_capacity((self.index.len() + 1) * PACKED_RECORD_SIZE);
+        for record in &self.index {
+            buffer.extend_from_slice(record.as_bytes());
+        }
+        let footer = PackedFileFooter(
+            self.end.into(),
+            (self.index.len() as u64).into(),
+            PACKED_MAGIC,
+        );
+        buffer.extend_from_slice(footer.as_bytes());
+        self.write_tail(&buffer)?;
+        self.full_count = self.index.len();
+        self.append_count = 0;
+        Ok(())
+    }
+
+    /// Writes out the given record and a footer chaining back to the
+    /// previous one, ending at `prev_end`, or the full index once enough
+    /// records were appended.
+    fn append_index(&mut self, record: PackedBlobRecord, prev_end: u64) -> Result<()> {
+        if self.append_count >= self.full_count.max(MIN_APPEND_COUNT) {
+            return self.write_index();
+        }
+        let mut buffer = Vec::with_capacity(PACKED_RECORD_SIZE + PACKED_FOOTER_SIZE);
+        buffer.extend_from_slice(record.as_bytes());
+        let footer = PackedFileFooter(prev_end.into(), 1.into(), PACKED_APPEND_MAGIC);
+        buffer.extend_from_slice(footer.as_bytes());
+        self.write_tail(&buffer)?;
+        self.append_count += 1;
+        Ok(())
+    }
+
+    /// Durably writes the data ending with a footer past the last footer,
+    /// which then becomes the last one.
+    fn write_tail(&mut self, buffer: &[u8]) -> Result<()> {
+        let result = self
+            .file
+            .write_all_at(buffer, self.end)
+            .and_then(|_| self.file.sync_data());
+        if let Err(err) = result {
+            let _ = self.file.set_len(self.end);
+            return Err(err.into());
+        }
+        self.end += buffer.len() as u64;
+        Ok(())
+    }
+
+    fn read_blob(&self, blob_id: BlobID) -> Result<Option<Blob>> {
+        let record = match blob_id {
+            0 => return Ok(None),
+            _ => match self.index.get(blob_id - 1) {
+                None => return Ok(None),
+                Some(record) => record,
+            },
+        };
+        if record.is_removed() {
+            return Err(BlobStoreError::Removed);
+        }
+        let blob_offset = record.offset();
+        let blob_size = record.1.get();
+        Ok(Some(Blob {
+            id: blob_id,
+            hash: record.0.into(),
+            size: blob_size,
+            data: Some(Arc::new(Mutex::new(PackedBlobData {
+                file: self.file.clone(),
+                offset: blob_offset,
+                size: blob_size,
+                pos: 0,
+            }))),
+            metadata: self.read_metadata(record)?,
+        }))
+    }
 
-#[derive(Default)]
-pub struct FileBlobStore {}
+    /// Reads the metadata record following the blob body, if any.
+    fn read_metadata(&self, record: &PackedBlobRecord) -> Result<Option<BlobMetadata>> {
+        if !record.has_metadata() {
+            return Ok(None); // stored before metadata was recorded
+        }
+        let metadata_offset = record.offset() + record.1.get();
+        let mut metadata_size = [0u8; 4];
+        self.file
+            .read_exact_at(&mut metadata_size, metadata_offset)?;
+        let mut buffer = vec![0u8; u32::from_be_bytes(metadata_size) as usize];
+        self.file
+            .read_exact_at(&mut buffer, metadata_offset + metadata_size.len() as u64)?;
+        Ok(Some(BlobMetadata::from_bytes(&buffer)?))
+    }
+}
 
 impl BlobStore for FileBlobStore {
     fn count(&self) -> Result<BlobID> {
-        Err(BlobStoreError::Unimplemented("count".to_string())) // TODO
+        Ok(self.lookup_id.len())
+    }
+
+    fn contains_hash(&self, blob_hash: BlobHash) -> Result<bool> {
+        Ok(self.lookup_id.contains_key(&blob_hash))
+    }
+
+    fn get_by_hash(&self, blob_hash: BlobHash) -> Result<Option<Blob>> {
+        match self.lookup_id.get(&blob_hash) {
+            None => Ok(None),
+            Some(blob_id) => self.read_blob(*blob_id),
+        }
+    }
+
+    fn put(&mut self, blob_data: &mut dyn Read) -> Result<(bool, Blob)> {
+        self.put_with_metadata(blob_data, BlobMetadata::new())
+    }
+
+    fn put_with_metadata(
+        &mut self,
+        blob_data: &mut dyn Read,
+        metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
+        if !self.config.writable {
+            return Err(crate::BlobStoreError::NotWritable);
+        }
+
+        // Append the blob data past the last footer, hashing it on the way:
+        let blob_offset = self.end;
+        let mut data_reader = HashingReader::with_max_size(blob_data, self.config.max_blob_size);
+        let mut buffer = [0u8; 64 * 1024];
+        let mut written = 0u64;
+        let result = loop {
+            let len = match data_reader.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(len) => len,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => break Err(err),
+            };
+            if let Err(err) = self
+                .file
+                .write_all_at(&buffer[..len], blob_offset + written)
+            {
+                break Err(err);
+            }
+            written += len as u64;
+        };
+        if let Err(err) = result {
+            // Discard the data written so far:
+            let _ = self.file.set_len(self.end);
+            return Err(err.into());
+        }
+        let metadata = metadata.complete(data_reader.prefix());
+        let (blob_hash, blob_size) = data_reader.finalize();
+
+        // Check if the blob is already in the store, and if so, discard it:
+        if let Some(blob_id) = self.lookup_id.get(&blob_hash).copied() {
+            self.file.set_len(self.end)?;
+            return Ok((
+                false,
+                Blob {
+                    id: blob_id,
+                    hash: blob_hash,
+                    size: blob_size,
+                    data: None,
+                    metadata: self.read_metadata(&self.index[blob_id - 1])?,
+                },
+            ));
+        }
+
+        // Follow the blob data with its metadata record, and make both
+        // durable before indexing them:
+        let metadata_bytes = metadata.to_bytes();
+        let mut metadata_record = Vec::with_capacity(4 + metadata_bytes.len());
+        metadata_record.extend_from_slice(&(metadata_bytes.len() as u32).to_be_bytes());
+        metadata_record.extend_from_slice(&metadata_bytes);
+        let result = self
+            .file
+            .write_all_at(&metadata_record, blob_offset + blob_size)
+            .and_then(|_| self.file.sync_data());
+        if let Err(err) = result {
+            let _ = self.file.set_len(self.end);
+            return Err(err.into());
+        }
+
+        let blob_id: BlobID = self.index.len() + 1;
+        let record = PackedBlobRecord(
+            blob_hash.into(),
+            blob_size.into(),
+            (blob_offset | METADATA_FLAG).into(),
+        );
+        self.index.push(record);
+        self.end = blob_offset + blob_size + metadata_record.len() as u64;
+        if let Err(err) = self.append_index(record, blob_offset) {
+            self.index.pop();
+            self.end = blob_offset;
+            let _ = self.file.set_len(self.end);
+            return Err(err);
+        }
+        self.lookup_id.insert(blob_hash, blob_id);
+
+        Ok((
+            true,
+            Blob {
+                id: blob_id,
+                hash: blob_hash,
+                size: blob_size,
+                data: None,
+                metadata: Some(metadata),
+            },
+        ))
     }
 
-    fn contains_hash(&self, _blob_hash: BlobHash) -> Result<bool> {
-        Err(BlobStoreError::Unimplemented("contains_hash".to_string())) // TODO
+    fn max_blob_size(&self) -> Option<u64> {
+        self.config.max_blob_size
     }
 
-    fn get_by_hash(&self, _blob_hash: BlobHash) -> Result<Option<Blob>> {
-        Err(BlobStoreError::Unimplemented("get_by_hash".to_string())) // TODO
+    fn remove(&mut self, blob_hash: BlobHash) -> Result<bool> {
+        if !self.config.writable {
+            return Err(crate::BlobStoreError::NotWritable);
+        }
+
+        // The blob body stays in the file, but its index record is marked
+        // removed, by an appended removal record until the next full index:
+        let Some(blob_id) = self.lookup_id.get(&blob_hash).copied() else {
+            return Ok(false); // not found
+        };
+        let record = self.index[blob_id - 1];
+        self.index[blob_id - 1].2 = REMOVED_OFFSET.into();
+        let removal = PackedBlobRecord(record.0, (blob_id as u64).into(), REMOVED_OFFSET.into());
+        if let Err(err) = self.append_index(removal, self.end) {
+            self.index[blob_id - 1] = record;
+            return Err(err);
+        }
+        self.lookup_id.remove(&blob_hash);
+        Ok(true)
     }
 
-    fn put(&mut self, _blob_data: &mut dyn Read) -> Result<(bool, Blob)> {
-        Err(BlobStoreError::Unimplemented("put".to_string())) // TODO
+    fn list(&self, token: Option<&str>) -> Result<BlobPage> {
+        let mut blob_id = parse_id_token(token)?;
+        let mut blobs = Vec::new();
+        while blob_id < self.index.len() && blobs.len() < LIST_PAGE_SIZE {
+            let record = &self.index[blob_id];
+            blob_id += 1;
+            if !record.is_removed() {
+                blobs.push((record.0.into(), record.1.get()));
+            }
+        }
+        Ok(BlobPage {
+            blobs,
+            next: (blob_id < self.index.len()).then(|| blob_id.to_string()),
+        })
     }
 
-    fn remove(&mut self, _blob_hash: BlobHash) -> Result<bool> {
-        Err(BlobStoreError::Unimplemented("remove".to_string())) // TODO
+    fn get_metadata(&self, blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        match self.lookup_id.get(&blob_hash) {
+            None => Ok(None),
+            Some(blob_id) => self.read_metadata(&self.index[blob_id - 1]),
+        }
     }
 }
 
 impl IndexedBlobStore for FileBlobStore {
-    fn hash_to_id(&self, _blob_hash: BlobHash) -> Result<Option<BlobID>> {
-        Err(BlobStoreError::Unimplemented("hash_to_id".to_string())) // TODO
+    fn hash_to_id(&self, blob_hash: BlobHash) -> Result<Option<BlobID>> {
+        Ok(self.lookup_id.get(&blob_hash).copied())
     }
 
-    fn id_to_hash(&self, _blob_id: BlobID) -> Result<Option<BlobHash>> {
-        Err(BlobStoreError::Unimplemented("id_to_hash".to_string())) // TODO
+    fn id_to_hash(&self, blob_id: BlobID) -> Result<Option<BlobHash>> {
+        Ok(match blob_id {
+            0 => None,
+            _ => self.index.get(blob_id - 1).map(|record| record.0.into()),
+        })
     }
 
-    fn get_by_id(&self, _blob_id: BlobID) -> Result<Option<Blob>> {
-        Err(BlobStoreError::Unimplemented("get_by_id".to_string())) // TODO
+    fn get_by_id(&self, blob_id: BlobID) -> Result<Option<Blob>> {
+        self.read_blob(blob_id)
     }
 }
 
 impl BlobStoreExt for FileBlobStore {}
+
+/// A seekable view of a single blob's byte range in the packed file.
+struct PackedBlobData {
+    file: Arc<std::fs::File>,
+    offset: u64,
+    size: u64,
+    pos: u64,
+}
+
+impl Read for PackedBlobData {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.size.saturating_sub(self.pos);
+        let len = buf.len().min(remaining as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        let len = self.file.read_at(&mut buf[..len], self.offset + self.pos)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for PackedBlobData {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+impl BlobData for PackedBlobData {
+    fn size(&mut self) -> std::io::Result<u64> {
+        Ok(self.size)
+    }
+}
+
+/// The index records, how many records the last full index has, and how
+/// many were appended since.
+type LoadedIndex = (Vec<PackedBlobRecord>, usize, usize);
+
+/// Reads the index whose last footer ends at `end`, following the chain of
+/// appended records back to the last full index, and applying any removal
+/// records among them. Returns the index and its record counts, or `None` if
+/// there is no valid chain of footers ending there.
+fn read_index(file: &std::fs::File, mut end: u64) -> Result<Option<LoadedIndex>> {
+    let min_end = (PACKED_MAGIC.len() + PACKED_FOOTER_SIZE) as u64;
+    let mut segments = Vec::new();
+    let mut append_count = 0;
+    loop {
+        if end < min_end {
+            return Ok(None);
+        }
+        let footer_offset = end - PACKED_FOOTER_SIZE as u64;
+        let mut buffer = [0u8; PACKED_FOOTER_SIZE];
+        file.read_exact_at(&mut buffer, footer_offset)?;
+        let footer = PackedFileFooter::read_from(&buffer).unwrap();
+        let Some(records_size) = footer
+            .1
+            .get()
+            .checked_mul(PACKED_RECORD_SIZE as u64)
+            .filter(|size| *size <= footer_offset - PACKED_MAGIC.len() as u64)
+        else {
+            return Ok(None);
+        };
+        let records_offset = footer_offset - records_size;
+        let is_full = match footer.2 {
+            PACKED_MAGIC if footer.0.get() == records_offset => true,
+            PACKED_APPEND_MAGIC if footer.0.get() <= records_offset => false,
+            _ => return Ok(None),
+        };
+
+        let mut buffer = vec![0u8; records_size as usize];
+        file.read_exact_at(&mut buffer, records_offset)?;
+        let records: Vec<_> = buffer
+            .chunks_exact(PACKED_RECORD_SIZE)
+            .map(|chunk| PackedBlobRecord::read_from(chunk).unwrap())
+            .collect();
+        if is_full {
+            segments.push(records);
+            break;
+        }
+        append_count += records.len();
+        segments.push(records);
+        end = footer.0.get();
+    }
+    let mut segments = segments.into_iter().rev();
+    let mut index = segments.next().unwrap_or_default();
+    let full_count = index.len();
+    for record in segments.flatten() {
+        if !record.is_removed() {
+            index.push(record);
+            continue;
+        }
+        let removed = usize::try_from(record.1.get())
+            .ok()
+            .and_then(|blob_id| index.get_mut(blob_id.checked_sub(1)?));
+        match removed {
+            Some(removed) if removed.0 == record.0 => removed.2 = REMOVED_OFFSET.into(),
+            _ => return Ok(None), // not a valid removal record
+        }
+    }
+    Ok(Some((index, full_count, append_count)))
+}
+
+/// Looks for the last intact footer, for a file whose last write was torn,
+/// returning where it ends along with its index.
+fn recover_index(file: &std::fs::File, file_size: u64) -> Result<Option<(u64, LoadedIndex)>> {
+    const CHUNK_SIZE: u64 = 64 * 1024;
+    let magic_len = PACKED_MAGIC.len() as u64;
+    let min_start = (PACKED_MAGIC.len() + PACKED_FOOTER_SIZE) as u64 - magic_len;
+    let mut buffer = vec![0u8; (CHUNK_SIZE + magic_len) as usize];
+
+    // Scan backwards for the magic bytes ending a footer:
+    let mut chunk_end = (file_size + 1).saturating_sub(magic_len);
+    while chunk_end > min_start {
+        let chunk_start = chunk_end.saturating_sub(CHUNK_SIZE).max(min_start);
+        let len = (chunk_end - chunk_start + magic_len - 1) as usize;
+        file.read_exact_at(&mut buffer[..len], chunk_start)?;
+        for pos in (0..(chunk_end - chunk_start) as usize).rev() {
+            let magic = &buffer[pos..pos + PACKED_MAGIC.len()];
+            if magic == PACKED_MAGIC || magic == PACKED_APPEND_MAGIC {
+                let end = chunk_start + pos as u64 + magic_len;
+                if let Some(index) = read_index(file, end)? {
+                    return Ok(Some((end, index)));
+                }
+            }
+        }
+        chunk_end = chunk_start;
+    }
+    Ok(None)
+}
+
+fn invalid_data(message: &str) -> BlobStoreError {
+    std::io::Error::new(ErrorKind::InvalidData, message).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn create_file(temp_dir: &cap_tempfile::TempDir) -> std::fs::File {
+        let options = cap_std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .clone();
+        temp_dir
+            .open_with("test.blobary", &options)
+            .unwrap()
+            .into_std()
+    }
+
+    #[test]
+    fn test() {
+        let temp_dir = cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+        let temp_file = create_file(&temp_dir);
+        let mut store = FileBlobStore::open_file(temp_file, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.count().unwrap(), 0);
+
+        let (_, foo) = store.put_string("Foo").unwrap();
+        assert_eq!(store.count().unwrap(), 1);
+        assert_eq!(foo.id, 1);
+
+        let (_, foo2) = store.put_string("Foo").unwrap();
+        assert_eq!(store.count().unwrap(), 1);
+        assert_eq!(foo2.id, 1);
+
+        let (_, bar) = store.put_string("Bar").unwrap();
+        assert_eq!(store.count().unwrap(), 2);
+        assert_eq!(bar.id, 2);
+
+        // A rejected oversized blob leaves the index intact:
+        store.config.max_blob_size = Some(3);
+        let large = "Quux".repeat(100_000);
+        assert!(matches!(
+            store.put_string(large),
+            Err(BlobStoreError::TooLarge(3))
+        ));
+
+        let temp_file = temp_dir.open("test.blobary").unwrap().into_std();
+        let store =
+            FileBlobStore::open_file(temp_file, BlobStoreOptions::new().writable(false)).unwrap();
+        assert_eq!(store.count().unwrap(), 2);
+        let page = store.list(None).unwrap();
+        assert_eq!(page.blobs, vec![(foo.hash, 3), (bar.hash, 3)]);
+        assert!(page.next.is_none());
+        let bar = store.get_by_hash(bar.hash).unwrap().unwrap();
+        let mut buffer = String::new();
+        let bar_data = bar.data.unwrap();
+        bar_data
+            .lock()
+            .unwrap()
+            .read_to_string(&mut buffer)
+            .unwrap();
+        assert_eq!(buffer, "Bar");
+        drop(store);
+
+        // A torn write leaves the previous state intact:
+        let mut file = temp_dir.read("test.blobary").unwrap();
+        let file_size = file.len() as u64;
+        file.extend_from_slice(b"Baz");
+        file.extend_from_slice(&PACKED_APPEND_MAGIC);
+        temp_dir.write("test.blobary", &file).unwrap();
+        let temp_file = temp_dir
+            .open_with(
+                "test.blobary",
+                cap_std::fs::OpenOptions::new().read(true).write(true),
+            )
+            .unwrap()
+            .into_std();
+        let mut store = FileBlobStore::open_file(temp_file, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.count().unwrap(), 2);
+        assert_eq!(temp_dir.metadata("test.blobary").unwrap().len(), file_size);
+
+        // Removals append a record rather than rewriting the index:
+        assert!(store.remove(foo.hash).unwrap());
+        assert!(!store.remove(foo.hash).unwrap());
+        let appended = (PACKED_RECORD_SIZE + PACKED_FOOTER_SIZE) as u64;
+        assert_eq!(
+            temp_dir.metadata("test.blobary").unwrap().len(),
+            file_size + appended
+        );
+        drop(store);
+        let temp_file = temp_dir
+            .open_with(
+                "test.blobary",
+                cap_std::fs::OpenOptions::new().read(true).write(true),
+            )
+            .unwrap()
+            .into_std();
+        let mut store = FileBlobStore::open_file(temp_file, BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.count().unwrap(), 1);
+        assert_eq!((store.full_count, store.append_count), (0, 3));
+        assert!(!store.contains_hash(foo.hash).unwrap());
+        assert!(matches!(store.get_by_id(1), Err(BlobStoreError::Removed)));
+
+        // Appended records are folded into a full index from time to time:
+        for n in 0..MIN_APPEND_COUNT + 2 {
+            store.put_string(n.to_string()).unwrap();
+        }
+        assert_eq!(store.append_count, 4);
+        let temp_file = temp_dir.open("test.blobary").unwrap().into_std();
+        let store =
+            FileBlobStore::open_file(temp_file, BlobStoreOptions::new().writable(false)).unwrap();
+        assert_eq!(store.count().unwrap(), MIN_APPEND_COUNT + 3);
+        assert_eq!(store.full_count, MIN_APPEND_COUNT);
+        assert_eq!(store.append_count, 4);
+        assert_eq!(store.get_by_id(2).unwrap().unwrap().hash, bar.hash);
+        assert!(matches!(store.get_by_id(1), Err(BlobStoreError::Removed)));
+
+        // A footer with a corrupt record count is skipped:
+        let mut file = temp_dir.read("test.blobary").unwrap();
+        let footer_offset = file.len() - PACKED_FOOTER_SIZE;
+        file[footer_offset + 8..footer_offset + 16].fill(0xFF);
+        temp_dir.write("test.blobary", &file).unwrap();
+        let temp_file = temp_dir.open("test.blobary").unwrap().into_std();
+        let store =
+            FileBlobStore::open_file(temp_file, BlobStoreOptions::new().writable(false)).unwrap();
+        assert_eq!(store.count().unwrap(), MIN_APPEND_COUNT + 2);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let temp_dir = cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+        let temp_file = create_file(&temp_dir);
+        let mut store = FileBlobStore::open_file(temp_file, BlobStoreOptions::default()).unwrap();
+        let metadata = BlobMetadata::new().name("foo.txt");
+        let (_, foo) = store.put_with_metadata(&mut &b"Foo"[..], metadata).unwrap();
+        let (_, bar) = store.put_string("Bar").unwrap();
+
+        // The first record of a blob is kept:
+        let metadata = BlobMetadata::new().name("baz.txt");
+        let (created, foo2) = store.put_with_metadata(&mut &b"Foo"[..], metadata).unwrap();
+        assert!(!created);
+        assert_eq!(foo2.metadata, foo.metadata);
+
+        let temp_file = temp_dir.open("test.blobary").unwrap().into_std();
+        let store =
+            FileBlobStore::open_file(temp_file, BlobStoreOptions::new().writable(false)).unwrap();
+        let metadata = store.get_metadata(foo.hash).unwrap().unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("foo.txt"));
+        assert!(metadata.created.is_some());
+        let bar = store.get_by_hash(bar.hash).unwrap().unwrap();
+        assert!(bar.metadata.unwrap().name.is_none());
+        let mut buffer = String::new();
+        let bar_data = bar.data.unwrap();
+        bar_data
+            .lock()
+            .unwrap()
+            .read_to_string(&mut buffer)
+            .unwrap();
+        assert_eq!(buffer, "Bar");
+
+        // Records written before metadata was kept have none:
+        let record = PackedBlobRecord(bar.hash.into(), 3.into(), 8.into());
+        assert!(store.read_metadata(&record).unwrap().is_none());
+    }
+}
@@ -1,12 +1,47 @@
 // This is free and unencumbered software released into the public domain.
 
+use cap_std::{ambient_authority, fs::Dir};
+use cap_tempfile::TempFile;
 use std::{
     fmt::{self, Debug, Formatter},
-    io::{self, Read, Result, Write},
+    io::{self, Read, Result, Seek, Write},
 };
 
+/// Identifies a filter in a blob [`Envelope`](crate::Envelope).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FilterID(pub u8);
+
+impl FilterID {
+    pub const NOOP: Self = Self(0);
+    pub const GZIP: Self = Self(1);
+    pub const LZ4: Self = Self(2);
+    pub const ENCRYPT: Self = Self(3);
+    pub const ZSTD: Self = Self(4);
+}
+
+impl fmt::Display for FilterID {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::NOOP => write!(f, "noop"),
+            Self::GZIP => write!(f, "gzip"),
+            Self::LZ4 => write!(f, "lz4"),
+            Self::ENCRYPT => write!(f, "encrypt"),
+            Self::ZSTD => write!(f, "zstd"),
+            Self(id) => write!(f, "#{}", id),
+        }
+    }
+}
+
 /// A filter is a function that transforms a blob.
-pub trait Filter {
+pub trait Filter: Send + Sync {
+    /// Returns the identifier recorded in the envelope of encoded blobs.
+    fn id(&self) -> FilterID;
+
+    /// Returns the parameters recorded in the envelope of encoded blobs.
+    fn params(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
     fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<u64>;
 
     fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<u64>;
@@ -27,6 +62,10 @@ impl Debug for dyn Filter {
 pub struct NoopFilter {}
 
 impl Filter for NoopFilter {
+    fn id(&self) -> FilterID {
+        FilterID::NOOP
+    }
+
     fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<u64> {
         io::copy(input, output)
     }
@@ -35,3 +74,54 @@ impl Filter for NoopFilter {
         io::copy(input, output)
     }
 }
+
+/// Decodes the input through the given filters in reverse order, spooling
+/// the original content into an anonymous temporary file.
+pub(crate) fn decode_into_tempfile(
+    filters: &[&dyn Filter],
+    input: &mut dyn Read,
+) -> Result<cap_std::fs::File> {
+    let temp_dir = Dir::open_ambient_dir(std::env::temp_dir(), ambient_authority())?;
+    let mut output = TempFile::new_anonymous(&temp_dir)?;
+    let mut filters = filters.iter().rev();
+    match filters.next() {
+        None => io::copy(input, &mut output)?,
+        Some(filter) => filter.decode(input, &mut output)?,
+    };
+    for filter in filters {
+        output.rewind()?;
+        let mut next_output = TempFile::new_anonymous(&temp_dir)?;
+        filter.decode(&mut output, &mut next_output)?;
+        output = next_output;
+    }
+    output.rewind()?;
+    Ok(output)
+}
+
+/// Encodes the input through the given filters in order, spooling the
+/// result, preceded by the envelope recording them, into an anonymous
+/// temporary file.
+pub(crate) fn encode_into_tempfile(
+    filters: &[Box<dyn Filter>],
+    input: &mut dyn Read,
+) -> Result<cap_std::fs::File> {
+    let temp_dir = Dir::open_ambient_dir(std::env::temp_dir(), ambient_authority())?;
+    let mut data = TempFile::new_anonymous(&temp_dir)?;
+    let mut filters_iter = filters.iter();
+    match filters_iter.next() {
+        None => io::copy(input, &mut data)?,
+        Some(filter) => filter.encode(input, &mut data)?,
+    };
+    for filter in filters_iter {
+        data.rewind()?;
+        let mut next_data = TempFile::new_anonymous(&temp_dir)?;
+        filter.encode(&mut data, &mut next_data)?;
+        data = next_data;
+    }
+    data.rewind()?;
+    let mut output = TempFile::new_anonymous(&temp_dir)?;
+    crate::Envelope::new(filters).write_to(&mut output)?;
+    io::copy(&mut data, &mut output)?;
+    output.rewind()?;
+    Ok(output)
+}
@@ -1,6 +1,6 @@
 // This is free and unencumbered software released into the public domain.
 
-use crate::BlobHash;
+use crate::{BlobHash, BlobStoreError, MIME_SNIFF_SIZE};
 use std::path::Path;
 
 pub fn hash(input: impl AsRef<[u8]>) -> BlobHash {
@@ -35,3 +35,59 @@ impl std::io::Write for BlobHasher {
         Ok(())
     }
 }
+
+/// A reader that computes the BLAKE3 hash and size of the data read through it.
+pub struct HashingReader<'a> {
+    inner: &'a mut dyn std::io::Read,
+    hasher: BlobHasher,
+    size: u64,
+    max_size: Option<u64>,
+    prefix: Vec<u8>,
+}
+
+impl<'a> HashingReader<'a> {
+    pub fn new(inner: &'a mut dyn std::io::Read) -> Self {
+        Self::with_max_size(inner, None)
+    }
+
+    /// Constructs a reader that fails with [`BlobStoreError::TooLarge`] once
+    /// more than `max_size` bytes have been read.
+    pub fn with_max_size(inner: &'a mut dyn std::io::Read, max_size: Option<u64>) -> Self {
+        Self {
+            inner,
+            hasher: BlobHasher::new(),
+            size: 0,
+            max_size,
+            prefix: Vec::new(),
+        }
+    }
+
+    /// Returns the leading bytes read so far, enough to detect the MIME type.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Returns the hash and size of the data read so far.
+    pub fn finalize(self) -> (BlobHash, u64) {
+        (self.hasher.finalize(), self.size)
+    }
+}
+
+impl<'a> std::io::Read for HashingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.0.update(&buf[..len]);
+        if self.prefix.len() < MIME_SNIFF_SIZE {
+            let prefix_len = len.min(MIME_SNIFF_SIZE - self.prefix.len());
+            self.prefix.extend_from_slice(&buf[..prefix_len]);
+        }
+        self.size += len as u64;
+        match self.max_size {
+            Some(max_size) if self.size > max_size => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                BlobStoreError::TooLarge(max_size),
+            )),
+            _ => Ok(len),
+        }
+    }
+}
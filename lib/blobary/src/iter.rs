@@ -1,7 +1,7 @@
 // This is proprietary and confidential source code not for distribution.
 
-use crate::{Blob, BlobStoreError, IndexedBlobStore};
-use std::iter::Iterator;
+use crate::{Blob, BlobHash, BlobStore, BlobStoreError, IndexedBlobStore, Result};
+use std::{collections::VecDeque, iter::Iterator};
 
 pub struct IndexedBlobStoreIterator<'a> {
     pub(crate) store: &'a mut dyn IndexedBlobStore,
@@ -24,17 +24,70 @@ impl<'a> Iterator for IndexedBlobStoreIterator<'a> {
     type Item = Blob;
 
     fn next(&mut self) -> Option<Self::Item> {
+        // Store IDs of removed blobs aren't reused, so keep going until as
+        // many blobs as the store counts have been returned:
         loop {
-            self.index += 1;
-            if self.index > self.count {
+            if self.count == 0 {
                 return None;
             }
+            self.index += 1;
             match self.store.get_by_id(self.index) {
-                Ok(None) => unreachable!(),
-                Ok(Some(blob)) => return Some(blob),
+                Ok(None) => return None,
+                Ok(Some(blob)) => {
+                    self.count -= 1;
+                    return Some(blob);
+                }
                 Err(BlobStoreError::Removed) => continue,
                 Err(err) => panic!("Failed to read blob #{}: {}", self.index, err),
             }
         }
     }
 }
+
+/// Iterates over the hashes and sizes of blobs in any store, including
+/// stores that don't support lookups by store ID. Pages are listed as the
+/// iteration reaches them, and a failure to list one ends the iteration
+/// after yielding the error. Blobs may repeat, as [`BlobStore::list`] allows.
+pub struct BlobStoreIterator<'a, S: BlobStore + ?Sized> {
+    pub(crate) store: &'a S,
+    pub(crate) page: VecDeque<(BlobHash, u64)>,
+    pub(crate) next: Option<String>,
+    pub(crate) done: bool,
+}
+
+impl<'a, S: BlobStore + ?Sized> BlobStoreIterator<'a, S> {
+    pub fn new(store: &'a S) -> Self {
+        Self {
+            store,
+            page: VecDeque::new(),
+            next: None,
+            done: false,
+        }
+    }
+}
+
+impl<'a, S: BlobStore + ?Sized> Iterator for BlobStoreIterator<'a, S> {
+    type Item = Result<(BlobHash, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.page.pop_front() {
+                return Some(Ok(entry));
+            }
+            if self.done {
+                return None;
+            }
+            match self.store.list(self.next.as_deref()) {
+                Ok(page) => {
+                    self.page.extend(page.blobs);
+                    self.done = page.next.is_none();
+                    self.next = page.next;
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
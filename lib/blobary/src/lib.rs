@@ -1,29 +1,49 @@
 // This is free and unencumbered software released into the public domain.
 
 mod blob;
+mod chunk;
 mod compress;
+mod concat;
 mod dir;
+mod envelope;
 mod error;
 mod feature;
 mod file;
 mod filter;
 mod hasher;
 mod iter;
+mod manifest;
+mod metadata;
+mod outboard;
+mod shared;
 mod store;
 mod temp;
 
 pub use blob::*;
+pub use chunk::*;
 pub use compress::*;
+pub use concat::*;
 pub use dir::*;
+pub use envelope::*;
 pub use error::*;
 pub use feature::*;
 pub use file::*;
 pub use filter::*;
 pub use hasher::*;
 pub use iter::*;
+pub use manifest::*;
+pub use metadata::*;
+pub use outboard::*;
+pub use shared::*;
 pub use store::*;
 pub use temp::*;
 
+#[cfg(feature = "tokio")]
+mod aio;
+
+#[cfg(feature = "tokio")]
+pub use aio::*;
+
 #[cfg(feature = "encrypt")]
 pub mod encrypt;
 
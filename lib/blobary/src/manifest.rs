@@ -0,0 +1,130 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::{BlobHash, PersistentBlobRecord, RECORD_SIZE};
+use std::{
+    io::{self, Read, Result, Write},
+    mem::size_of,
+};
+use zerocopy::{byteorder::network_endian::U64, AsBytes, FromBytes};
+use zerocopy_derive::{AsBytes, FromBytes, FromZeroes, Unaligned};
+
+/// The magic bytes starting every manifest blob.
+pub const MANIFEST_MAGIC: [u8; 8] = *b"\x89BLOBMAN";
+
+/// The header of a manifest blob: the magic bytes, the number of parts, and
+/// the total size of the content.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub(crate) struct ManifestHeader(pub [u8; 8], pub U64, pub U64);
+
+const _: () = assert!(
+    size_of::<ManifestHeader>() == 24,
+    "sizeof(ManifestHeader) == 24"
+);
+
+const HEADER_SIZE: usize = size_of::<ManifestHeader>();
+
+const CHECKSUM_SIZE: usize = blake3::OUT_LEN;
+
+/// An ordered list of blob hashes and sizes whose concatenation makes up a
+/// larger piece of content, stored as a blob of its own.
+///
+/// The manifest is a header followed by one 40-byte record per part, in the
+/// same format as the records of the directory store index, and the BLAKE3
+/// hash of all that. The trailing hash tells manifests apart from other data
+/// that merely starts with the magic bytes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub parts: Vec<(BlobHash, u64)>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, blob_hash: BlobHash, blob_size: u64) {
+        self.parts.push((blob_hash, blob_size));
+    }
+
+    /// Returns the total size of the content.
+    pub fn size(&self) -> u64 {
+        self.parts.iter().map(|(_, size)| size).sum()
+    }
+
+    /// Determines if the given data looks like a manifest. Only
+    /// [`Manifest::read_from`] can tell for sure.
+    pub fn is_manifest(data_prefix: &[u8]) -> bool {
+        data_prefix.starts_with(&MANIFEST_MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer =
+            Vec::with_capacity(HEADER_SIZE + self.parts.len() * RECORD_SIZE + CHECKSUM_SIZE);
+        let header = ManifestHeader(
+            MANIFEST_MAGIC,
+            (self.parts.len() as u64).into(),
+            self.size().into(),
+        );
+        buffer.extend_from_slice(header.as_bytes());
+        for (blob_hash, blob_size) in &self.parts {
+            let record = PersistentBlobRecord((*blob_hash).into(), (*blob_size).into());
+            buffer.extend_from_slice(record.as_bytes());
+        }
+        let checksum = blake3::hash(&buffer);
+        buffer.extend_from_slice(checksum.as_bytes());
+        buffer
+    }
+
+    pub fn write_to(&self, output: &mut dyn Write) -> Result<()> {
+        output.write_all(&self.to_bytes())
+    }
+
+    /// Reads a whole manifest blob, failing with [`io::ErrorKind::InvalidData`]
+    /// if the input isn't one.
+    pub fn read_from(input: &mut dyn Read) -> Result<Self> {
+        let mut hasher = blake3::Hasher::new();
+        let mut buffer = [0u8; HEADER_SIZE];
+        read_exact(input, &mut buffer)?;
+        hasher.update(&buffer);
+        let header = ManifestHeader::read_from(&buffer[..]).unwrap();
+        if header.0 != MANIFEST_MAGIC {
+            return Err(invalid_data("not a manifest blob"));
+        }
+
+        let count = header.1.get();
+        let mut parts = Vec::with_capacity(count.min(1 << 20) as usize);
+        let mut buffer = [0u8; RECORD_SIZE];
+        for _ in 0..count {
+            read_exact(input, &mut buffer)?;
+            hasher.update(&buffer);
+            let record = PersistentBlobRecord::read_from(&buffer[..]).unwrap();
+            parts.push((record.0.into(), record.1.get()));
+        }
+        let mut checksum = [0u8; CHECKSUM_SIZE];
+        read_exact(input, &mut checksum)?;
+        if checksum != *hasher.finalize().as_bytes() || input.read(&mut [0u8; 1])? != 0 {
+            return Err(invalid_data("not a manifest blob"));
+        }
+        let manifest = Self { parts };
+        if manifest.size() != header.2.get() {
+            return Err(invalid_data("corrupted manifest blob"));
+        }
+        Ok(manifest)
+    }
+}
+
+/// Reads exactly enough bytes to fill the buffer, treating truncated input
+/// as not being a manifest.
+fn read_exact(input: &mut dyn Read, buffer: &mut [u8]) -> Result<()> {
+    input
+        .read_exact(buffer)
+        .map_err(|error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => invalid_data("not a manifest blob"),
+            _ => error,
+        })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,183 @@
+// This is free and unencumbered software released into the public domain.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Result},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How many leading bytes of a blob are inspected to detect its MIME type.
+pub const MIME_SNIFF_SIZE: usize = 1024;
+
+const MIME_TYPE_KEY: &str = "type";
+const CREATED_KEY: &str = "created";
+const NAME_KEY: &str = "name";
+const ATTRIBUTE_KEY_PREFIX: &str = "attr:";
+
+/// The metadata recorded for a blob when it is first stored.
+///
+/// A record is a sequence of key/value entries, each a 16-bit key length,
+/// the key, a 32-bit value length, and the value, in network byte order.
+/// Entries with unknown keys are skipped when reading, so that new fields
+/// can be added without breaking older readers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlobMetadata {
+    /// The MIME type detected from the blob's content.
+    pub mime_type: Option<String>,
+    /// When the blob was stored, to the second.
+    pub created: Option<SystemTime>,
+    /// The name of the file the blob was stored from.
+    pub name: Option<String>,
+    /// Caller-supplied attributes.
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl BlobMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.insert(key.into(), value.into());
+        self
+    }
+
+    /// Guesses the MIME type of a blob from its leading bytes.
+    pub fn detect_mime_type(data_prefix: &[u8]) -> Option<&'static str> {
+        #[cfg(feature = "magic")]
+        return infer::get(data_prefix).map(|t| t.mime_type());
+        #[cfg(not(feature = "magic"))]
+        return None;
+    }
+
+    /// Fills in the MIME type and the insertion time, unless already set.
+    pub(crate) fn complete(mut self, data_prefix: &[u8]) -> Self {
+        if self.mime_type.is_none() {
+            self.mime_type = Self::detect_mime_type(data_prefix).map(|t| t.to_string());
+        }
+        if self.created.is_none() {
+            let secs = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default();
+            self.created = Some(UNIX_EPOCH + Duration::from_secs(secs));
+        }
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        if let Some(mime_type) = &self.mime_type {
+            write_entry(&mut buffer, MIME_TYPE_KEY, mime_type.as_bytes());
+        }
+        if let Some(created) = self.created {
+            let secs = created
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default();
+            write_entry(&mut buffer, CREATED_KEY, &secs.to_be_bytes());
+        }
+        if let Some(name) = &self.name {
+            write_entry(&mut buffer, NAME_KEY, name.as_bytes());
+        }
+        for (key, value) in &self.attributes {
+            let key = format!("{}{}", ATTRIBUTE_KEY_PREFIX, key);
+            write_entry(&mut buffer, &key, value.as_bytes());
+        }
+        buffer
+    }
+
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let mut metadata = Self::new();
+        while !input.is_empty() {
+            let key = read_field(&mut input, 2)?;
+            let value = read_field(&mut input, 4)?;
+            let key = std::str::from_utf8(key).map_err(|_| invalid_data("invalid metadata key"))?;
+            match key {
+                CREATED_KEY => {
+                    let secs = value
+                        .try_into()
+                        .map_err(|_| invalid_data("invalid metadata timestamp"))?;
+                    let secs = u64::from_be_bytes(secs);
+                    metadata.created = Some(UNIX_EPOCH + Duration::from_secs(secs));
+                }
+                MIME_TYPE_KEY => metadata.mime_type = Some(read_string(value)?),
+                NAME_KEY => metadata.name = Some(read_string(value)?),
+                key => {
+                    if let Some(key) = key.strip_prefix(ATTRIBUTE_KEY_PREFIX) {
+                        metadata
+                            .attributes
+                            .insert(key.to_string(), read_string(value)?);
+                    }
+                }
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+fn write_entry(buffer: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let key = &key.as_bytes()[..key.len().min(u16::MAX as usize)];
+    let value = &value[..value.len().min(u32::MAX as usize)];
+    buffer.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(key);
+    buffer.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(value);
+}
+
+/// Reads a field preceded by its length, which takes up `len_size` bytes.
+fn read_field<'a>(input: &mut &'a [u8], len_size: usize) -> Result<&'a [u8]> {
+    if input.len() < len_size {
+        return Err(invalid_data("truncated metadata record"));
+    }
+    let (len, rest) = input.split_at(len_size);
+    let len = len
+        .iter()
+        .fold(0usize, |len, byte| len << 8 | *byte as usize);
+    if rest.len() < len {
+        return Err(invalid_data("truncated metadata record"));
+    }
+    let (field, rest) = rest.split_at(len);
+    *input = rest;
+    Ok(field)
+}
+
+fn read_string(value: &[u8]) -> Result<String> {
+    String::from_utf8(value.to_vec()).map_err(|_| invalid_data("invalid metadata value"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let metadata = BlobMetadata::new()
+            .name("foo.png")
+            .attribute("name", "not the name")
+            .attribute("source", "https://example.org/foo.png")
+            .complete(b"\x89PNG\r\n\x1a\n");
+        assert_eq!(metadata.mime_type.as_deref(), Some("image/png"));
+        assert!(metadata.created.is_some());
+        let bytes = metadata.to_bytes();
+        assert_eq!(BlobMetadata::from_bytes(&bytes).unwrap(), metadata);
+        assert_eq!(BlobMetadata::from_bytes(&[]).unwrap(), BlobMetadata::new());
+        assert!(BlobMetadata::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        // Entries with unknown keys are skipped:
+        let mut bytes = Vec::new();
+        write_entry(&mut bytes, "future", b"value");
+        write_entry(&mut bytes, NAME_KEY, b"foo.png");
+        let metadata = BlobMetadata::from_bytes(&bytes).unwrap();
+        assert_eq!(metadata, BlobMetadata::new().name("foo.png"));
+    }
+}
@@ -0,0 +1,306 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::BlobHash;
+use blake3::{
+    hazmat::{self, ChainingValue, HasherExt, Mode},
+    Hasher, CHUNK_LEN,
+};
+use cap_std::{ambient_authority, fs::Dir};
+use cap_tempfile::TempFile;
+use std::io::{self, Cursor, Read, Result, Seek, SeekFrom};
+
+const PARENT_SIZE: usize = 64;
+const HEADER_SIZE: usize = 8;
+
+/// A Bao outboard encoding of a blob: the interior nodes of its BLAKE3 hash
+/// tree, without the content itself.
+///
+/// With the outboard, any byte range of the blob can be verified against the
+/// root hash by reading just the chunks covering the range. The serialized
+/// form is compatible with `bao encode --outboard`: the content length as a
+/// little-endian `u64`, followed by the parent nodes in pre-order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outboard {
+    size: u64,
+    tree: Vec<u8>,
+}
+
+impl Outboard {
+    /// Computes the outboard and the root hash for `size` bytes of input.
+    pub fn encode(input: &mut dyn Read, size: u64) -> Result<(BlobHash, Self)> {
+        let mut tree = Vec::with_capacity(parent_count(size) * PARENT_SIZE);
+        let mut buffer = vec![0u8; CHUNK_LEN];
+        let root = encode_subtree(input, &mut buffer, &mut tree, 0, size, true)?;
+        Ok((BlobHash::from(root), Self { size, tree }))
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(invalid_data("truncated outboard"));
+        }
+        let size = u64::from_le_bytes(bytes[..HEADER_SIZE].try_into().unwrap());
+        let tree = bytes[HEADER_SIZE..].to_vec();
+        if tree.len() as u64 != parent_count(size) as u64 * PARENT_SIZE as u64 {
+            return Err(invalid_data("outboard does not match the content length"));
+        }
+        Ok(Self { size, tree })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.tree.len());
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        bytes.extend_from_slice(&self.tree);
+        bytes
+    }
+
+    /// Reads `len` bytes at `offset` from the blob data, verifying them and
+    /// the outboard against the root hash. Only the chunks overlapping the
+    /// range are read.
+    pub fn read_range<R: Read + Seek + ?Sized>(
+        &self,
+        root: BlobHash,
+        data: &mut R,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        let mut tree = Cursor::new(&self.tree[..]);
+        verify_range(root, &mut tree, 0, self.size, data, offset, len)
+    }
+
+    /// Like [`read_range`](Self::read_range), but reads the serialized
+    /// outboard by offset instead of holding it in memory. Only the parent
+    /// nodes on the paths to the chunks overlapping the range are read.
+    pub fn read_range_from<O, R>(
+        root: BlobHash,
+        outboard: &mut O,
+        data: &mut R,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>>
+    where
+        O: Read + Seek + ?Sized,
+        R: Read + Seek + ?Sized,
+    {
+        let mut header = [0u8; HEADER_SIZE];
+        outboard.seek(SeekFrom::Start(0))?;
+        read_tree(outboard, &mut header)?;
+        let size = u64::from_le_bytes(header);
+        verify_range(root, outboard, HEADER_SIZE as u64, size, data, offset, len)
+    }
+}
+
+/// Spools the input into an anonymous temporary file while computing its
+/// outboard, for stores that upload the data elsewhere. Returns the file,
+/// rewound, along with the outboard.
+pub(crate) fn spool_with_outboard(
+    input: &mut dyn Read,
+) -> crate::Result<(cap_std::fs::File, Outboard)> {
+    let temp_dir = Dir::open_ambient_dir(std::env::temp_dir(), ambient_authority())?;
+    let mut data_file = TempFile::new_anonymous(&temp_dir)?;
+    let size = io::copy(input, &mut data_file)?;
+    data_file.rewind()?;
+    let (_, outboard) = Outboard::encode(&mut data_file, size)?;
+    data_file.rewind()?;
+    Ok((data_file, outboard))
+}
+
+fn verify_range<O, R>(
+    root: BlobHash,
+    tree: &mut O,
+    tree_start: u64,
+    size: u64,
+    data: &mut R,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>>
+where
+    O: Read + Seek + ?Sized,
+    R: Read + Seek + ?Sized,
+{
+    let end = offset.saturating_add(len).min(size);
+    let range = offset.min(end)..end;
+    let mut verifier = Verifier {
+        tree,
+        tree_pos: tree_start,
+        range,
+        buffer: vec![0u8; CHUNK_LEN],
+        output: Vec::with_capacity((end - offset.min(end)) as usize),
+    };
+    let root: [u8; 32] = root.into();
+    verifier.verify_subtree(data, 0, size, &root, true)?;
+    Ok(verifier.output)
+}
+
+/// Reads from the outboard, reporting its end as a truncated outboard.
+fn read_tree<O: Read + ?Sized>(tree: &mut O, buffer: &mut [u8]) -> Result<()> {
+    tree.read_exact(buffer).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => invalid_data("truncated outboard"),
+        _ => err,
+    })
+}
+
+/// Returns the number of parent nodes in the tree for `size` bytes.
+fn parent_count(size: u64) -> usize {
+    let chunk_count = (size + CHUNK_LEN as u64 - 1) / CHUNK_LEN as u64;
+    (chunk_count.max(1) - 1) as usize
+}
+
+fn encode_subtree(
+    input: &mut dyn Read,
+    buffer: &mut [u8],
+    tree: &mut Vec<u8>,
+    offset: u64,
+    len: u64,
+    is_root: bool,
+) -> Result<ChainingValue> {
+    if len <= CHUNK_LEN as u64 {
+        let chunk = &mut buffer[..len as usize];
+        input.read_exact(chunk)?;
+        return Ok(hash_chunk(chunk, offset, is_root));
+    }
+    let parent_pos = tree.len();
+    tree.extend_from_slice(&[0u8; PARENT_SIZE]);
+    let left_len = hazmat::left_subtree_len(len);
+    let left = encode_subtree(input, buffer, tree, offset, left_len, false)?;
+    let right = encode_subtree(
+        input,
+        buffer,
+        tree,
+        offset + left_len,
+        len - left_len,
+        false,
+    )?;
+    tree[parent_pos..parent_pos + 32].copy_from_slice(&left);
+    tree[parent_pos + 32..parent_pos + PARENT_SIZE].copy_from_slice(&right);
+    Ok(merge_subtrees(&left, &right, is_root))
+}
+
+fn hash_chunk(chunk: &[u8], offset: u64, is_root: bool) -> ChainingValue {
+    match is_root {
+        true => *Hasher::new().update(chunk).finalize().as_bytes(),
+        false => Hasher::new()
+            .set_input_offset(offset)
+            .update(chunk)
+            .finalize_non_root(),
+    }
+}
+
+fn merge_subtrees(left: &ChainingValue, right: &ChainingValue, is_root: bool) -> ChainingValue {
+    match is_root {
+        true => *hazmat::merge_subtrees_root(left, right, Mode::Hash).as_bytes(),
+        false => hazmat::merge_subtrees_non_root(left, right, Mode::Hash),
+    }
+}
+
+struct Verifier<'a, O: ?Sized> {
+    tree: &'a mut O,
+    tree_pos: u64,
+    range: std::ops::Range<u64>,
+    buffer: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl<'a, O: Read + Seek + ?Sized> Verifier<'a, O> {
+    fn verify_subtree<R: Read + Seek + ?Sized>(
+        &mut self,
+        data: &mut R,
+        offset: u64,
+        len: u64,
+        expected: &ChainingValue,
+        is_root: bool,
+    ) -> Result<()> {
+        if len <= CHUNK_LEN as u64 {
+            let chunk = &mut self.buffer[..len as usize];
+            data.seek(SeekFrom::Start(offset))?;
+            data.read_exact(chunk)?;
+            if hash_chunk(chunk, offset, is_root) != *expected {
+                return Err(invalid_data("blob chunk failed verification"));
+            }
+            let start = (self.range.start.max(offset) - offset) as usize;
+            let end = (self.range.end.min(offset + len) - offset) as usize;
+            self.output.extend_from_slice(&chunk[start..end.max(start)]);
+            return Ok(());
+        }
+
+        let mut parent = [0u8; PARENT_SIZE];
+        self.tree.seek(SeekFrom::Start(self.tree_pos))?;
+        read_tree(self.tree, &mut parent)?;
+        self.tree_pos += PARENT_SIZE as u64;
+        let left: ChainingValue = parent[..32].try_into().unwrap();
+        let right: ChainingValue = parent[32..].try_into().unwrap();
+        if merge_subtrees(&left, &right, is_root) != *expected {
+            return Err(invalid_data("blob outboard failed verification"));
+        }
+
+        let left_len = hazmat::left_subtree_len(len);
+        let right_offset = offset + left_len;
+        if self.range.start < right_offset && offset < self.range.end {
+            self.verify_subtree(data, offset, left_len, &left, false)?;
+        } else {
+            self.tree_pos += (parent_count(left_len) * PARENT_SIZE) as u64;
+        }
+        if self.range.start < offset + len && right_offset < self.range.end {
+            self.verify_subtree(data, right_offset, len - left_len, &right, false)?;
+        } else {
+            self.tree_pos += (parent_count(len - left_len) * PARENT_SIZE) as u64;
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hash;
+
+    #[test]
+    fn test() {
+        for size in [0, 1, CHUNK_LEN, CHUNK_LEN + 1, 5 * CHUNK_LEN + 7] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let (root, outboard) = Outboard::encode(&mut &data[..], size as u64).unwrap();
+            assert_eq!(root, hash(&data));
+            assert_eq!(
+                Outboard::from_bytes(&outboard.to_bytes()).unwrap(),
+                outboard
+            );
+
+            let mut cursor = Cursor::new(&data[..]);
+            let mut serialized = Cursor::new(outboard.to_bytes());
+            let from = Outboard::read_range_from(root, &mut serialized, &mut cursor, 1, 2);
+            assert_eq!(from.unwrap(), &data[1.min(size)..3.min(size)]);
+            let all = outboard
+                .read_range(root, &mut cursor, 0, size as u64)
+                .unwrap();
+            assert_eq!(all, data);
+            if size > CHUNK_LEN {
+                let (start, end) = (CHUNK_LEN - 3, size - 2);
+                let range = outboard
+                    .read_range(root, &mut cursor, start as u64, (end - start) as u64)
+                    .unwrap();
+                assert_eq!(range, &data[start..end]);
+                let mut truncated = outboard.to_bytes();
+                truncated.pop();
+                let mut truncated = Cursor::new(truncated);
+                let last = size as u64 - 1;
+                let result = Outboard::read_range_from(root, &mut truncated, &mut cursor, last, 1);
+                assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+                // Corrupted content is detected within the range only:
+                let mut corrupted = data.clone();
+                corrupted[size - 1] ^= 1;
+                let mut cursor = Cursor::new(&corrupted[..]);
+                assert!(outboard.read_range(root, &mut cursor, 0, 10).is_ok());
+                let last = size as u64 - 1;
+                assert!(outboard.read_range(root, &mut cursor, last, 1).is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,266 @@
+// This is free and unencumbered software released into the public domain.
+
+use super::store::{
+    data_key, max_blob_size, parse_metadata, range_positions, temp_key, temp_outboard_key,
+    BlobFields, COUNT_KEY, DATA_KEY_PREFIX, GET_RANGE_SCRIPT, INDEX_KEY, METADATA_KEY,
+    OUTBOARD_KEY_PREFIX, PUT_SCRIPT, REMOVE_SCRIPT, STORE_KEY, TEMP_KEY_TTL, UPLOAD_CHUNK_SIZE,
+};
+use crate::{
+    AsyncBlob, AsyncBlobStore, Blob, BlobHash, BlobHasher, BlobID, BlobMetadata, BlobPage,
+    BlobStoreError, BlobStoreOptions, Result, LIST_PAGE_SIZE, MIME_SNIFF_SIZE,
+};
+use async_trait::async_trait;
+use redis::{aio::MultiplexedConnection, AsyncCommands, Script};
+use std::{io::Cursor, str::FromStr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The async counterpart of [`RedisBlobStore`](super::RedisBlobStore),
+/// sharing its keys and scripts.
+///
+/// The connection is multiplexed, so concurrent tasks share it without
+/// locking.
+pub struct AsyncRedisBlobStore {
+    pub(crate) config: BlobStoreOptions,
+    connection: MultiplexedConnection,
+    put_script: Script,
+    remove_script: Script,
+    get_range_script: Script,
+}
+
+impl AsyncRedisBlobStore {
+    pub async fn open(url: impl AsRef<str>, config: BlobStoreOptions) -> Result<Self> {
+        config.check_unfiltered("Redis")?;
+        let client = redis::Client::open(url.as_ref())?;
+        let connection = client.get_multiplexed_tokio_connection().await?;
+        Ok(Self {
+            config,
+            connection,
+            put_script: Script::new(PUT_SCRIPT),
+            remove_script: Script::new(REMOVE_SCRIPT),
+            get_range_script: Script::new(GET_RANGE_SCRIPT),
+        })
+    }
+}
+
+#[async_trait]
+impl AsyncBlobStore for AsyncRedisBlobStore {
+    async fn count(&self) -> Result<usize> {
+        let mut conn = self.connection.clone();
+        Ok(conn.zcard(INDEX_KEY).await.unwrap_or(0))
+    }
+
+    async fn contains_hash(&self, blob_hash: BlobHash) -> Result<bool> {
+        let mut conn = self.connection.clone();
+        let blob_hash_str = blob_hash.to_hex();
+        let blob_id: Option<BlobID> = conn.zscore(INDEX_KEY, blob_hash_str.as_str()).await?;
+        Ok(blob_id.is_some())
+    }
+
+    async fn get_by_hash(&self, blob_hash: BlobHash) -> Result<Option<AsyncBlob>> {
+        let mut conn = self.connection.clone();
+        let blob_hash_str = blob_hash.to_hex();
+        let blob_id: Option<BlobID> = conn.zscore(INDEX_KEY, blob_hash_str.as_str()).await?;
+        let Some(blob_id) = blob_id else {
+            return Ok(None);
+        };
+        let (blob_data, legacy_data, metadata): BlobFields = redis::pipe()
+            .get(data_key(blob_id))
+            .hget(STORE_KEY, blob_id)
+            .hget(METADATA_KEY, blob_id)
+            .query_async(&mut conn)
+            .await?;
+        let Some(blob_data) = blob_data.or(legacy_data) else {
+            return Ok(None); // removed concurrently
+        };
+        Ok(Some(AsyncBlob {
+            id: blob_id,
+            hash: blob_hash,
+            size: blob_data.len() as u64,
+            data: Box::new(Cursor::new(blob_data)),
+            metadata: parse_metadata(metadata.unwrap_or_default())?,
+        }))
+    }
+
+    async fn put(&self, blob_data: &mut (dyn AsyncRead + Send + Unpin)) -> Result<(bool, Blob)> {
+        self.put_with_metadata(blob_data, BlobMetadata::new()).await
+    }
+
+    async fn put_with_metadata(
+        &self,
+        blob_data: &mut (dyn AsyncRead + Send + Unpin),
+        metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
+        if !self.config.writable {
+            return Err(BlobStoreError::NotWritable);
+        }
+
+        // Stream the blob data into a temporary key, hashing it on the way:
+        let temp_key = temp_key();
+        let mut conn = self.connection.clone();
+        let mut blob_hasher = BlobHasher::new();
+        let mut blob_size = 0u64;
+        let mut data_prefix = Vec::new();
+        let mut chunk = Vec::new();
+        let uploaded: Result<()> = loop {
+            chunk.clear();
+            if let Err(err) = (&mut *blob_data)
+                .take(UPLOAD_CHUNK_SIZE)
+                .read_to_end(&mut chunk)
+                .await
+            {
+                break Err(err.into());
+            }
+            if chunk.is_empty() {
+                break Ok(());
+            }
+            blob_hasher.0.update(&chunk);
+            if data_prefix.is_empty() {
+                data_prefix.extend_from_slice(&chunk[..chunk.len().min(MIME_SNIFF_SIZE)]);
+            }
+            blob_size += chunk.len() as u64;
+            let max_size = max_blob_size(&self.config);
+            if blob_size > max_size {
+                break Err(BlobStoreError::TooLarge(max_size));
+            }
+            if let Err(err) = redis::pipe()
+                .append(&temp_key, &chunk)
+                .ignore()
+                .expire(&temp_key, TEMP_KEY_TTL)
+                .ignore()
+                .query_async::<_, ()>(&mut conn)
+                .await
+            {
+                break Err(err.into());
+            }
+        };
+        if let Err(err) = uploaded {
+            let _: redis::RedisResult<()> = conn.del(&temp_key).await;
+            return Err(err);
+        }
+        let metadata = metadata.complete(&data_prefix);
+        let blob_hash = blob_hasher.finalize();
+        let blob_hash_str = blob_hash.to_hex();
+
+        let (created, blob_id, metadata): (bool, BlobID, Vec<u8>) = self
+            .put_script
+            .key(INDEX_KEY)
+            .key(COUNT_KEY)
+            .key(&temp_key)
+            .key(METADATA_KEY)
+            .key(temp_outboard_key(&temp_key)) // no outboards are computed here
+            .arg(blob_hash_str.as_str())
+            .arg(metadata.to_bytes())
+            .arg(DATA_KEY_PREFIX)
+            .arg(OUTBOARD_KEY_PREFIX)
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok((
+            created,
+            Blob {
+                id: blob_id,
+                hash: blob_hash,
+                size: blob_size,
+                data: None,
+                metadata: parse_metadata(metadata)?,
+            },
+        ))
+    }
+
+    async fn remove(&self, blob_hash: BlobHash) -> Result<bool> {
+        if !self.config.writable {
+            return Err(BlobStoreError::NotWritable);
+        }
+
+        let mut conn = self.connection.clone();
+        let blob_hash_str = blob_hash.to_hex();
+        let removed: bool = self
+            .remove_script
+            .key(INDEX_KEY)
+            .key(STORE_KEY)
+            .key(METADATA_KEY)
+            .arg(blob_hash_str.as_str())
+            .arg(DATA_KEY_PREFIX)
+            .arg(OUTBOARD_KEY_PREFIX)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(removed)
+    }
+
+    async fn list(&self, token: Option<&str>) -> Result<BlobPage> {
+        let mut conn = self.connection.clone();
+        let cursor = token.unwrap_or("0");
+        // ZSCAN may return a member more than once, such as when the index
+        // is rehashed during the scan:
+        let (cursor, members): (String, Vec<(String, BlobID)>) = redis::cmd("ZSCAN")
+            .arg(INDEX_KEY)
+            .arg(cursor)
+            .arg("COUNT")
+            .arg(LIST_PAGE_SIZE)
+            .query_async(&mut conn)
+            .await?;
+
+        // Fetch the blob sizes in one round trip, from the data keys or else
+        // the store hash:
+        let mut pipeline = redis::pipe();
+        for (_, blob_id) in &members {
+            pipeline.strlen(data_key(*blob_id));
+            pipeline.cmd("HSTRLEN").arg(STORE_KEY).arg(*blob_id);
+        }
+        let sizes: Vec<u64> = match members.is_empty() {
+            true => Vec::new(),
+            false => pipeline.query_async(&mut conn).await?,
+        };
+
+        let mut blobs = Vec::with_capacity(members.len());
+        for ((blob_hash, _), blob_sizes) in members.into_iter().zip(sizes.chunks(2)) {
+            let blob_size = blob_sizes.iter().sum();
+            let blob_hash = BlobHash::from_str(blob_hash.as_str())
+                .map_err(|err| BlobStoreError::Other(Box::new(err)))?;
+            blobs.push((blob_hash, blob_size));
+        }
+        Ok(BlobPage {
+            blobs,
+            next: (cursor != "0").then_some(cursor),
+        })
+    }
+
+    async fn get_range(
+        &self,
+        blob_hash: BlobHash,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        if len == 0 {
+            return Ok(self.contains_hash(blob_hash).await?.then(Vec::new));
+        }
+        let mut conn = self.connection.clone();
+        let blob_hash_str = blob_hash.to_hex();
+        let (start, end) = range_positions(offset, len);
+        let result: Option<Vec<u8>> = self
+            .get_range_script
+            .key(INDEX_KEY)
+            .key(STORE_KEY)
+            .arg(blob_hash_str.as_str())
+            .arg(DATA_KEY_PREFIX)
+            .arg(start)
+            .arg(end)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn get_metadata(&self, blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        let mut conn = self.connection.clone();
+        let blob_hash_str = blob_hash.to_hex();
+        let blob_id: Option<BlobID> = conn.zscore(INDEX_KEY, blob_hash_str.as_str()).await?;
+        let Some(blob_id) = blob_id else {
+            return Ok(None);
+        };
+        let metadata: Option<Vec<u8>> = conn.hget(METADATA_KEY, blob_id).await?;
+        match metadata {
+            None => Ok(None),
+            Some(metadata) => parse_metadata(metadata),
+        }
+    }
+}
@@ -3,3 +3,9 @@
 mod store;
 
 pub use store::*;
+
+#[cfg(feature = "tokio")]
+mod aio;
+
+#[cfg(feature = "tokio")]
+pub use aio::*;
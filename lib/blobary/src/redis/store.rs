@@ -1,119 +1,459 @@
 // This is free and unencumbered software released into the public domain.
 
 use crate::{
-    hash, Blob, BlobHash, BlobID, BlobStore, BlobStoreError, BlobStoreExt, BlobStoreOptions,
-    IndexedBlobStore, Result,
+    hash, spool_with_outboard, Blob, BlobHash, BlobID, BlobMetadata, BlobPage, BlobStore,
+    BlobStoreError, BlobStoreExt, BlobStoreOptions, HashingReader, IndexedBlobStore, Result,
+    LIST_PAGE_SIZE,
 };
-use redis::Commands;
-use std::{cell::RefCell, io::Read, str::FromStr};
+use redis::{Commands, Script};
+use std::{
+    io::{Cursor, Read},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub(super) const COUNT_KEY: &str = "blobs:id";
+pub(super) const INDEX_KEY: &str = "blobs:index";
+pub(super) const STORE_KEY: &str = "blobs:store"; // blob data of older versions
+pub(super) const METADATA_KEY: &str = "blobs:meta";
+
+/// The key prefix for blob data, followed by the blob's ID.
+pub(super) const DATA_KEY_PREFIX: &str = "blobs:data:";
+
+/// The key prefix for Bao outboards, followed by the blob's ID.
+pub(super) const OUTBOARD_KEY_PREFIX: &str = "blobs:outboard:";
+
+/// The key prefix for blob data being uploaded before its hash is known.
+const TEMP_KEY_PREFIX: &str = "blobs:tmp:";
+
+/// How many seconds an abandoned upload is kept for.
+pub(super) const TEMP_KEY_TTL: usize = 60 * 60;
 
+/// How many bytes to upload per `APPEND` command.
+pub(super) const UPLOAD_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// The largest blob that can be stored, as Redis strings hold at most
+/// 512 MiB by default (`proto-max-bulk-len`).
+pub const REDIS_MAX_BLOB_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Atomically assigns an ID to a new blob and renames the temporary keys
+/// holding its uploaded data and outboard, if any, into place, recording its
+/// metadata, unless the blob is already indexed. Returns `{created, id,
+/// metadata}`, where the metadata is empty if none was recorded.
+pub(super) const PUT_SCRIPT: &str = r"
+local id = redis.call('ZSCORE', KEYS[1], ARGV[1])
+if id then
+  redis.call('DEL', KEYS[3], KEYS[5])
+  return {0, tonumber(id), redis.call('HGET', KEYS[4], id) or ''}
+end
+id = redis.call('INCR', KEYS[2])
+local data_key = ARGV[3] .. id
+if redis.call('EXISTS', KEYS[3]) == 1 then
+  redis.call('RENAME', KEYS[3], data_key)
+  redis.call('PERSIST', data_key)
+else
+  redis.call('SET', data_key, '')
+end
+if redis.call('EXISTS', KEYS[5]) == 1 then
+  local outboard_key = ARGV[4] .. id
+  redis.call('RENAME', KEYS[5], outboard_key)
+  redis.call('PERSIST', outboard_key)
+end
+redis.call('ZADD', KEYS[1], id, ARGV[1])
+redis.call('HSET', KEYS[4], id, ARGV[2])
+return {1, id, ARGV[2]}
+";
+
+/// Atomically removes a blob's index entry, data, outboard, and metadata.
+/// Returns 1 if removed.
+pub(super) const REMOVE_SCRIPT: &str = r"
+local id = redis.call('ZSCORE', KEYS[1], ARGV[1])
+if not id then
+  return 0
+end
+redis.call('ZREM', KEYS[1], ARGV[1])
+redis.call('DEL', ARGV[2] .. id, ARGV[3] .. id)
+redis.call('HDEL', KEYS[2], id)
+redis.call('HDEL', KEYS[3], id)
+return 1
+";
+
+/// Atomically looks up a byte range of a blob's data by its hash, given the
+/// zero-based start and end positions as `GETRANGE` takes them. Returns the
+/// bytes, or nil if not found. The data of blobs stored by older versions
+/// lives in hash fields, which lack `GETRANGE`, so it is sliced in Lua.
+pub(super) const GET_RANGE_SCRIPT: &str = r"
+local id = redis.call('ZSCORE', KEYS[1], ARGV[1])
+if not id then
+  return false
+end
+local data_key = ARGV[2] .. id
+if redis.call('EXISTS', data_key) == 1 then
+  return redis.call('GETRANGE', data_key, ARGV[3], ARGV[4])
+end
+local data = redis.call('HGET', KEYS[2], id)
+if not data then
+  return false
+end
+local last = tonumber(ARGV[4])
+return string.sub(data, tonumber(ARGV[3]) + 1, last < 0 and -1 or last + 1)
+";
+
+/// Blob data is kept in a string key per blob, named by its ID, so a blob can
+/// be at most [`REDIS_MAX_BLOB_SIZE`] bytes. Bao outboards, if kept, are in
+/// string keys named the same way. The index, the ID counter, and the
+/// metadata records are kept in a sorted set, a string, and a hash.
 pub struct RedisBlobStore {
     pub(crate) config: BlobStoreOptions,
-    connection: RefCell<redis::Connection>,
+    connection: Mutex<redis::Connection>,
     count_key: String,
     index_key: String,
     store_key: String,
+    metadata_key: String,
+    put_script: Script,
+    remove_script: Script,
+    get_range_script: Script,
+}
+
+/// Parses a metadata record, which is empty for blobs stored before metadata
+/// was recorded.
+pub(super) fn parse_metadata(bytes: Vec<u8>) -> Result<Option<BlobMetadata>> {
+    match bytes.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(BlobMetadata::from_bytes(&bytes)?)),
+    }
+}
+
+/// A blob's data, its data in the store hash instead, and its metadata
+/// record, as fetched together.
+pub(super) type BlobFields = (Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// Returns the key holding a blob's data.
+pub(super) fn data_key(blob_id: BlobID) -> String {
+    format!("{}{}", DATA_KEY_PREFIX, blob_id)
+}
+
+/// Returns the key holding a blob's outboard.
+pub(super) fn outboard_key(blob_id: BlobID) -> String {
+    format!("{}{}", OUTBOARD_KEY_PREFIX, blob_id)
+}
+
+/// Returns the temporary key to upload the outboard of the blob data being
+/// uploaded to the given temporary key.
+pub(super) fn temp_outboard_key(temp_key: &str) -> String {
+    format!("{}:{}", temp_key, crate::OUTBOARD_EXTENSION)
+}
+
+/// Returns the largest blob the store accepts, which is at most
+/// [`REDIS_MAX_BLOB_SIZE`].
+pub(super) fn max_blob_size(config: &BlobStoreOptions) -> u64 {
+    config
+        .max_blob_size
+        .map_or(REDIS_MAX_BLOB_SIZE, |max_size| {
+            max_size.min(REDIS_MAX_BLOB_SIZE)
+        })
+}
+
+/// Converts a non-empty byte range into the zero-based, inclusive positions
+/// taken by [`GET_RANGE_SCRIPT`]. Lua numbers are doubles, so positions
+/// saturate at 2^53, and ranges ending past it extend to the end of the blob
+/// (`-1`).
+pub(super) fn range_positions(offset: u64, len: u64) -> (u64, i64) {
+    const MAX_POSITION: u64 = 1 << 53;
+    let start = offset.min(MAX_POSITION);
+    let end = match offset.saturating_add(len) - 1 {
+        end if end >= MAX_POSITION => -1,
+        end => end as i64,
+    };
+    (start, end)
+}
+
+/// Returns a unique key to upload blob data to.
+pub(super) fn temp_key() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let suffix = hash(format!("{}:{}:{}", std::process::id(), nanos, counter));
+    format!("{}{}", TEMP_KEY_PREFIX, suffix)
 }
 
 impl RedisBlobStore {
     #[allow(unused)]
     pub fn open(url: impl AsRef<str>, config: BlobStoreOptions) -> Result<Self> {
+        config.check_unfiltered("Redis")?;
         let client = redis::Client::open(url.as_ref())?;
         let connection = client.get_connection()?;
         Ok(Self {
             config,
-            connection: RefCell::new(connection),
-            count_key: "blobs:id".to_string(),
-            index_key: "blobs:index".to_string(),
-            store_key: "blobs:store".to_string(),
+            connection: Mutex::new(connection),
+            count_key: COUNT_KEY.to_string(),
+            index_key: INDEX_KEY.to_string(),
+            store_key: STORE_KEY.to_string(),
+            metadata_key: METADATA_KEY.to_string(),
+            put_script: Script::new(PUT_SCRIPT),
+            remove_script: Script::new(REMOVE_SCRIPT),
+            get_range_script: Script::new(GET_RANGE_SCRIPT),
         })
     }
+
+    /// Fetches a blob's data and metadata by its ID, falling back to the
+    /// store hash for blobs stored by older versions.
+    fn fetch_blob(
+        &self,
+        conn: &mut redis::Connection,
+        blob_id: BlobID,
+        blob_hash: BlobHash,
+    ) -> Result<Option<Blob>> {
+        let (blob_data, legacy_data, metadata): BlobFields = redis::pipe()
+            .get(data_key(blob_id))
+            .hget(&self.store_key, blob_id)
+            .hget(&self.metadata_key, blob_id)
+            .query(conn)?;
+        let Some(blob_data) = blob_data.or(legacy_data) else {
+            return Ok(None); // removed concurrently
+        };
+        Ok(Some(Blob {
+            id: blob_id,
+            hash: blob_hash,
+            size: blob_data.len() as u64,
+            data: Some(Arc::new(Mutex::new(Cursor::new(blob_data)))),
+            metadata: parse_metadata(metadata.unwrap_or_default())?,
+        }))
+    }
 }
 
 impl BlobStore for RedisBlobStore {
     fn count(&self) -> Result<BlobID> {
-        let mut conn = self.connection.borrow_mut();
-        Ok(conn.zcard(&self.index_key).unwrap_or(0))
+        let mut conn = self.connection.lock().unwrap();
+        Ok(conn.zcard(&self.index_key)?)
     }
 
-    fn contains_hash(&self, _blob_hash: BlobHash) -> Result<bool> {
-        Err(BlobStoreError::Unimplemented("contains_hash".to_string())) // TODO
+    fn contains_hash(&self, blob_hash: BlobHash) -> Result<bool> {
+        Ok(self.hash_to_id(blob_hash)?.is_some())
     }
 
-    fn get_by_hash(&self, _blob_hash: BlobHash) -> Result<Option<Blob>> {
-        Err(BlobStoreError::Unimplemented("get_by_hash".to_string())) // TODO
+    fn get_by_hash(&self, blob_hash: BlobHash) -> Result<Option<Blob>> {
+        let Some(blob_id) = self.hash_to_id(blob_hash)? else {
+            return Ok(None);
+        };
+        let mut conn = self.connection.lock().unwrap();
+        self.fetch_blob(&mut conn, blob_id, blob_hash)
     }
 
     fn put(&mut self, blob_data: &mut dyn Read) -> Result<(bool, Blob)> {
+        self.put_with_metadata(blob_data, BlobMetadata::new())
+    }
+
+    fn put_with_metadata(
+        &mut self,
+        blob_data: &mut dyn Read,
+        metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
         if !self.config.writable {
-            return Err(crate::BlobStoreError::NotWritable.into());
+            return Err(crate::BlobStoreError::NotWritable);
         }
 
-        let mut conn = self.connection.borrow_mut();
-        let blob_id: BlobID = match conn.incr(&self.count_key, 1) {
-            Ok(value) => value,
-            Err(err) => return Err(err.into()),
+        // Stream the blob data into a temporary key, hashing it on the way:
+        let temp_key = temp_key();
+        let temp_outboard_key = temp_outboard_key(&temp_key);
+        let max_size = Some(max_blob_size(&self.config));
+        let mut data_reader = HashingReader::with_max_size(blob_data, max_size);
+        let (mut spool_file, outboard) = match self.config.outboard {
+            false => (None, None),
+            true => {
+                // Spool the blob data first, to compute its outboard:
+                let (spool_file, outboard) = spool_with_outboard(&mut data_reader)?;
+                (Some(spool_file), Some(outboard))
+            }
         };
-
-        let mut buffer = Vec::new();
-        blob_data.read_to_end(&mut buffer)?;
-
-        let blob = Blob {
-            id: blob_id,
-            hash: hash(&buffer),
-            size: buffer.len() as u64,
-            data: None,
+        let source: &mut dyn Read = match spool_file.as_mut() {
+            Some(spool_file) => spool_file,
+            None => &mut data_reader,
         };
-        let blob_hash_str = blob.hash.to_hex();
+        let mut conn = self.connection.lock().unwrap();
+        let mut chunk = Vec::new();
+        let uploaded = loop {
+            chunk.clear();
+            if let Err(err) = source.take(UPLOAD_CHUNK_SIZE).read_to_end(&mut chunk) {
+                break Err(err.into());
+            }
+            if chunk.is_empty() {
+                break Ok(());
+            }
+            if let Err(err) = redis::pipe()
+                .append(&temp_key, &chunk)
+                .ignore()
+                .expire(&temp_key, TEMP_KEY_TTL)
+                .ignore()
+                .query::<()>(&mut *conn)
+            {
+                break Err(BlobStoreError::from(err));
+            }
+        };
+        let uploaded = uploaded.and_then(|_| match &outboard {
+            None => Ok(()),
+            Some(outboard) => {
+                Ok(conn.set_ex(&temp_outboard_key, outboard.to_bytes(), TEMP_KEY_TTL)?)
+            }
+        });
+        if let Err(err) = uploaded {
+            let _: redis::RedisResult<()> = conn.del(&[&temp_key, &temp_outboard_key]);
+            return Err(err);
+        }
+        let metadata = metadata.complete(data_reader.prefix());
+        let (blob_hash, blob_size) = data_reader.finalize();
+        let blob_hash_str = blob_hash.to_hex();
 
-        let mut cmd = redis::cmd("ZADD");
-        let cmd = cmd
-            .arg(&self.index_key)
-            .arg("NX")
-            .arg(blob.id)
-            .arg(blob_hash_str.as_str());
-
-        match cmd.query(&mut conn) {
-            Ok(0) => Ok((false, blob)), // already existed
-            Ok(1) => match conn.hset_nx(&self.store_key, blob.id, buffer) {
-                Ok(false) => Ok((false, blob)), // already existed
-                Ok(true) => Ok((true, blob)),   // just created
-                Err(err) => Err(err.into()),
+        let (created, blob_id, metadata): (bool, BlobID, Vec<u8>) = self
+            .put_script
+            .key(&self.index_key)
+            .key(&self.count_key)
+            .key(&temp_key)
+            .key(&self.metadata_key)
+            .key(&temp_outboard_key)
+            .arg(blob_hash_str.as_str())
+            .arg(metadata.to_bytes())
+            .arg(DATA_KEY_PREFIX)
+            .arg(OUTBOARD_KEY_PREFIX)
+            .invoke(&mut *conn)?;
+
+        Ok((
+            created,
+            Blob {
+                id: blob_id,
+                hash: blob_hash,
+                size: blob_size,
+                data: None,
+                metadata: parse_metadata(metadata)?,
             },
-            Ok(_) => unreachable!("ZADD should only return 0 or 1"),
-            Err(err) => Err(err.into()),
-        }
+        ))
+    }
+
+    fn max_blob_size(&self) -> Option<u64> {
+        Some(max_blob_size(&self.config))
     }
 
     fn remove(&mut self, blob_hash: BlobHash) -> Result<bool> {
         if !self.config.writable {
-            return Err(crate::BlobStoreError::NotWritable.into());
+            return Err(crate::BlobStoreError::NotWritable);
         }
 
-        match self.hash_to_id(blob_hash)? {
-            None => Ok(false), // not found
-            Some(blob_id) => {
-                let mut conn = self.connection.borrow_mut();
-                let blob_hash_str = blob_hash.to_hex();
-                match conn.zrem(&self.index_key, blob_hash_str.as_str()) {
-                    Ok(0) => Ok(false), // unreachable except in a race
-                    Ok(1) => match conn.hdel(&self.store_key, blob_id) {
-                        Ok(0) => Ok(false), // unreachable except in a race
-                        Ok(1) => Ok(true),
-                        Ok(_) => unreachable!("HDEL should only return 0 or 1"),
-                        Err(err) => Err(err.into()),
-                    },
-                    Ok(_) => unreachable!("ZREM should only return 0 or 1"),
-                    Err(err) => Err(err.into()),
-                }
-            }
+        let mut conn = self.connection.lock().unwrap();
+        let blob_hash_str = blob_hash.to_hex();
+        let removed: bool = self
+            .remove_script
+            .key(&self.index_key)
+            .key(&self.store_key)
+            .key(&self.metadata_key)
+            .arg(blob_hash_str.as_str())
+            .arg(DATA_KEY_PREFIX)
+            .arg(OUTBOARD_KEY_PREFIX)
+            .invoke(&mut *conn)?;
+        Ok(removed)
+    }
+
+    fn get_range(&self, blob_hash: BlobHash, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        if len == 0 {
+            return Ok(self.contains_hash(blob_hash)?.then(Vec::new));
+        }
+        let mut conn = self.connection.lock().unwrap();
+        let blob_hash_str = blob_hash.to_hex();
+        let (start, end) = range_positions(offset, len);
+        let result: Option<Vec<u8>> = self
+            .get_range_script
+            .key(&self.index_key)
+            .key(&self.store_key)
+            .arg(blob_hash_str.as_str())
+            .arg(DATA_KEY_PREFIX)
+            .arg(start)
+            .arg(end)
+            .invoke(&mut *conn)?;
+        Ok(result)
+    }
+
+    fn get_outboard_range(
+        &self,
+        blob_hash: BlobHash,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(blob_id) = self.hash_to_id(blob_hash)? else {
+            return Ok(None);
+        };
+        let outboard_key = outboard_key(blob_id);
+        let mut conn = self.connection.lock().unwrap();
+        if len == 0 {
+            let exists: bool = conn.exists(&outboard_key)?;
+            return Ok(exists.then(Vec::new));
+        }
+        let (start, end) = range_positions(offset, len);
+        let (exists, bytes): (bool, Vec<u8>) = redis::pipe()
+            .exists(&outboard_key)
+            .getrange(&outboard_key, start as isize, end as isize)
+            .query(&mut *conn)?;
+        Ok(exists.then_some(bytes))
+    }
+
+    fn list(&self, token: Option<&str>) -> Result<BlobPage> {
+        let mut conn = self.connection.lock().unwrap();
+        let cursor = token.unwrap_or("0");
+        // ZSCAN may return a member more than once, such as when the index
+        // is rehashed during the scan:
+        let (cursor, members): (String, Vec<(String, BlobID)>) = redis::cmd("ZSCAN")
+            .arg(&self.index_key)
+            .arg(cursor)
+            .arg("COUNT")
+            .arg(LIST_PAGE_SIZE)
+            .query(&mut *conn)?;
+
+        // Fetch the blob sizes in one round trip, from the data keys or else
+        // the store hash:
+        let mut pipeline = redis::pipe();
+        for (_, blob_id) in &members {
+            pipeline.strlen(data_key(*blob_id));
+            pipeline.cmd("HSTRLEN").arg(&self.store_key).arg(*blob_id);
+        }
+        let sizes: Vec<u64> = match members.is_empty() {
+            true => Vec::new(),
+            false => pipeline.query(&mut *conn)?,
+        };
+
+        let mut blobs = Vec::with_capacity(members.len());
+        for ((blob_hash, _), blob_sizes) in members.into_iter().zip(sizes.chunks(2)) {
+            let blob_size = blob_sizes.iter().sum();
+            let blob_hash = BlobHash::from_str(blob_hash.as_str())
+                .map_err(|err| BlobStoreError::Other(Box::new(err)))?;
+            blobs.push((blob_hash, blob_size));
+        }
+        Ok(BlobPage {
+            blobs,
+            next: (cursor != "0").then_some(cursor),
+        })
+    }
+
+    fn get_metadata(&self, blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        let blob_id = match self.hash_to_id(blob_hash)? {
+            None => return Ok(None),
+            Some(blob_id) => blob_id,
+        };
+        let mut conn = self.connection.lock().unwrap();
+        match conn.hget::<&str, BlobID, Option<Vec<u8>>>(&self.metadata_key, blob_id)? {
+            None => Ok(None),
+            Some(metadata) => parse_metadata(metadata),
         }
     }
 }
 
 impl IndexedBlobStore for RedisBlobStore {
     fn hash_to_id(&self, blob_hash: BlobHash) -> Result<Option<BlobID>> {
-        let mut conn = self.connection.borrow_mut();
+        let mut conn = self.connection.lock().unwrap();
         let blob_hash_str = blob_hash.to_hex();
         match conn.zscore::<&str, &str, Option<BlobID>>(&self.index_key, blob_hash_str.as_str()) {
             Ok(Some(rank)) => Ok(Some(rank)),
@@ -123,25 +463,107 @@ impl IndexedBlobStore for RedisBlobStore {
     }
 
     fn id_to_hash(&self, blob_id: BlobID) -> Result<Option<BlobHash>> {
-        let mut conn = self.connection.borrow_mut();
-        match conn.zrangebyscore::<&str, BlobID, BlobID, Vec<String>>(
-            &self.index_key,
-            blob_id,
-            blob_id,
-        ) {
-            Ok(mut results) if results.len() == 1 => {
-                let result = results.pop().unwrap();
-                let blob_hash = BlobHash::from_str(result.as_str()).expect("parse blob hash");
-                Ok(Some(blob_hash))
-            }
-            Ok(_) => unreachable!("ZRANGEBYSCORE should only return 1 result"),
-            Err(err) => Err(err.into()),
+        let mut conn = self.connection.lock().unwrap();
+        let results: Vec<String> = conn.zrangebyscore(&self.index_key, blob_id, blob_id)?;
+        match results.as_slice() {
+            [] => Ok(None),
+            [blob_hash] => BlobHash::from_str(blob_hash)
+                .map(Some)
+                .map_err(|err| BlobStoreError::Other(Box::new(err))),
+            _ => Err(BlobStoreError::Unexpected), // several blobs share the ID
         }
     }
 
-    fn get_by_id(&self, _blob_id: BlobID) -> Result<Option<Blob>> {
-        Err(BlobStoreError::Unimplemented("get_by_id".to_string())) // TODO
+    fn get_by_id(&self, blob_id: BlobID) -> Result<Option<Blob>> {
+        let blob_hash = match self.id_to_hash(blob_id)? {
+            Some(blob_hash) => blob_hash,
+            None => {
+                // The ID was assigned once, but the blob has since been removed:
+                let mut conn = self.connection.lock().unwrap();
+                let max_id: Option<BlobID> = conn.get(&self.count_key)?;
+                return match max_id {
+                    Some(max_id) if blob_id > 0 && blob_id <= max_id => {
+                        Err(BlobStoreError::Removed)
+                    }
+                    _ => Ok(None),
+                };
+            }
+        };
+        let mut conn = self.connection.lock().unwrap();
+        match self.fetch_blob(&mut conn, blob_id, blob_hash)? {
+            None => Err(BlobStoreError::Removed), // removed concurrently
+            Some(blob) => Ok(Some(blob)),
+        }
     }
 }
 
 impl BlobStoreExt for RedisBlobStore {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        assert_eq!(range_positions(0, 1), (0, 0));
+        assert_eq!(range_positions(5, 10), (5, 14));
+        assert_eq!(range_positions(5, u64::MAX), (5, -1));
+        assert_eq!(range_positions(u64::MAX, 1), (1 << 53, -1));
+
+        let config = BlobStoreOptions::default();
+        assert_eq!(max_blob_size(&config), REDIS_MAX_BLOB_SIZE);
+        let config = config.max_blob_size(Some(42));
+        assert_eq!(max_blob_size(&config), 42);
+        let config = config.max_blob_size(Some(u64::MAX));
+        assert_eq!(max_blob_size(&config), REDIS_MAX_BLOB_SIZE);
+    }
+
+    /// Runs the scripts against the Redis server at `REDIS_URL`, if set. The
+    /// blob is removed again, but the database's ID counter is advanced.
+    #[test]
+    fn test_scripts() {
+        let Ok(url) = std::env::var("REDIS_URL") else {
+            return; // no server to test against
+        };
+        let mut store = RedisBlobStore::open(url, BlobStoreOptions::default()).unwrap();
+        let max_id = |store: &RedisBlobStore| -> BlobID {
+            store.connection.lock().unwrap().get(COUNT_KEY).unwrap()
+        };
+        let text = format!("Foo {}", temp_key());
+        let count = store.count().unwrap();
+
+        let (created, foo) = store.put_string(&text).unwrap();
+        assert!(created);
+        assert_eq!(store.count().unwrap(), count + 1);
+        assert_eq!(store.id_to_hash(foo.id).unwrap(), Some(foo.hash));
+
+        // A duplicate put keeps the blob, and uses up no ID:
+        let foo_max_id = max_id(&store);
+        let (created, foo2) = store.put_string(&text).unwrap();
+        assert!(!created);
+        assert_eq!(foo2.id, foo.id);
+        assert_eq!(store.count().unwrap(), count + 1);
+        assert_eq!(max_id(&store), foo_max_id);
+
+        let range = store.get_range(foo.hash, 1, 2).unwrap();
+        assert_eq!(range.unwrap(), b"oo");
+        let range = store.get_range(foo.hash, 4, u64::MAX).unwrap();
+        assert_eq!(range.unwrap(), &text.as_bytes()[4..]);
+
+        assert!(store.remove(foo.hash).unwrap());
+        assert!(!store.remove(foo.hash).unwrap());
+        assert_eq!(store.count().unwrap(), count);
+        assert_eq!(store.get_range(foo.hash, 0, 1).unwrap(), None);
+        assert!(matches!(
+            store.get_by_id(foo.id),
+            Err(BlobStoreError::Removed)
+        ));
+        let exists: bool = store
+            .connection
+            .lock()
+            .unwrap()
+            .exists(data_key(foo.id))
+            .unwrap();
+        assert!(!exists);
+    }
+}
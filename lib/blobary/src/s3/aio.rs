@@ -0,0 +1,384 @@
+// This is free and unencumbered software released into the public domain.
+
+use super::{
+    object::{
+        blob_path, check_metadata, decode_metadata, encode_metadata, is_enveloped, key_prefix,
+        temp_path, to_blob_page, ENVELOPE_HEADER,
+    },
+    request::{get_object_range, move_object, put_object_stream},
+    S3Options,
+};
+use crate::{
+    decode_into_tempfile, encode_into_tempfile, AsyncBlob, AsyncBlobStore, Blob, BlobHash,
+    BlobHasher, BlobMetadata, BlobPage, BlobStoreError, BlobStoreOptions, Envelope, Result,
+    ENVELOPE_MAGIC, LIST_PAGE_SIZE, MIME_SNIFF_SIZE, NO_BLOB_ID,
+};
+use async_trait::async_trait;
+use cap_std::{ambient_authority, fs::Dir};
+use cap_tempfile::TempFile;
+use s3::Bucket;
+use std::{
+    io::{self, SeekFrom},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, ReadBuf},
+    task::spawn_blocking,
+};
+
+/// The async counterpart of the blocking `S3BlobStore`, on the Tokio client
+/// of `rust-s3`, storing blobs, their metadata records, and their envelopes
+/// the same way.
+///
+/// Fetched blobs are downloaded into anonymous temporary files, whereas
+/// [`get_range`](AsyncBlobStore::get_range) requests only the range of
+/// unfiltered blobs.
+pub struct AsyncS3BlobStore {
+    pub(crate) config: Arc<BlobStoreOptions>, // shared with blocking tasks
+    bucket: Bucket,
+    prefix: String,
+}
+
+impl AsyncS3BlobStore {
+    pub async fn open(
+        bucket: impl AsRef<str>,
+        prefix: impl AsRef<str>,
+        config: BlobStoreOptions,
+    ) -> Result<Self> {
+        Self::open_with_options(bucket, prefix, S3Options::default(), config).await
+    }
+
+    pub async fn open_with_options(
+        bucket: impl AsRef<str>,
+        prefix: impl AsRef<str>,
+        options: S3Options,
+        config: BlobStoreOptions,
+    ) -> Result<Self> {
+        // Loading the credentials may make blocking HTTP requests:
+        let region = options.load_region();
+        let path_style = options.path_style;
+        let credentials = spawn_blocking(move || options.credentials.load())
+            .await
+            .map_err(|err| BlobStoreError::Other(Box::new(err)))??;
+        let bucket = Bucket::new(bucket.as_ref(), region, credentials)?;
+        let bucket = if path_style {
+            bucket.with_path_style()
+        } else {
+            bucket
+        };
+        Ok(Self {
+            config: Arc::new(config),
+            bucket,
+            prefix: prefix.as_ref().to_string(),
+        })
+    }
+
+    fn blob_path(&self, blob_hash: BlobHash) -> String {
+        blob_path(&self.prefix, blob_hash)
+    }
+
+    /// Encodes the data through the configured filters, preceded by their
+    /// envelope. The filters are blocking, so the data is spooled to an
+    /// anonymous temporary file and encoded on the blocking thread pool.
+    async fn encode(&self, input: &mut (dyn AsyncRead + Send + Unpin)) -> Result<tokio::fs::File> {
+        let mut data_file = tokio::fs::File::from_std(new_tempfile().await?);
+        tokio::io::copy(input, &mut data_file).await?;
+        data_file.flush().await?;
+        data_file.rewind().await?;
+        let mut data_file = data_file.into_std().await;
+        let config = self.config.clone();
+        let object_file =
+            spawn_blocking(move || encode_into_tempfile(&config.filters, &mut data_file))
+                .await
+                .map_err(|err| BlobStoreError::Other(Box::new(err)))??;
+        Ok(tokio::fs::File::from_std(object_file.into_std()))
+    }
+
+    /// Undoes the filters recorded in the envelope of the downloaded object
+    /// on the blocking thread pool, returning the original content.
+    async fn decode(&self, object_file: tokio::fs::File) -> Result<tokio::fs::File> {
+        let mut object_file = object_file.into_std().await;
+        let config = self.config.clone();
+        let blob_file = spawn_blocking(move || -> Result<cap_std::fs::File> {
+            let envelope = Envelope::read_from(&mut object_file)?.unwrap_or_default();
+            let filters = envelope.resolve(&config.filters)?;
+            Ok(decode_into_tempfile(&filters, &mut object_file)?)
+        })
+        .await
+        .map_err(|err| BlobStoreError::Other(Box::new(err)))??;
+        Ok(tokio::fs::File::from_std(blob_file.into_std()))
+    }
+}
+
+#[async_trait]
+impl AsyncBlobStore for AsyncS3BlobStore {
+    async fn count(&self) -> Result<usize> {
+        let mut count = 0;
+        let mut token = None;
+        loop {
+            let page = self.list(token.as_deref()).await?;
+            count += page.blobs.len();
+            match page.next {
+                None => return Ok(count),
+                next => token = next,
+            }
+        }
+    }
+
+    async fn contains_hash(&self, blob_hash: BlobHash) -> Result<bool> {
+        let (_, status_code) = self.bucket.head_object(self.blob_path(blob_hash)).await?;
+        match status_code {
+            404 => Ok(false), // not found
+            200 => Ok(true),  // found
+            _ => Err(BlobStoreError::Unexpected),
+        }
+    }
+
+    async fn get_by_hash(&self, blob_hash: BlobHash) -> Result<Option<AsyncBlob>> {
+        let blob_path = self.blob_path(blob_hash);
+        let (head, status_code) = self.bucket.head_object(&blob_path).await?;
+        match status_code {
+            404 => return Ok(None), // not found
+            200 => (),              // found
+            _ => return Err(BlobStoreError::Unexpected),
+        }
+        let mut blob_file = tokio::fs::File::from_std(new_tempfile().await?);
+        match self
+            .bucket
+            .get_object_to_writer(&blob_path, &mut blob_file)
+            .await?
+        {
+            200 => (),
+            404 => return Ok(None), // removed since
+            _ => return Err(BlobStoreError::Unexpected),
+        }
+        blob_file.flush().await?;
+        blob_file.rewind().await?;
+        if is_enveloped(&head) {
+            blob_file = self.decode(blob_file).await?;
+        }
+        let blob_size = blob_file.seek(SeekFrom::End(0)).await?;
+        blob_file.rewind().await?;
+        Ok(Some(AsyncBlob {
+            id: NO_BLOB_ID,
+            hash: blob_hash,
+            size: blob_size,
+            data: Box::new(blob_file),
+            metadata: decode_metadata(&head)?,
+        }))
+    }
+
+    async fn put(&self, blob_data: &mut (dyn AsyncRead + Send + Unpin)) -> Result<(bool, Blob)> {
+        self.put_with_metadata(blob_data, BlobMetadata::new()).await
+    }
+
+    async fn put_with_metadata(
+        &self,
+        blob_data: &mut (dyn AsyncRead + Send + Unpin),
+        metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
+        if !self.config.writable {
+            return Err(BlobStoreError::NotWritable);
+        }
+        check_metadata(&metadata)?;
+
+        // Stream the blob data into a temporary key, hashing it on the way,
+        // and encoding it first if it needs an envelope:
+        let temp_path = temp_path(&self.prefix);
+        let mut reader = HashingReader::new(blob_data, self.config.max_blob_size);
+        let mut data_prefix = Vec::with_capacity(ENVELOPE_MAGIC.len());
+        (&mut reader)
+            .take(ENVELOPE_MAGIC.len() as u64)
+            .read_to_end(&mut data_prefix)
+            .await?;
+        let enveloped = Envelope::is_required(&self.config.filters, &data_prefix);
+        let mut input = (&data_prefix[..]).chain(&mut reader);
+        let (result, object_size) = match enveloped {
+            false => (
+                put_object_stream(&self.bucket, &mut input, &temp_path).await,
+                None,
+            ),
+            true => {
+                let mut object_file = self.encode(&mut input).await?;
+                let object_size = object_file.metadata().await?.len();
+                let result = put_object_stream(&self.bucket, &mut object_file, &temp_path).await;
+                (result, Some(object_size))
+            }
+        };
+        if let Err(err) = result {
+            let _ = self.bucket.delete_object(&temp_path).await;
+            return Err(err);
+        }
+        let metadata = metadata.complete(&reader.prefix);
+        let blob_hash = reader.hasher.finalize();
+        let blob_size = reader.size;
+        let object_size = object_size.unwrap_or(blob_size);
+
+        // Move the temporary object to its final key, unless it already
+        // exists, recording the metadata on the object as it is moved:
+        let result: Result<_> = async {
+            if self.contains_hash(blob_hash).await? {
+                return Ok((false, self.get_metadata(blob_hash).await?));
+            }
+            let blob_path = self.blob_path(blob_hash);
+            let encoded_metadata = encode_metadata(&metadata)?;
+            move_object(
+                &self.bucket,
+                &temp_path,
+                &blob_path,
+                object_size,
+                &encoded_metadata,
+                enveloped,
+            )
+            .await?;
+            Ok((true, Some(metadata)))
+        }
+        .await;
+        let _ = self.bucket.delete_object(&temp_path).await;
+        let (created, metadata) = result?;
+        Ok((
+            created,
+            Blob {
+                id: NO_BLOB_ID,
+                hash: blob_hash,
+                size: blob_size,
+                data: None,
+                metadata,
+            },
+        ))
+    }
+
+    async fn remove(&self, blob_hash: BlobHash) -> Result<bool> {
+        if !self.config.writable {
+            return Err(BlobStoreError::NotWritable);
+        }
+        self.bucket.delete_object(self.blob_path(blob_hash)).await?;
+        Ok(true) // can't determine if it existed or not
+    }
+
+    /// Lists the blobs under the prefix. Filtered blobs are listed with the
+    /// size of their stored objects, as decoding them would need a download.
+    async fn list(&self, token: Option<&str>) -> Result<BlobPage> {
+        let key_prefix = key_prefix(&self.prefix);
+        let (result, status_code) = self
+            .bucket
+            .list_page(
+                key_prefix.clone(),
+                Some("/".to_string()),
+                token.map(|token| token.to_string()),
+                None,
+                Some(LIST_PAGE_SIZE),
+            )
+            .await?;
+        if status_code != 200 {
+            return Err(BlobStoreError::Unexpected);
+        }
+        Ok(to_blob_page(result, &key_prefix))
+    }
+
+    async fn get_range(
+        &self,
+        blob_hash: BlobHash,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        if len == 0 {
+            return Ok(self.contains_hash(blob_hash).await?.then(Vec::new));
+        }
+        let blob_path = self.blob_path(blob_hash);
+        let end = offset.saturating_add(len) - 1;
+        let response = get_object_range(&self.bucket, &blob_path, offset..=end).await?;
+        match response.status_code() {
+            404 => Ok(None),             // not found
+            416 => Ok(Some(Vec::new())), // past the end of the blob
+            200 | 206 if response.headers().contains_key(ENVELOPE_HEADER) => {
+                // Filtered blobs must be decoded from their start:
+                match self.get_by_hash(blob_hash).await? {
+                    None => Ok(None),
+                    Some(mut blob) => {
+                        let mut buffer = Vec::new();
+                        blob.data.seek(SeekFrom::Start(offset)).await?;
+                        (&mut blob.data).take(len).read_to_end(&mut buffer).await?;
+                        Ok(Some(buffer))
+                    }
+                }
+            }
+            200 | 206 => {
+                let mut bytes = response.bytes().to_vec();
+                bytes.truncate(len.try_into().unwrap_or(usize::MAX));
+                Ok(Some(bytes))
+            }
+            _ => Err(BlobStoreError::Unexpected),
+        }
+    }
+
+    async fn get_metadata(&self, blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        let (head, status_code) = self.bucket.head_object(self.blob_path(blob_hash)).await?;
+        match status_code {
+            404 => Ok(None), // not found
+            200 => decode_metadata(&head),
+            _ => Err(BlobStoreError::Unexpected),
+        }
+    }
+}
+
+/// Creates an anonymous temporary file, on the blocking thread pool.
+async fn new_tempfile() -> Result<std::fs::File> {
+    spawn_blocking(|| -> io::Result<std::fs::File> {
+        let temp_dir = Dir::open_ambient_dir(std::env::temp_dir(), ambient_authority())?;
+        Ok(TempFile::new_anonymous(&temp_dir)?.into_std())
+    })
+    .await
+    .map_err(|err| BlobStoreError::Other(Box::new(err)))?
+    .map_err(BlobStoreError::from)
+}
+
+/// An async reader that computes the BLAKE3 hash and size of the data read
+/// through it, like [`HashingReader`](crate::HashingReader).
+struct HashingReader<'a> {
+    inner: &'a mut (dyn AsyncRead + Send + Unpin),
+    hasher: BlobHasher,
+    size: u64,
+    max_size: Option<u64>,
+    prefix: Vec<u8>,
+}
+
+impl<'a> HashingReader<'a> {
+    fn new(inner: &'a mut (dyn AsyncRead + Send + Unpin), max_size: Option<u64>) -> Self {
+        Self {
+            inner,
+            hasher: BlobHasher::new(),
+            size: 0,
+            max_size,
+            prefix: Vec::new(),
+        }
+    }
+}
+
+impl<'a> AsyncRead for HashingReader<'a> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled_len = buf.filled().len();
+        ready!(Pin::new(&mut *this.inner).poll_read(cx, buf))?;
+        let data = &buf.filled()[filled_len..];
+        this.hasher.0.update(data);
+        if this.prefix.len() < MIME_SNIFF_SIZE {
+            let prefix_len = data.len().min(MIME_SNIFF_SIZE - this.prefix.len());
+            this.prefix.extend_from_slice(&data[..prefix_len]);
+        }
+        this.size += data.len() as u64;
+        Poll::Ready(match this.max_size {
+            Some(max_size) if this.size > max_size => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                BlobStoreError::TooLarge(max_size),
+            )),
+            _ => Ok(()),
+        })
+    }
+}
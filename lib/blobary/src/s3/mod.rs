@@ -1,5 +1,15 @@
 // This is free and unencumbered software released into the public domain.
 
+mod object;
+mod options;
+mod request;
 mod store;
 
+pub use options::*;
 pub use store::*;
+
+#[cfg(feature = "tokio")]
+mod aio;
+
+#[cfg(feature = "tokio")]
+pub use aio::*;
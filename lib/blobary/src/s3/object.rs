@@ -0,0 +1,193 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::{hash, BlobHash, BlobMetadata, BlobPage, BlobStoreError, Result, OUTBOARD_EXTENSION};
+use s3::serde_types::{HeadObjectResult, ListBucketResult};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The key prefix for objects being uploaded before their hash is known.
+const TEMP_PREFIX: &str = ".tmp-";
+
+/// The name of the user metadata entry holding a blob's metadata record,
+/// hex-encoded, on the blob's object.
+pub(super) const METADATA_HEADER: &str = "x-amz-meta-blobary";
+
+/// The name of the user metadata entry marking objects that hold an
+/// [`Envelope`](crate::Envelope) followed by the filtered blob data.
+pub(super) const ENVELOPE_HEADER: &str = "x-amz-meta-blobary-envelope";
+
+/// The most user metadata that S3 keeps per object, in bytes.
+const MAX_METADATA_SIZE: usize = 2048;
+
+/// An upper bound on the length of MIME types detected from blob content.
+const MAX_MIME_TYPE_LEN: usize = 127;
+
+/// The largest object that S3 can copy in a single request.
+pub(super) const MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// How many bytes to copy per part when copying larger objects.
+pub(super) const COPY_PART_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Returns the object key prefix under which blobs are stored.
+pub(super) fn key_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_start_matches('/');
+    match prefix.is_empty() {
+        true => String::new(),
+        false => format!("{}/", prefix),
+    }
+}
+
+pub(super) fn blob_path(prefix: &str, blob_hash: BlobHash) -> String {
+    format!("{}/{}", prefix, blob_hash)
+}
+
+/// Returns the path of the blob's Bao outboard, next to the blob's object.
+pub(super) fn outboard_path(prefix: &str, blob_hash: BlobHash) -> String {
+    format!("{}/{}.{}", prefix, blob_hash, OUTBOARD_EXTENSION)
+}
+
+/// Returns a unique path to upload blob data to.
+pub(super) fn temp_path(prefix: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let suffix = hash(format!("{}:{}:{}", std::process::id(), nanos, counter));
+    format!("{}/{}{}", prefix, TEMP_PREFIX, suffix)
+}
+
+/// Encodes a metadata record as the value of the [`METADATA_HEADER`],
+/// failing if it doesn't fit in the user metadata of an object.
+pub(super) fn encode_metadata(metadata: &BlobMetadata) -> Result<String> {
+    let metadata = encode_hex(&metadata.to_bytes());
+    if METADATA_HEADER.len() - "x-amz-meta-".len() + metadata.len() > MAX_METADATA_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "blob metadata record too large for S3",
+        )
+        .into());
+    }
+    Ok(metadata)
+}
+
+/// Checks that a metadata record will still fit in the user metadata of an
+/// object once completed, so that oversized records are refused before any
+/// blob data is uploaded.
+pub(super) fn check_metadata(metadata: &BlobMetadata) -> Result<()> {
+    let mut metadata = metadata.clone().complete(&[]);
+    if metadata.mime_type.is_none() {
+        metadata.mime_type = Some("x".repeat(MAX_MIME_TYPE_LEN));
+    }
+    encode_metadata(&metadata).map(|_| ())
+}
+
+/// Decodes the metadata record kept in an object's user metadata, if any.
+pub(super) fn decode_metadata(head: &HeadObjectResult) -> Result<Option<BlobMetadata>> {
+    let key = &METADATA_HEADER["x-amz-meta-".len()..];
+    let Some(value) = head
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get(key))
+    else {
+        return Ok(None); // stored before metadata was recorded
+    };
+    let bytes = decode_hex(value).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "invalid blob metadata record",
+        )
+    })?;
+    Ok(Some(BlobMetadata::from_bytes(&bytes)?))
+}
+
+/// Determines if an object holds an envelope followed by filtered data.
+pub(super) fn is_enveloped(head: &HeadObjectResult) -> bool {
+    let key = &ENVELOPE_HEADER["x-amz-meta-".len()..];
+    head.metadata
+        .as_ref()
+        .is_some_and(|metadata| metadata.contains_key(key))
+}
+
+/// Parses the ETag of a copied part from the body of an UploadPartCopy
+/// response, as in `<CopyPartResult><ETag>...</ETag>`.
+pub(super) fn parse_copy_etag(body: &str) -> Result<String> {
+    body.split_once("<ETag>")
+        .and_then(|(_, rest)| rest.split_once("</ETag>"))
+        .map(|(etag, _)| etag.replace("&quot;", "\""))
+        .ok_or(BlobStoreError::Unexpected)
+}
+
+/// Determines if a CompleteMultipartUpload request succeeded. S3 may report
+/// a failed completion in the body of a 200 response.
+pub(super) fn is_completed(status_code: u16, body: &str) -> bool {
+    (200..300).contains(&status_code) && !body.contains("<Error>")
+}
+
+/// Converts a page of listed objects into a page of blobs.
+pub(super) fn to_blob_page(result: ListBucketResult, key_prefix: &str) -> BlobPage {
+    let mut blobs = Vec::with_capacity(result.contents.len());
+    for object in result.contents {
+        let key = object.key.strip_prefix(key_prefix).unwrap_or(&object.key);
+        // Skip temporary uploads and any unrelated objects:
+        if let Ok(blob_hash) = BlobHash::from_hex(key) {
+            blobs.push((blob_hash, object.size));
+        }
+    }
+    BlobPage {
+        blobs,
+        next: match result.is_truncated {
+            true => result.next_continuation_token,
+            false => None,
+        },
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(input: &str) -> Option<Vec<u8>> {
+    if input.len() % 2 != 0 {
+        return None;
+    }
+    (0..input.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(input.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        assert_eq!(key_prefix(""), "");
+        assert_eq!(key_prefix("/blobs"), "blobs/");
+        assert!(temp_path("/blobs").starts_with("/blobs/.tmp-"));
+
+        let metadata = BlobMetadata::new().name("foo.txt");
+        let value = encode_metadata(&metadata).unwrap();
+        assert_eq!(decode_hex(&value).unwrap(), metadata.to_bytes());
+        assert!(decode_hex("abc").is_none());
+        let metadata = BlobMetadata::new().name("x".repeat(MAX_METADATA_SIZE));
+        assert!(encode_metadata(&metadata).is_err());
+        assert!(check_metadata(&BlobMetadata::new().name("foo.txt")).is_ok());
+        let metadata = BlobMetadata::new().name("x".repeat(MAX_METADATA_SIZE / 2 - 100));
+        assert!(encode_metadata(&metadata).is_ok());
+        assert!(check_metadata(&metadata).is_err());
+
+        let body = "<CopyPartResult><ETag>&quot;abc&quot;</ETag></CopyPartResult>";
+        assert_eq!(parse_copy_etag(body).unwrap(), "\"abc\"");
+        assert!(parse_copy_etag("<Error></Error>").is_err());
+        assert!(is_completed(200, "<CompleteMultipartUploadResult/>"));
+        assert!(!is_completed(
+            200,
+            "<Error><Code>InternalError</Code></Error>"
+        ));
+    }
+}
@@ -0,0 +1,110 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::Result;
+use s3::{creds::Credentials, Region};
+use std::str::FromStr;
+
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Where to obtain the S3 access credentials from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum S3Credentials {
+    /// Try STS web identity, the environment, the default profile, and the
+    /// instance metadata, in that order.
+    #[default]
+    Default,
+    /// Read `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, and
+    /// `AWS_SESSION_TOKEN` from the environment.
+    Environment,
+    /// Read the given (or the default) profile from `~/.aws/credentials`.
+    Profile(Option<String>),
+    /// Use the given access key and secret key.
+    Static {
+        access_key: String,
+        secret_key: String,
+        session_token: Option<String>,
+    },
+    /// Send unsigned requests.
+    Anonymous,
+}
+
+impl S3Credentials {
+    pub(crate) fn load(&self) -> Result<Credentials> {
+        Ok(match self {
+            Self::Default => Credentials::from_sts_env("blobary")
+                .or_else(|_| Credentials::from_env())
+                .or_else(|_| Credentials::from_profile(None))
+                .or_else(|_| Credentials::from_instance_metadata())?,
+            Self::Environment => Credentials::from_env()?,
+            Self::Profile(profile) => Credentials::from_profile(profile.as_deref())?,
+            Self::Static {
+                access_key,
+                secret_key,
+                session_token,
+            } => Credentials::new(
+                Some(access_key),
+                Some(secret_key),
+                None,
+                session_token.as_deref(),
+                None,
+            )?,
+            Self::Anonymous => Credentials::anonymous()?,
+        })
+    }
+}
+
+/// Connection settings for an S3-compatible service.
+#[derive(Clone, Debug, Default)]
+pub struct S3Options {
+    /// The region name, e.g. `eu-west-1`. Defaults to `AWS_REGION`, or
+    /// else `us-east-1`.
+    pub region: Option<String>,
+    /// A custom endpoint URL for MinIO, Ceph RGW, and the like, e.g.
+    /// `http://localhost:9000`. Defaults to `AWS_ENDPOINT`, if set.
+    pub endpoint: Option<String>,
+    /// Whether to address the bucket in the URL path instead of the hostname.
+    pub path_style: bool,
+    pub credentials: S3Credentials,
+}
+
+impl S3Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn region(mut self, region: impl AsRef<str>) -> Self {
+        self.region = Some(region.as_ref().to_string());
+        self
+    }
+
+    pub fn endpoint(mut self, endpoint: impl AsRef<str>) -> Self {
+        self.endpoint = Some(endpoint.as_ref().to_string());
+        self
+    }
+
+    pub fn path_style(mut self, path_style: bool) -> Self {
+        self.path_style = path_style;
+        self
+    }
+
+    pub fn credentials(mut self, credentials: S3Credentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    pub(crate) fn load_region(&self) -> Region {
+        let region = self
+            .region
+            .clone()
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .unwrap_or_else(|| DEFAULT_REGION.to_string());
+        let endpoint = self
+            .endpoint
+            .clone()
+            .or_else(|| std::env::var("AWS_ENDPOINT").ok());
+        match endpoint {
+            Some(endpoint) => Region::Custom { region, endpoint },
+            None => Region::from_str(&region).unwrap(), // infallible
+        }
+    }
+}
@@ -0,0 +1,251 @@
+// This is free and unencumbered software released into the public domain.
+
+use super::object::{
+    is_completed, parse_copy_etag, COPY_PART_SIZE, ENVELOPE_HEADER, MAX_COPY_SIZE, METADATA_HEADER,
+};
+use crate::{BlobStoreError, Result};
+use s3::{
+    command::{Command, Multipart},
+    request::{tokio_backend::Reqwest, Request, ResponseData},
+    serde_types::Part,
+    Bucket,
+};
+use std::ops::RangeInclusive;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How many bytes to upload per part of a multipart upload, at first.
+const UPLOAD_PART_SIZE: u64 = s3::bucket::CHUNK_SIZE as u64;
+
+/// How many parts to upload before doubling the part size, so that objects
+/// of up to S3's limit of 5 TiB fit in [`MAX_UPLOAD_PARTS`] parts.
+const PARTS_PER_PART_SIZE: u32 = 1000;
+
+/// The most parts that S3 accepts in a multipart upload.
+const MAX_UPLOAD_PARTS: u32 = 10_000;
+
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Fetches an inclusive byte range of an object. Unlike
+/// `Bucket::get_object_range`, this accepts single-byte ranges.
+pub(super) async fn get_object_range(
+    bucket: &Bucket,
+    path: &str,
+    range: RangeInclusive<u64>,
+) -> Result<ResponseData> {
+    let command = Command::GetObjectRange {
+        start: *range.start(),
+        end: Some(*range.end()),
+    };
+    Ok(Reqwest::new(bucket, path, command)?
+        .response_data(false)
+        .await?)
+}
+
+/// Uploads the data to an object, as a multipart upload if it doesn't fit
+/// in a single part. Unlike `Bucket::put_object_stream`, this uploads each
+/// part before reading the next one, so that only one part is buffered,
+/// grows the parts as the upload goes on, and aborts the multipart upload on
+/// failure.
+pub(super) async fn put_object_stream(
+    bucket: &Bucket,
+    reader: &mut (impl AsyncRead + Unpin),
+    path: &str,
+) -> Result<()> {
+    let first_part = read_part(reader, UPLOAD_PART_SIZE).await?;
+    if (first_part.len() as u64) < UPLOAD_PART_SIZE {
+        let response = bucket
+            .put_object_with_content_type(path, &first_part, CONTENT_TYPE)
+            .await?;
+        return match response.status_code() {
+            200..=299 => Ok(()),
+            _ => Err(BlobStoreError::Unexpected),
+        };
+    }
+
+    let upload = bucket.initiate_multipart_upload(path, CONTENT_TYPE).await?;
+    let result: Result<()> = async {
+        let mut parts = Vec::new();
+        let mut part = first_part;
+        loop {
+            let part_number = parts.len() as u32 + 1;
+            let done = (part.len() as u64) < upload_part_size(part_number);
+            if !part.is_empty() {
+                let Part { etag, .. } = bucket
+                    .put_multipart_chunk(part, path, part_number, &upload.upload_id, CONTENT_TYPE)
+                    .await?;
+                parts.push(Part { part_number, etag });
+            }
+            if done {
+                break;
+            }
+            part = read_part(reader, upload_part_size(part_number + 1)).await?;
+            if part_number == MAX_UPLOAD_PARTS && !part.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "blob too large for an S3 multipart upload",
+                )
+                .into());
+            }
+        }
+        complete_multipart_upload(bucket, path, &upload.upload_id, parts).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = bucket.abort_upload(path, &upload.upload_id).await;
+    }
+    result
+}
+
+/// Copies an object to a new key, server-side, replacing its user metadata
+/// with the encoded metadata record and, for filtered objects, the envelope
+/// marker. S3 can only copy objects of up to 5 GiB in a single request, so
+/// larger objects are copied as the parts of a multipart upload.
+pub(super) async fn move_object(
+    bucket: &Bucket,
+    from_path: &str,
+    to_path: &str,
+    size: u64,
+    metadata: &str,
+    enveloped: bool,
+) -> Result<()> {
+    let mut target = bucket.clone();
+    target.add_header(METADATA_HEADER, metadata);
+    if enveloped {
+        target.add_header(ENVELOPE_HEADER, "1");
+    }
+    if size > MAX_COPY_SIZE {
+        return copy_object_multipart(bucket, &target, from_path, to_path, size).await;
+    }
+    target.add_header("x-amz-metadata-directive", "REPLACE");
+    match target.copy_object_internal(from_path, to_path).await? {
+        200..=299 => Ok(()),
+        _ => Err(BlobStoreError::Unexpected),
+    }
+}
+
+/// Copies an object to a new key using UploadPartCopy requests, aborting
+/// the multipart upload on failure. The new object gets the user metadata
+/// headers of the `target` bucket handle.
+async fn copy_object_multipart(
+    bucket: &Bucket,
+    target: &Bucket,
+    from_path: &str,
+    to_path: &str,
+    size: u64,
+) -> Result<()> {
+    let upload = target
+        .initiate_multipart_upload(to_path, CONTENT_TYPE)
+        .await?;
+    let result: Result<()> = async {
+        let mut parts = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            let end = (offset + COPY_PART_SIZE).min(size) - 1;
+            let part_number = parts.len() as u32 + 1;
+            let etag = upload_part_copy(
+                bucket,
+                from_path,
+                to_path,
+                &upload.upload_id,
+                part_number,
+                offset..=end,
+            )
+            .await?;
+            parts.push(Part { part_number, etag });
+            offset = end + 1;
+        }
+        complete_multipart_upload(bucket, to_path, &upload.upload_id, parts).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = bucket.abort_upload(to_path, &upload.upload_id).await;
+    }
+    result
+}
+
+/// Copies a byte range of an object into a part of a multipart upload,
+/// returning the part's ETag.
+async fn upload_part_copy(
+    bucket: &Bucket,
+    from_path: &str,
+    to_path: &str,
+    upload_id: &str,
+    part_number: u32,
+    range: RangeInclusive<u64>,
+) -> Result<String> {
+    let mut bucket = bucket.clone();
+    let from_path = from_path.strip_prefix('/').unwrap_or(from_path);
+    bucket.add_header(
+        "x-amz-copy-source",
+        &format!("{}/{}", bucket.name(), from_path),
+    );
+    bucket.add_header(
+        "x-amz-copy-source-range",
+        &format!("bytes={}-{}", range.start(), range.end()),
+    );
+    let command = Command::PutObject {
+        content: &[],
+        multipart: Some(Multipart::new(part_number, upload_id)),
+        content_type: CONTENT_TYPE,
+    };
+    let response = Reqwest::new(&bucket, to_path, command)?
+        .response_data(false)
+        .await?;
+    if !(200..300).contains(&response.status_code()) {
+        return Err(BlobStoreError::Unexpected);
+    }
+    parse_copy_etag(response.as_str().unwrap_or_default())
+}
+
+async fn complete_multipart_upload(
+    bucket: &Bucket,
+    path: &str,
+    upload_id: &str,
+    parts: Vec<Part>,
+) -> Result<()> {
+    let response = bucket
+        .complete_multipart_upload(path, upload_id, parts)
+        .await?;
+    match is_completed(
+        response.status_code(),
+        response.as_str().unwrap_or_default(),
+    ) {
+        true => Ok(()),
+        false => Err(BlobStoreError::Unexpected),
+    }
+}
+
+/// Returns the size of the given part of a multipart upload, numbered from 1.
+fn upload_part_size(part_number: u32) -> u64 {
+    UPLOAD_PART_SIZE << ((part_number - 1) / PARTS_PER_PART_SIZE)
+}
+
+/// Reads up to a part's worth of data, stopping early only at its end.
+async fn read_part(reader: &mut (impl AsyncRead + Unpin), part_size: u64) -> Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(part_size as usize);
+    (&mut *reader)
+        .take(part_size)
+        .read_to_end(&mut part)
+        .await?;
+    Ok(part)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        assert_eq!(upload_part_size(1), UPLOAD_PART_SIZE);
+        assert_eq!(upload_part_size(PARTS_PER_PART_SIZE), UPLOAD_PART_SIZE);
+        assert_eq!(
+            upload_part_size(PARTS_PER_PART_SIZE + 1),
+            2 * UPLOAD_PART_SIZE
+        );
+
+        // S3 parts are at most 5 GiB, and objects at most 5 TiB:
+        assert!(upload_part_size(MAX_UPLOAD_PARTS) <= 5 << 30);
+        let max_size: u64 = (1..=MAX_UPLOAD_PARTS).map(upload_part_size).sum();
+        assert!(max_size >= 5 << 40);
+    }
+}
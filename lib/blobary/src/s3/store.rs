@@ -1,20 +1,45 @@
 // This is free and unencumbered software released into the public domain.
 
+use super::{
+    object::{
+        blob_path, check_metadata, decode_metadata, encode_metadata, is_enveloped, key_prefix,
+        outboard_path, temp_path, to_blob_page, ENVELOPE_HEADER,
+    },
+    request::{get_object_range, move_object, put_object_stream},
+    S3Options,
+};
 use crate::{
-    hash, Blob, BlobHash, BlobID, BlobStore, BlobStoreError, BlobStoreExt, BlobStoreOptions,
-    IndexedBlobStore, Result,
+    decode_into_tempfile, encode_into_tempfile, read_range, spool_with_outboard, Blob, BlobData,
+    BlobHash, BlobID, BlobMetadata, BlobPage, BlobStore, BlobStoreError, BlobStoreExt,
+    BlobStoreOptions, Envelope, HashingReader, IndexedBlobStore, Result, ENVELOPE_MAGIC,
+    LIST_PAGE_SIZE, NO_BLOB_ID,
 };
-use s3::creds::Credentials;
 use std::{
-    cell::RefCell,
-    io::{Cursor, Read},
-    rc::Rc,
+    future::Future,
+    io::{self, Read, Seek, SeekFrom},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    runtime::Runtime,
 };
 
+/// How many bytes to fetch per ranged GET request.
+const READ_AHEAD_SIZE: u64 = 8 * 1024 * 1024;
+
+/// A blob store on an S3 bucket.
+///
+/// Its requests are made with the Tokio client of `rust-s3`, on a private
+/// single-threaded runtime that blocks the calling thread. It mustn't be
+/// used from async tasks, other than through `spawn_blocking`; use
+/// `AsyncS3BlobStore` there instead.
 pub struct S3BlobStore {
     pub(crate) config: BlobStoreOptions,
     bucket: s3::Bucket,
     prefix: String,
+    runtime: Arc<Runtime>, // shared with fetched blob data
 }
 
 impl S3BlobStore {
@@ -23,29 +48,64 @@ impl S3BlobStore {
         bucket: impl AsRef<str>,
         prefix: impl AsRef<str>,
         config: BlobStoreOptions,
+    ) -> Result<Self> {
+        Self::open_with_options(bucket, prefix, S3Options::default(), config)
+    }
+
+    pub fn open_with_options(
+        bucket: impl AsRef<str>,
+        prefix: impl AsRef<str>,
+        options: S3Options,
+        config: BlobStoreOptions,
     ) -> Result<Self> {
         let bucket = s3::Bucket::new(
             bucket.as_ref(),
-            s3::Region::UsEast1,     // TODO: support other regions
-            Credentials::default()?, // TODO: support other credentials
+            options.load_region(),
+            options.credentials.load()?,
         )?;
+        let bucket = if options.path_style {
+            bucket.with_path_style()
+        } else {
+            bucket
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
         Ok(Self {
             config,
             bucket,
             prefix: prefix.as_ref().to_string(),
+            runtime: Arc::new(runtime),
         })
     }
+
+    fn blob_path(&self, blob_hash: BlobHash) -> String {
+        blob_path(&self.prefix, blob_hash)
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
 }
 
 impl BlobStore for S3BlobStore {
     fn count(&self) -> Result<BlobID> {
-        Err(BlobStoreError::Unsupported)
+        let mut count = 0;
+        let mut token = None;
+        loop {
+            let page = self.list(token.as_deref())?;
+            count += page.blobs.len();
+            match page.next {
+                None => return Ok(count),
+                next => token = next,
+            }
+        }
     }
 
     fn contains_hash(&self, blob_hash: BlobHash) -> Result<bool> {
-        let blob_path = format!("{}/{}", self.prefix, blob_hash);
+        let blob_path = self.blob_path(blob_hash);
 
-        match self.bucket.head_object(blob_path) {
+        match self.block_on(self.bucket.head_object(blob_path)) {
             Err(err) => Err(err.into()),
             Ok((_, status_code)) => {
                 match status_code {
@@ -58,24 +118,45 @@ impl BlobStore for S3BlobStore {
     }
 
     fn get_by_hash(&self, blob_hash: BlobHash) -> Result<Option<Blob>> {
-        let blob_path = format!("{}/{}", self.prefix, blob_hash);
+        let blob_path = self.blob_path(blob_hash);
 
-        match self.bucket.get_object(blob_path) {
+        match self.block_on(self.bucket.head_object(&blob_path)) {
             Err(err) => Err(err.into()),
-            Ok(response) => {
-                match response.status_code() {
+            Ok((head, status_code)) => {
+                match status_code {
                     404 => Ok(None), // not found
                     200 => {
                         // found
-                        let blob_data = response.bytes().to_vec();
-                        let blob_size = blob_data.len();
-                        let blob_data = Cursor::new(blob_data);
-                        let blob_data = Rc::new(RefCell::new(blob_data));
+                        let object_size = head.content_length.unwrap_or(0) as u64;
+                        let mut blob_data = S3BlobData {
+                            bucket: self.bucket.clone(),
+                            runtime: self.runtime.clone(),
+                            path: blob_path,
+                            size: object_size,
+                            pos: 0,
+                            buffer: Vec::new(),
+                            buffer_pos: 0,
+                        };
+                        let (blob_size, blob_data): (u64, Arc<Mutex<dyn BlobData>>) =
+                            match is_enveloped(&head) {
+                                false => (object_size, Arc::new(Mutex::new(blob_data))),
+                                true => {
+                                    let envelope =
+                                        Envelope::read_from(&mut blob_data)?.unwrap_or_default();
+                                    let filters = envelope.resolve(&self.config.filters)?;
+                                    let mut blob_file =
+                                        decode_into_tempfile(&filters, &mut blob_data)?;
+                                    let blob_size = blob_file.seek(SeekFrom::End(0))?;
+                                    blob_file.rewind()?;
+                                    (blob_size, Arc::new(Mutex::new(blob_file)))
+                                }
+                            };
                         Ok(Some(Blob {
-                            id: 0, // FIXME
+                            id: NO_BLOB_ID,
                             hash: blob_hash,
-                            size: blob_size as _,
+                            size: blob_size,
                             data: Some(blob_data),
+                            metadata: decode_metadata(&head)?,
                         }))
                     }
                     _ => Err(BlobStoreError::Unexpected),
@@ -85,42 +166,213 @@ impl BlobStore for S3BlobStore {
     }
 
     fn put(&mut self, blob_data: &mut dyn Read) -> Result<(bool, Blob)> {
+        self.put_with_metadata(blob_data, BlobMetadata::new())
+    }
+
+    fn put_with_metadata(
+        &mut self,
+        blob_data: &mut dyn Read,
+        metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
         if !self.config.writable {
-            return Err(crate::BlobStoreError::NotWritable.into());
+            return Err(crate::BlobStoreError::NotWritable);
         }
+        check_metadata(&metadata)?;
 
-        let mut buffer = Vec::new();
-        blob_data.read_to_end(&mut buffer)?;
-
-        let blob = Blob {
-            id: 0, // FIXME
-            hash: hash(&buffer),
-            size: buffer.len() as u64,
-            data: None,
+        // Stream the blob data into a temporary key, hashing it on the way,
+        // and encoding it first if it needs an envelope:
+        let temp_path = temp_path(&self.prefix);
+        let mut reader = HashingReader::with_max_size(blob_data, self.config.max_blob_size);
+        let (mut spool_file, outboard) = match self.config.outboard {
+            false => (None, None),
+            true => {
+                // Spool the blob data first, to compute its outboard:
+                let (spool_file, outboard) = spool_with_outboard(&mut reader)?;
+                (Some(spool_file), Some(outboard))
+            }
         };
-        let blob_path = format!("{}/{}", self.prefix, blob.hash);
-
-        match self
-            .bucket
-            .put_object(blob_path.as_str(), buffer.as_slice())
-        {
-            Ok(_response) => Ok((true, blob)),
-            Err(err) => Err(err.into()),
+        let source: &mut dyn Read = match spool_file.as_mut() {
+            Some(spool_file) => spool_file,
+            None => &mut reader,
+        };
+        let mut data_prefix = Vec::with_capacity(ENVELOPE_MAGIC.len());
+        source
+            .take(ENVELOPE_MAGIC.len() as u64)
+            .read_to_end(&mut data_prefix)?;
+        let enveloped = Envelope::is_required(&self.config.filters, &data_prefix);
+        let mut input = (&data_prefix[..]).chain(source);
+        let (result, object_size) = match enveloped {
+            false => {
+                let mut input = BlockingReader(input);
+                let result = put_object_stream(&self.bucket, &mut input, &temp_path);
+                (self.block_on(result), None)
+            }
+            true => {
+                let mut object_file = encode_into_tempfile(&self.config.filters, &mut input)?;
+                let object_size = object_file.metadata()?.len();
+                let mut object_file = BlockingReader(&mut object_file);
+                let result = put_object_stream(&self.bucket, &mut object_file, &temp_path);
+                (self.block_on(result), Some(object_size))
+            }
+        };
+        if let Err(err) = result {
+            let _ = self.block_on(self.bucket.delete_object(&temp_path));
+            return Err(err);
         }
+        let metadata = metadata.complete(reader.prefix());
+        let (blob_hash, blob_size) = reader.finalize();
+        let object_size = object_size.unwrap_or(blob_size);
+
+        // Move the temporary object to its final key, unless it already
+        // exists, recording the metadata on the object as it is moved, and
+        // storing the outboard next to it first:
+        let result = self.contains_hash(blob_hash).and_then(|exists| {
+            if exists {
+                return Ok((false, self.get_metadata(blob_hash)?));
+            }
+            if let Some(outboard) = &outboard {
+                let outboard_path = outboard_path(&self.prefix, blob_hash);
+                let mut outboard = BlockingReader(&outboard.to_bytes()[..]);
+                self.block_on(put_object_stream(
+                    &self.bucket,
+                    &mut outboard,
+                    &outboard_path,
+                ))?;
+            }
+            let blob_path = self.blob_path(blob_hash);
+            let encoded_metadata = encode_metadata(&metadata)?;
+            self.block_on(move_object(
+                &self.bucket,
+                &temp_path,
+                &blob_path,
+                object_size,
+                &encoded_metadata,
+                enveloped,
+            ))?;
+            Ok((true, Some(metadata)))
+        });
+        let _ = self.block_on(self.bucket.delete_object(&temp_path));
+        let (created, metadata) = result?;
+        Ok((
+            created,
+            Blob {
+                id: NO_BLOB_ID,
+                hash: blob_hash,
+                size: blob_size,
+                data: None,
+                metadata,
+            },
+        ))
+    }
+
+    fn max_blob_size(&self) -> Option<u64> {
+        self.config.max_blob_size
     }
 
     fn remove(&mut self, blob_hash: BlobHash) -> Result<bool> {
         if !self.config.writable {
-            return Err(crate::BlobStoreError::NotWritable.into());
+            return Err(crate::BlobStoreError::NotWritable);
         }
 
-        let blob_path = format!("{}/{}", self.prefix, blob_hash);
+        let blob_path = self.blob_path(blob_hash);
+
+        // Remove the outboard too, even if kept from when the store was
+        // opened with outboards enabled:
+        let outboard_path = outboard_path(&self.prefix, blob_hash);
+        self.block_on(self.bucket.delete_object(outboard_path))?;
 
-        match self.bucket.delete_object(blob_path.as_str()) {
+        match self.block_on(self.bucket.delete_object(blob_path.as_str())) {
             Ok(_response) => Ok(true), // can't determine if it existed or not
             Err(err) => Err(err.into()),
         }
     }
+
+    fn get_range(&self, blob_hash: BlobHash, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        if len == 0 {
+            return Ok(self.contains_hash(blob_hash)?.then(Vec::new));
+        }
+        let blob_path = self.blob_path(blob_hash);
+        let end = offset.saturating_add(len) - 1;
+        let response = self.block_on(get_object_range(&self.bucket, &blob_path, offset..=end))?;
+        match response.status_code() {
+            404 => Ok(None),             // not found
+            416 => Ok(Some(Vec::new())), // past the end of the blob
+            200 | 206 if response.headers().contains_key(ENVELOPE_HEADER) => {
+                // Filtered blobs must be decoded from their start:
+                match self.get_by_hash(blob_hash)? {
+                    None => Ok(None),
+                    Some(blob) => Ok(Some(read_range(
+                        &mut *blob.data.unwrap().lock().unwrap(),
+                        offset,
+                        len,
+                    )?)),
+                }
+            }
+            200 | 206 => {
+                let mut bytes = response.bytes().to_vec();
+                bytes.truncate(len.try_into().unwrap_or(usize::MAX));
+                Ok(Some(bytes))
+            }
+            _ => Err(BlobStoreError::Unexpected),
+        }
+    }
+
+    fn get_outboard_range(
+        &self,
+        blob_hash: BlobHash,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let outboard_path = outboard_path(&self.prefix, blob_hash);
+        if len == 0 {
+            let (_, status_code) = self.block_on(self.bucket.head_object(&outboard_path))?;
+            return match status_code {
+                404 => Ok(None), // not found
+                200 => Ok(Some(Vec::new())),
+                _ => Err(BlobStoreError::Unexpected),
+            };
+        }
+        let end = offset.saturating_add(len) - 1;
+        let response =
+            self.block_on(get_object_range(&self.bucket, &outboard_path, offset..=end))?;
+        match response.status_code() {
+            404 => Ok(None),             // not found
+            416 => Ok(Some(Vec::new())), // past the end of the outboard
+            200 | 206 => {
+                let mut bytes = response.bytes().to_vec();
+                bytes.truncate(len.try_into().unwrap_or(usize::MAX));
+                Ok(Some(bytes))
+            }
+            _ => Err(BlobStoreError::Unexpected),
+        }
+    }
+
+    /// Lists the blobs under the prefix. Filtered blobs are listed with the
+    /// size of their stored objects, as decoding them would need a download.
+    fn list(&self, token: Option<&str>) -> Result<BlobPage> {
+        let key_prefix = key_prefix(&self.prefix);
+        let (result, status_code) = self.block_on(self.bucket.list_page(
+            key_prefix.clone(),
+            Some("/".to_string()),
+            token.map(|token| token.to_string()),
+            None,
+            Some(LIST_PAGE_SIZE),
+        ))?;
+        if status_code != 200 {
+            return Err(BlobStoreError::Unexpected);
+        }
+        Ok(to_blob_page(result, &key_prefix))
+    }
+
+    fn get_metadata(&self, blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        let (head, status_code) =
+            self.block_on(self.bucket.head_object(self.blob_path(blob_hash)))?;
+        match status_code {
+            404 => Ok(None), // not found
+            200 => decode_metadata(&head),
+            _ => Err(BlobStoreError::Unexpected),
+        }
+    }
 }
 
 impl IndexedBlobStore for S3BlobStore {
@@ -135,6 +387,112 @@ impl IndexedBlobStore for S3BlobStore {
     fn get_by_id(&self, _blob_id: BlobID) -> Result<Option<Blob>> {
         Err(BlobStoreError::Unsupported)
     }
+
+    fn is_indexed(&self) -> bool {
+        false
+    }
 }
 
 impl BlobStoreExt for S3BlobStore {}
+
+/// A seekable reader over an S3 object that fetches it lazily in ranges.
+struct S3BlobData {
+    bucket: s3::Bucket,
+    runtime: Arc<Runtime>,
+    path: String,
+    size: u64,
+    pos: u64,
+    buffer: Vec<u8>,
+    buffer_pos: u64,
+}
+
+impl S3BlobData {
+    fn fetch(&mut self) -> std::io::Result<()> {
+        let start = self.pos;
+        let end = (start + READ_AHEAD_SIZE).min(self.size) - 1;
+        let response = self
+            .runtime
+            .block_on(get_object_range(&self.bucket, &self.path, start..=end))
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        match response.status_code() {
+            200 | 206 => {
+                self.buffer = response.bytes().to_vec();
+                self.buffer_pos = start;
+                Ok(())
+            }
+            status_code => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("unexpected HTTP status code: {}", status_code),
+            )),
+        }
+    }
+}
+
+impl Read for S3BlobData {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let buffer_end = self.buffer_pos + self.buffer.len() as u64;
+        if self.pos < self.buffer_pos || self.pos >= buffer_end {
+            self.fetch()?;
+        }
+        let offset = (self.pos - self.buffer_pos) as usize;
+        let len = buf.len().min(self.buffer.len() - offset);
+        if len == 0 {
+            return Ok(0);
+        }
+        buf[..len].copy_from_slice(&self.buffer[offset..offset + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for S3BlobData {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+impl BlobData for S3BlobData {
+    fn size(&mut self) -> std::io::Result<u64> {
+        Ok(self.size)
+    }
+}
+
+/// Adapts a blocking reader for the async uploads, which the store's runtime
+/// drives on the calling thread anyway.
+struct BlockingReader<R>(R);
+
+impl<R: Read + Unpin> AsyncRead for BlockingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            match self.0.read(buf.initialize_unfilled()) {
+                Ok(len) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+}
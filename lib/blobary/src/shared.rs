@@ -0,0 +1,165 @@
+// This is free and unencumbered software released into the public domain.
+
+use crate::{
+    Blob, BlobHash, BlobID, BlobMetadata, BlobPage, BlobStore, BlobStoreExt, IndexedBlobStore,
+    Result,
+};
+use std::{
+    io::Read,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+/// A handle to a blob store shared across threads.
+///
+/// Clones of the handle refer to the same store. Reads from different
+/// threads proceed concurrently, while writes take exclusive access.
+pub struct SharedBlobStore<S> {
+    inner: Arc<RwLock<S>>,
+}
+
+impl<S> SharedBlobStore<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(store)),
+        }
+    }
+
+    /// Locks the store for reading.
+    pub fn read(&self) -> RwLockReadGuard<'_, S> {
+        self.inner.read().unwrap()
+    }
+
+    /// Locks the store for writing.
+    pub fn write(&self) -> RwLockWriteGuard<'_, S> {
+        self.inner.write().unwrap()
+    }
+}
+
+impl<S> Clone for SharedBlobStore<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: BlobStore> BlobStore for SharedBlobStore<S> {
+    fn count(&self) -> Result<usize> {
+        self.read().count()
+    }
+
+    fn contains_hash(&self, blob_hash: BlobHash) -> Result<bool> {
+        self.read().contains_hash(blob_hash)
+    }
+
+    fn get_by_hash(&self, blob_hash: BlobHash) -> Result<Option<Blob>> {
+        self.read().get_by_hash(blob_hash)
+    }
+
+    fn put(&mut self, blob_data: &mut dyn Read) -> Result<(bool, Blob)> {
+        self.write().put(blob_data)
+    }
+
+    fn put_with_metadata(
+        &mut self,
+        blob_data: &mut dyn Read,
+        metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
+        self.write().put_with_metadata(blob_data, metadata)
+    }
+
+    fn max_blob_size(&self) -> Option<u64> {
+        self.read().max_blob_size()
+    }
+
+    fn remove(&mut self, blob_hash: BlobHash) -> Result<bool> {
+        self.write().remove(blob_hash)
+    }
+
+    fn list(&self, token: Option<&str>) -> Result<BlobPage> {
+        self.read().list(token)
+    }
+
+    fn get_range(&self, blob_hash: BlobHash, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        self.read().get_range(blob_hash, offset, len)
+    }
+
+    fn get_outboard_range(
+        &self,
+        blob_hash: BlobHash,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        self.read().get_outboard_range(blob_hash, offset, len)
+    }
+
+    fn get_metadata(&self, blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        self.read().get_metadata(blob_hash)
+    }
+}
+
+impl<S: IndexedBlobStore> IndexedBlobStore for SharedBlobStore<S> {
+    fn hash_to_id(&self, blob_hash: BlobHash) -> Result<Option<BlobID>> {
+        self.read().hash_to_id(blob_hash)
+    }
+
+    fn id_to_hash(&self, blob_id: BlobID) -> Result<Option<BlobHash>> {
+        self.read().id_to_hash(blob_id)
+    }
+
+    fn get_by_id(&self, blob_id: BlobID) -> Result<Option<Blob>> {
+        self.read().get_by_id(blob_id)
+    }
+
+    fn is_indexed(&self) -> bool {
+        self.read().is_indexed()
+    }
+}
+
+impl<S: BlobStore> BlobStoreExt for SharedBlobStore<S> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BlobStoreOptions, DirectoryBlobStore, EphemeralBlobStore, SyncBlobStore};
+
+    fn assert_sync<S: SyncBlobStore>() {}
+
+    #[test]
+    fn test() {
+        assert_sync::<DirectoryBlobStore>();
+        assert_sync::<EphemeralBlobStore>();
+        assert_sync::<crate::FileBlobStore>();
+        #[cfg(feature = "redis")]
+        assert_sync::<crate::redis::RedisBlobStore>();
+        #[cfg(feature = "s3")]
+        assert_sync::<crate::s3::S3BlobStore>();
+        #[cfg(feature = "sqlite")]
+        assert_sync::<crate::sqlite::SQLiteBlobStore>();
+
+        let temp_dir = cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+        let store = DirectoryBlobStore::open_tempdir(&temp_dir, BlobStoreOptions::default());
+        let store = SharedBlobStore::new(store.unwrap());
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let mut store = store.clone();
+                std::thread::spawn(move || {
+                    let (_, blob) = store.put_string(format!("Foo{}", i % 2)).unwrap();
+                    let blob = store.get_by_hash(blob.hash).unwrap().unwrap();
+                    let mut buffer = String::new();
+                    let blob_data = blob.data.unwrap();
+                    blob_data
+                        .lock()
+                        .unwrap()
+                        .read_to_string(&mut buffer)
+                        .unwrap();
+                    buffer
+                })
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            assert_eq!(thread.join().unwrap(), format!("Foo{}", i % 2));
+        }
+        assert_eq!(store.count().unwrap(), 2);
+    }
+}
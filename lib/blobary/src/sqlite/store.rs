@@ -1,47 +1,513 @@
 // This is free and unencumbered software released into the public domain.
 
 use crate::{
-    Blob, BlobHash, BlobID, BlobStore, BlobStoreError, BlobStoreExt, IndexedBlobStore, Result,
+    parse_id_token, Blob, BlobData, BlobHash, BlobID, BlobMetadata, BlobPage, BlobStore,
+    BlobStoreError, BlobStoreExt, BlobStoreOptions, HashingReader, IndexedBlobStore, Result,
+    LIST_PAGE_SIZE,
 };
-use std::io::Read;
+use cap_tempfile::{ambient_authority, TempDir, TempFile};
+use rusqlite::{
+    blob::ZeroBlob, limits::Limit, params, Connection, DatabaseName, OpenFlags, OptionalExtension,
+};
+use std::{
+    io::{Read, Seek, SeekFrom},
+    ops::Deref,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+const TABLE_NAME: &str = "blobs";
+const DATA_COLUMN: &str = "data";
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS blobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    hash BLOB NOT NULL UNIQUE,
+    size INTEGER NOT NULL,
+    data BLOB NOT NULL
+)";
+
+/// Blob metadata records live in a table of their own, so that databases
+/// created before they were recorded need no migration.
+const METADATA_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS metadata (
+    id INTEGER PRIMARY KEY REFERENCES blobs (id),
+    data BLOB NOT NULL
+)";
+
+pub struct SQLiteBlobStore {
+    pub(crate) config: BlobStoreOptions,
+    connection: Arc<Mutex<Connection>>,
+    has_metadata: bool,
+}
+
+impl SQLiteBlobStore {
+    /// Opens the database at the path, creating it if the store is
+    /// writable. Read-only stores fail with [`NotFound`] if the database or
+    /// its tables don't exist.
+    ///
+    /// [`NotFound`]: std::io::ErrorKind::NotFound
+    pub fn open_path(path: impl AsRef<Path>, config: BlobStoreOptions) -> Result<Self> {
+        if !config.writable && !path.as_ref().exists() {
+            return Err(not_found("SQLite database not found").into());
+        }
+        let flags = if config.writable {
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
+        } else {
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+        };
+        let connection =
+            Connection::open_with_flags(path, flags | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+        Self::open_connection(connection, config)
+    }
+
+    pub fn open_in_memory(config: BlobStoreOptions) -> Result<Self> {
+        Self::open_connection(Connection::open_in_memory()?, config)
+    }
+
+    pub fn open_connection(connection: Connection, config: BlobStoreOptions) -> Result<Self> {
+        config.check_unfiltered("SQLite")?;
+        if config.writable {
+            connection.execute(SCHEMA, [])?;
+            connection.execute(METADATA_SCHEMA, [])?;
+        }
+        if !has_table(&connection, TABLE_NAME)? {
+            return Err(not_found("SQLite database has no blob store").into());
+        }
+        let has_metadata = has_table(&connection, "metadata")?;
+        Ok(Self {
+            config,
+            connection: Arc::new(Mutex::new(connection)),
+            has_metadata,
+        })
+    }
+
+    fn read_metadata(&self, blob_id: BlobID) -> Result<Option<BlobMetadata>> {
+        if !self.has_metadata {
+            return Ok(None);
+        }
+        let metadata: Option<Vec<u8>> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM metadata WHERE id = ?1",
+                [blob_id as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match metadata {
+            None => Ok(None),
+            Some(metadata) => Ok(Some(BlobMetadata::from_bytes(&metadata)?)),
+        }
+    }
 
-#[derive(Default)]
-pub struct SQLiteBlobStore {}
+    fn max_id(&self) -> Result<BlobID> {
+        let max_id: Option<i64> =
+            self.connection
+                .lock()
+                .unwrap()
+                .query_row("SELECT MAX(id) FROM blobs", [], |row| row.get(0))?;
+        Ok(max_id.unwrap_or(0) as BlobID)
+    }
+
+    fn read_row(&self, sql: &str, key: impl rusqlite::ToSql) -> Result<Option<Blob>> {
+        let row = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(sql, [key], |row| {
+                let blob_id: i64 = row.get(0)?;
+                let blob_hash: Vec<u8> = row.get(1)?;
+                let blob_size: i64 = row.get(2)?;
+                Ok((blob_id, blob_hash, blob_size))
+            })
+            .optional()?;
+        match row {
+            None => Ok(None),
+            Some((blob_id, blob_hash, blob_size)) => {
+                let blob_hash = BlobHash::from_vec(&blob_hash)
+                    .map_err(|err| BlobStoreError::Other(Box::new(err)))?;
+                let blob_data = SQLiteBlobData {
+                    connection: self.connection.clone(),
+                    row_id: blob_id,
+                    size: blob_size as u64,
+                    pos: 0,
+                };
+                Ok(Some(Blob {
+                    id: blob_id as BlobID,
+                    hash: blob_hash,
+                    size: blob_size as u64,
+                    data: Some(Arc::new(Mutex::new(blob_data))),
+                    metadata: self.read_metadata(blob_id as BlobID)?,
+                }))
+            }
+        }
+    }
+}
 
 impl BlobStore for SQLiteBlobStore {
     fn count(&self) -> Result<BlobID> {
-        Err(BlobStoreError::Unimplemented("count".to_string())) // TODO
+        let count: i64 =
+            self.connection
+                .lock()
+                .unwrap()
+                .query_row("SELECT COUNT(*) FROM blobs", [], |row| row.get(0))?;
+        Ok(count as BlobID)
+    }
+
+    fn contains_hash(&self, blob_hash: BlobHash) -> Result<bool> {
+        Ok(self.hash_to_id(blob_hash)?.is_some())
+    }
+
+    fn get_by_hash(&self, blob_hash: BlobHash) -> Result<Option<Blob>> {
+        self.read_row(
+            "SELECT id, hash, size FROM blobs WHERE hash = ?1",
+            blob_hash.0.as_bytes(),
+        )
+    }
+
+    fn put(&mut self, blob_data: &mut dyn Read) -> Result<(bool, Blob)> {
+        self.put_with_metadata(blob_data, BlobMetadata::new())
+    }
+
+    fn put_with_metadata(
+        &mut self,
+        blob_data: &mut dyn Read,
+        metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
+        if !self.config.writable {
+            return Err(crate::BlobStoreError::NotWritable);
+        }
+
+        // Buffer the blob data in a temporary file, hashing it on the way:
+        let temp_dir = TempDir::new(ambient_authority())?;
+        let mut data_file = TempFile::new(temp_dir.deref())?;
+        let mut data_reader = HashingReader::with_max_size(blob_data, self.max_blob_size());
+        std::io::copy(&mut data_reader, &mut data_file)?;
+        let metadata = metadata.complete(data_reader.prefix());
+        let (blob_hash, blob_size) = data_reader.finalize();
+
+        // Reserve the row, unless the blob is already in the store, and
+        // stream the blob data into it. Conflicting inserts would still use
+        // up an ID, so existing blobs are skipped in the same statement:
+        let connection = self.connection.lock().unwrap();
+        let tx = connection.unchecked_transaction()?;
+        let inserted = tx.execute(
+            "INSERT INTO blobs (hash, size, data) SELECT ?1, ?2, ?3
+                WHERE NOT EXISTS (SELECT 1 FROM blobs WHERE hash = ?1)
+                ON CONFLICT (hash) DO NOTHING",
+            params![
+                blob_hash.0.as_bytes(),
+                blob_size as i64,
+                ZeroBlob(blob_size as i32)
+            ],
+        )?;
+        if inserted == 0 {
+            let blob_id: i64 = tx.query_row(
+                "SELECT id FROM blobs WHERE hash = ?1",
+                [blob_hash.0.as_bytes()],
+                |row| row.get(0),
+            )?;
+            tx.commit()?;
+            drop(connection);
+            return Ok((
+                false,
+                Blob {
+                    id: blob_id as BlobID,
+                    hash: blob_hash,
+                    size: blob_size,
+                    data: None,
+                    metadata: self.read_metadata(blob_id as BlobID)?,
+                },
+            ));
+        }
+        let row_id = tx.last_insert_rowid();
+        {
+            let mut sql_blob =
+                tx.blob_open(DatabaseName::Main, TABLE_NAME, DATA_COLUMN, row_id, false)?;
+            data_file.rewind()?;
+            std::io::copy(&mut data_file, &mut sql_blob)?;
+            sql_blob.close()?;
+        }
+        tx.execute(
+            "INSERT INTO metadata (id, data) VALUES (?1, ?2)",
+            params![row_id, metadata.to_bytes()],
+        )?;
+        tx.commit()?;
+
+        Ok((
+            true,
+            Blob {
+                id: row_id as BlobID,
+                hash: blob_hash,
+                size: blob_size,
+                data: None,
+                metadata: Some(metadata),
+            },
+        ))
     }
 
-    fn contains_hash(&self, _blob_hash: BlobHash) -> Result<bool> {
-        Err(BlobStoreError::Unimplemented("contains_hash".to_string())) // TODO
+    /// SQLite limits the length of blobs, which can be lowered per
+    /// connection, so larger blobs are refused before they are spooled.
+    fn max_blob_size(&self) -> Option<u64> {
+        let max_length = self
+            .connection
+            .lock()
+            .unwrap()
+            .limit(Limit::SQLITE_LIMIT_LENGTH) as u64;
+        match self.config.max_blob_size {
+            Some(max_blob_size) => Some(max_blob_size.min(max_length)),
+            None => Some(max_length),
+        }
     }
 
-    fn get_by_hash(&self, _blob_hash: BlobHash) -> Result<Option<Blob>> {
-        Err(BlobStoreError::Unimplemented("get_by_hash".to_string())) // TODO
+    fn remove(&mut self, blob_hash: BlobHash) -> Result<bool> {
+        if !self.config.writable {
+            return Err(crate::BlobStoreError::NotWritable);
+        }
+
+        let connection = self.connection.lock().unwrap();
+        let tx = connection.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM metadata WHERE id = (SELECT id FROM blobs WHERE hash = ?1)",
+            [blob_hash.0.as_bytes()],
+        )?;
+        let deleted = tx.execute(
+            "DELETE FROM blobs WHERE hash = ?1",
+            [blob_hash.0.as_bytes()],
+        )?;
+        tx.commit()?;
+        Ok(deleted > 0)
     }
 
-    fn put(&mut self, _blob_data: &mut dyn Read) -> Result<(bool, Blob)> {
-        Err(BlobStoreError::Unimplemented("put".to_string())) // TODO
+    fn list(&self, token: Option<&str>) -> Result<BlobPage> {
+        let start = parse_id_token(token)?;
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT id, hash, size FROM blobs WHERE id > ?1 ORDER BY id LIMIT ?2",
+        )?;
+        let mut rows = statement.query(params![start as i64, LIST_PAGE_SIZE as i64])?;
+        let mut blobs = Vec::new();
+        let mut last_id = start;
+        while let Some(row) = rows.next()? {
+            let blob_id: i64 = row.get(0)?;
+            let blob_hash: Vec<u8> = row.get(1)?;
+            let blob_size: i64 = row.get(2)?;
+            let blob_hash = BlobHash::from_vec(&blob_hash)
+                .map_err(|err| BlobStoreError::Other(Box::new(err)))?;
+            blobs.push((blob_hash, blob_size as u64));
+            last_id = blob_id as BlobID;
+        }
+        Ok(BlobPage {
+            next: (blobs.len() == LIST_PAGE_SIZE).then(|| last_id.to_string()),
+            blobs,
+        })
     }
 
-    fn remove(&mut self, _blob_hash: BlobHash) -> Result<bool> {
-        Err(BlobStoreError::Unimplemented("remove".to_string())) // TODO
+    fn get_metadata(&self, blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        match self.hash_to_id(blob_hash)? {
+            None => Ok(None),
+            Some(blob_id) => self.read_metadata(blob_id),
+        }
     }
 }
 
 impl IndexedBlobStore for SQLiteBlobStore {
-    fn hash_to_id(&self, _blob_hash: BlobHash) -> Result<Option<BlobID>> {
-        Err(BlobStoreError::Unimplemented("hash_to_id".to_string())) // TODO
+    fn hash_to_id(&self, blob_hash: BlobHash) -> Result<Option<BlobID>> {
+        let blob_id: Option<i64> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT id FROM blobs WHERE hash = ?1",
+                [blob_hash.0.as_bytes()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(blob_id.map(|blob_id| blob_id as BlobID))
     }
 
-    fn id_to_hash(&self, _blob_id: BlobID) -> Result<Option<BlobHash>> {
-        Err(BlobStoreError::Unimplemented("id_to_hash".to_string())) // TODO
+    fn id_to_hash(&self, blob_id: BlobID) -> Result<Option<BlobHash>> {
+        let blob_hash: Option<Vec<u8>> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT hash FROM blobs WHERE id = ?1",
+                [blob_id as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match blob_hash {
+            None => Ok(None),
+            Some(blob_hash) => BlobHash::from_vec(&blob_hash)
+                .map(Some)
+                .map_err(|err| BlobStoreError::Other(Box::new(err))),
+        }
     }
 
-    fn get_by_id(&self, _blob_id: BlobID) -> Result<Option<Blob>> {
-        Err(BlobStoreError::Unimplemented("get_by_id".to_string())) // TODO
+    fn get_by_id(&self, blob_id: BlobID) -> Result<Option<Blob>> {
+        match self.read_row(
+            "SELECT id, hash, size FROM blobs WHERE id = ?1",
+            blob_id as i64,
+        )? {
+            // The ID was assigned once, but the row has since been deleted:
+            None if blob_id > 0 && blob_id <= self.max_id()? => Err(BlobStoreError::Removed),
+            result => Ok(result),
+        }
     }
 }
 
 impl BlobStoreExt for SQLiteBlobStore {}
+
+/// A seekable reader over a blob column, using SQLite's incremental BLOB I/O.
+/// A BLOB handle borrows the connection, so one is opened under the
+/// connection's lock for each read.
+struct SQLiteBlobData {
+    connection: Arc<Mutex<Connection>>,
+    row_id: i64,
+    size: u64,
+    pos: u64,
+}
+
+impl Read for SQLiteBlobData {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let connection = self.connection.lock().unwrap();
+        let sql_blob = connection
+            .blob_open(
+                DatabaseName::Main,
+                TABLE_NAME,
+                DATA_COLUMN,
+                self.row_id,
+                true,
+            )
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        let read_len = sql_blob
+            .read_at(buf, self.pos as usize)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        self.pos += read_len as u64;
+        Ok(read_len)
+    }
+}
+
+impl Seek for SQLiteBlobData {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+impl BlobData for SQLiteBlobData {
+    fn size(&mut self) -> std::io::Result<u64> {
+        Ok(self.size)
+    }
+}
+
+fn has_table(connection: &Connection, table_name: &str) -> Result<bool> {
+    Ok(connection
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [table_name],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+fn not_found(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test() {
+        // Filters are refused rather than silently ignored:
+        let config = BlobStoreOptions::default().filter(Box::new(crate::NoopFilter {}));
+        assert!(matches!(
+            SQLiteBlobStore::open_in_memory(config),
+            Err(BlobStoreError::Unimplemented(_))
+        ));
+
+        let mut store = SQLiteBlobStore::open_in_memory(BlobStoreOptions::default()).unwrap();
+        assert_eq!(store.count().unwrap(), 0);
+
+        let (_, foo) = store.put_string("Foo").unwrap();
+        assert_eq!(store.count().unwrap(), 1);
+        assert_eq!(foo.id, 1);
+
+        let (_, foo2) = store.put_string("Foo").unwrap();
+        assert_eq!(store.count().unwrap(), 1);
+        assert_eq!(foo2.id, 1);
+
+        let (_, bar) = store.put_string("Bar").unwrap();
+        assert_eq!(store.count().unwrap(), 2);
+        assert_eq!(bar.id, 2);
+
+        let bar = store.get_by_hash(bar.hash).unwrap().unwrap();
+        let mut buffer = String::new();
+        let bar_data = bar.data.unwrap();
+        bar_data
+            .lock()
+            .unwrap()
+            .read_to_string(&mut buffer)
+            .unwrap();
+        assert_eq!(buffer, "Bar");
+        assert!(bar.metadata.unwrap().created.is_some());
+
+        let metadata = BlobMetadata::new().name("baz.txt");
+        let (_, baz) = store.put_with_metadata(&mut &b"Baz"[..], metadata).unwrap();
+        let metadata = store.get_metadata(baz.hash).unwrap().unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("baz.txt"));
+        assert!(store.remove(baz.hash).unwrap());
+        assert_eq!(store.get_metadata(baz.hash).unwrap(), None);
+
+        // Blobs longer than the connection allows are refused up front:
+        let connection = store.connection.lock().unwrap();
+        connection.set_limit(Limit::SQLITE_LIMIT_LENGTH, 1024);
+        drop(connection);
+        assert!(matches!(
+            store.put_string("Quux".repeat(1024)),
+            Err(BlobStoreError::TooLarge(1024))
+        ));
+
+        assert!(store.remove(foo.hash).unwrap());
+        assert_eq!(store.count().unwrap(), 1);
+        assert!(!store.contains_hash(foo.hash).unwrap());
+        assert!(matches!(store.get_by_id(1), Err(BlobStoreError::Removed)));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let is_not_found = |result: Result<SQLiteBlobStore>| match result {
+            Err(BlobStoreError::IO(err)) => err.kind() == std::io::ErrorKind::NotFound,
+            _ => false,
+        };
+        let read_only = || BlobStoreOptions::default().writable(false);
+        let path = std::env::temp_dir().join(format!("{}.db", crate::hash("missing")));
+        assert!(is_not_found(SQLiteBlobStore::open_path(&path, read_only())));
+        assert!(!path.exists());
+        let connection = Connection::open_in_memory().unwrap();
+        assert!(is_not_found(SQLiteBlobStore::open_connection(
+            connection,
+            read_only()
+        )));
+    }
+}
@@ -1,10 +1,27 @@
 // This is free and unencumbered software released into the public domain.
 
-use crate::{Blob, BlobHash, BlobID, BlobStoreError, Filter, IndexedBlobStoreIterator};
-use std::{io::Read, path::Path};
+use crate::{
+    Blob, BlobData, BlobHash, BlobID, BlobMetadata, BlobStoreError, Chunker, ChunkerOptions,
+    ConcatBlobData, Filter, IndexedBlobStoreIterator, Manifest, Outboard,
+};
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
 
 pub type Result<T> = std::result::Result<T, BlobStoreError>;
 
+/// The maximum number of blobs returned per [`BlobStore::list`] page.
+pub const LIST_PAGE_SIZE: usize = 1000;
+
+/// A page of blob hashes and sizes, as returned by [`BlobStore::list`].
+#[derive(Clone, Debug, Default)]
+pub struct BlobPage {
+    pub blobs: Vec<(BlobHash, u64)>,
+    /// The continuation token for the next page, if there are more blobs.
+    pub next: Option<String>,
+}
+
 pub trait BlobStore {
     /// Returns the number of blobs in this store.
     fn count(&self) -> Result<usize>;
@@ -18,10 +35,69 @@ pub trait BlobStore {
     /// Stores a blob and returns its metadata.
     fn put(&mut self, blob_data: &mut dyn Read) -> Result<(bool, Blob)>;
 
+    /// Stores a blob along with a metadata record, if the store keeps them.
+    /// The store fills in the MIME type and insertion time unless given. If
+    /// the blob is already in the store, its existing record is kept.
+    fn put_with_metadata(
+        &mut self,
+        blob_data: &mut dyn Read,
+        _metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
+        self.put(blob_data)
+    }
+
+    /// Returns the largest blob the store accepts, if it limits them.
+    fn max_blob_size(&self) -> Option<u64> {
+        None
+    }
+
     /// Removes a blob by its BLAKE3 hash.
     fn remove(&mut self, blob_hash: BlobHash) -> Result<bool>;
+
+    /// Lists the hashes and sizes of blobs in the store, a page at a time,
+    /// starting from the given continuation token. Stores that list by
+    /// scanning, such as Redis, may list a blob more than once.
+    fn list(&self, token: Option<&str>) -> Result<BlobPage>;
+
+    /// Reads up to `len` bytes of a blob starting at `offset`, stopping at
+    /// the end of the blob.
+    fn get_range(&self, blob_hash: BlobHash, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        match self.get_by_hash(blob_hash)? {
+            None => Ok(None),
+            Some(blob) => Ok(Some(read_range(
+                &mut *blob.data.unwrap().lock().unwrap(),
+                offset,
+                len,
+            )?)),
+        }
+    }
+
+    /// Reads up to `len` bytes of the serialized Bao outboard kept for a
+    /// blob, starting at `offset`, if the store keeps them.
+    fn get_outboard_range(
+        &self,
+        _blob_hash: BlobHash,
+        _offset: u64,
+        _len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Fetches the metadata record kept for a blob, if the store keeps them.
+    fn get_metadata(&self, _blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        Ok(None)
+    }
 }
 
+/// A blob store that can be shared across threads.
+///
+/// Reads only need `&self`, so any number of threads can read from a store
+/// held in an [`Arc`](std::sync::Arc). To also share writes, wrap the store
+/// in a [`SharedBlobStore`](crate::SharedBlobStore).
+pub trait SyncBlobStore: BlobStore + Send + Sync {}
+
+impl<T: BlobStore + Send + Sync + ?Sized> SyncBlobStore for T {}
+
 pub trait IndexedBlobStore: BlobStore {
     /// Converts a BLAKE3 hash to a store ID.
     fn hash_to_id(&self, blob_hash: BlobHash) -> Result<Option<BlobID>>;
@@ -31,6 +107,11 @@ pub trait IndexedBlobStore: BlobStore {
 
     /// Fetches a blob by its store ID.
     fn get_by_id(&self, blob_id: BlobID) -> Result<Option<Blob>>;
+
+    /// Determines if the store supports lookups by store ID.
+    fn is_indexed(&self) -> bool {
+        true
+    }
 }
 
 pub trait BlobStoreExt: BlobStore {
@@ -49,9 +130,102 @@ pub trait BlobStoreExt: BlobStore {
         self.put(&mut data.as_ref().as_bytes())
     }
 
-    /// Stores a blob and returns its store ID.
+    /// Stores a blob and returns its store ID, recording the file name.
     fn put_file(&mut self, path: impl AsRef<Path>) -> Result<(bool, Blob)> {
-        self.put(&mut std::fs::File::open(path)?)
+        let path = path.as_ref();
+        let mut metadata = BlobMetadata::new();
+        if let Some(name) = path.file_name() {
+            metadata = metadata.name(name.to_string_lossy());
+        }
+        self.put_with_metadata(&mut std::fs::File::open(path)?, metadata)
+    }
+
+    /// Stores the data as content-defined chunks, followed by a manifest blob
+    /// listing the chunks, and returns the manifest blob.
+    fn put_chunked(
+        &mut self,
+        data: &mut dyn Read,
+        options: &ChunkerOptions,
+    ) -> Result<(bool, Blob)> {
+        let mut manifest = Manifest::new();
+        for chunk in Chunker::new(data, options)? {
+            let (_, blob) = self.put(&mut &chunk?[..])?;
+            manifest.push(blob.hash, blob.size);
+        }
+        self.put(&mut &manifest.to_bytes()[..])
+    }
+
+    /// Writes out the content described by the given manifest blob, returning
+    /// its size, or `None` if the manifest is not in the store.
+    fn get_chunked(&self, manifest_hash: BlobHash, output: &mut dyn Write) -> Result<Option<u64>> {
+        match self.get_manifest(manifest_hash)? {
+            None => Ok(None),
+            Some(manifest) => Ok(Some(std::io::copy(
+                &mut self.get_concat(&manifest)?,
+                output,
+            )?)),
+        }
+    }
+
+    /// Fetches and parses a manifest blob.
+    fn get_manifest(&self, manifest_hash: BlobHash) -> Result<Option<Manifest>> {
+        match self.get_by_hash(manifest_hash)? {
+            None => Ok(None),
+            Some(blob) => Ok(Some(Manifest::read_from(
+                &mut *blob.data.unwrap().lock().unwrap(),
+            )?)),
+        }
+    }
+
+    /// Reads a byte range of a blob, verifying it against the blob hash. Only
+    /// the chunks covering the range and their parent nodes in the outboard
+    /// are fetched. If the store keeps no outboard for the blob, the whole
+    /// blob is fetched and hashed.
+    fn get_verified_range(
+        &self,
+        blob_hash: BlobHash,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        if self.get_outboard_range(blob_hash, 0, 0)?.is_some() {
+            let mut outboard =
+                RangeReader::new(|offset, len| self.get_outboard_range(blob_hash, offset, len));
+            let mut blob_data =
+                RangeReader::new(|offset, len| self.get_range(blob_hash, offset, len));
+            return match Outboard::read_range_from(
+                blob_hash,
+                &mut outboard,
+                &mut blob_data,
+                offset,
+                len,
+            ) {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None), // removed since
+                Err(err) => Err(err.into()),
+            };
+        }
+
+        let blob = match self.get_by_hash(blob_hash)? {
+            None => return Ok(None),
+            Some(blob) => blob,
+        };
+        let blob_data = blob.data.unwrap();
+        let mut blob_data = blob_data.lock().unwrap();
+        blob_data.rewind()?;
+        let (_, outboard) = Outboard::encode(&mut *blob_data, blob.size)?;
+        Ok(Some(outboard.read_range(
+            blob_hash,
+            &mut *blob_data,
+            offset,
+            len,
+        )?))
+    }
+
+    /// Presents the concatenation of the blobs listed in the manifest as one
+    /// seekable blob, without copying their data. Parts are fetched lazily,
+    /// as reads reach them.
+    fn get_concat(&self, manifest: &Manifest) -> Result<ConcatBlobData<'_, Self>> {
+        Ok(ConcatBlobData::new(self, manifest))
     }
 }
 
@@ -59,6 +233,10 @@ pub trait BlobStoreExt: BlobStore {
 pub struct BlobStoreOptions {
     pub writable: bool,
     pub filters: Vec<Box<dyn Filter>>,
+    /// Whether to keep a Bao outboard per blob, for verified range reads.
+    pub outboard: bool,
+    /// The largest blob that can be stored, in bytes.
+    pub max_blob_size: Option<u64>,
 }
 
 impl Default for BlobStoreOptions {
@@ -66,6 +244,8 @@ impl Default for BlobStoreOptions {
         Self {
             writable: true,
             filters: vec![],
+            outboard: false,
+            max_blob_size: None,
         }
     }
 }
@@ -89,12 +269,109 @@ impl BlobStoreOptions {
         self.filters.push(filter);
         self
     }
+
+    pub fn outboard(mut self, outboard: bool) -> Self {
+        self.outboard = outboard;
+        self
+    }
+
+    pub fn max_blob_size(mut self, max_blob_size: Option<u64>) -> Self {
+        self.max_blob_size = max_blob_size;
+        self
+    }
+
+    /// Fails if filters are configured for a store that can't apply them,
+    /// rather than letting it store blobs unfiltered.
+    pub(crate) fn check_unfiltered(&self, store_kind: &str) -> Result<()> {
+        match self.filters.is_empty() {
+            true => Ok(()),
+            false => Err(BlobStoreError::Unimplemented(format!(
+                "filters for {} stores",
+                store_kind
+            ))),
+        }
+    }
+}
+
+/// Reads up to `len` bytes at `offset` by seeking in the blob data.
+pub(crate) fn read_range(blob_data: &mut dyn BlobData, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    blob_data.seek(SeekFrom::Start(offset))?;
+    blob_data.take(len).read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// A seekable reader over a ranged read such as [`BlobStore::get_range`],
+/// fetching just the bytes asked for by each read. It can't seek from the
+/// end, as the size is unknown.
+pub(crate) struct RangeReader<F> {
+    read_range: F,
+    pos: u64,
+}
+
+impl<F: FnMut(u64, u64) -> Result<Option<Vec<u8>>>> RangeReader<F> {
+    pub(crate) fn new(read_range: F) -> Self {
+        Self { read_range, pos: 0 }
+    }
+}
+
+impl<F: FnMut(u64, u64) -> Result<Option<Vec<u8>>>> Read for RangeReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let bytes = (self.read_range)(self.pos, buf.len() as u64)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "blob not found"))?;
+        let len = bytes.len().min(buf.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<F: FnMut(u64, u64) -> Result<Option<Vec<u8>>>> Seek for RangeReader<F> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "can't seek from the end of a ranged read",
+                ))
+            }
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Parses a continuation token holding the last-listed store ID.
+pub(crate) fn parse_id_token(token: Option<&str>) -> Result<BlobID> {
+    match token {
+        None => Ok(0),
+        Some(token) => token.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid continuation token: {}", token),
+            )
+            .into()
+        }),
+    }
 }
 
 impl BlobStoreExt for dyn BlobStore {}
 
 impl BlobStoreExt for dyn IndexedBlobStore {}
 
+impl BlobStoreExt for dyn SyncBlobStore {}
+
 impl<'a> IntoIterator for &'a mut dyn IndexedBlobStore {
     type Item = Blob;
     type IntoIter = IndexedBlobStoreIterator<'a>;
@@ -1,16 +1,17 @@
 // This is free and unencumbered software released into the public domain.
 
 use crate::{
-    hash, Blob, BlobHash, BlobID, BlobStore, BlobStoreExt, BlobStoreOptions, IndexedBlobStore,
-    Result,
+    parse_id_token, Blob, BlobHash, BlobID, BlobMetadata, BlobPage, BlobStore, BlobStoreExt,
+    BlobStoreOptions, HashingReader, IndexedBlobStore, Result, LIST_PAGE_SIZE,
 };
 use std::{
-    cell::RefCell,
     collections::HashMap,
     io::{Cursor, Read},
-    rc::Rc,
+    sync::{Arc, Mutex},
 };
 
+/// An in-memory blob store. Blobs never leave the process, so filters are
+/// refused rather than applied.
 #[derive(Default)]
 pub struct EphemeralBlobStore {
     pub(crate) config: BlobStoreOptions,
@@ -20,11 +21,12 @@ pub struct EphemeralBlobStore {
 
 impl EphemeralBlobStore {
     #[allow(unused)]
-    pub fn new(config: BlobStoreOptions) -> Self {
-        Self {
+    pub fn new(config: BlobStoreOptions) -> Result<Self> {
+        config.check_unfiltered("ephemeral")?;
+        Ok(Self {
             config,
             ..Default::default()
-        }
+        })
     }
 }
 
@@ -45,15 +47,23 @@ impl BlobStore for EphemeralBlobStore {
     }
 
     fn put(&mut self, blob_data: &mut dyn Read) -> Result<(bool, Blob)> {
+        self.put_with_metadata(blob_data, BlobMetadata::new())
+    }
+
+    fn put_with_metadata(
+        &mut self,
+        blob_data: &mut dyn Read,
+        metadata: BlobMetadata,
+    ) -> Result<(bool, Blob)> {
         if !self.config.writable {
-            return Err(crate::BlobStoreError::NotWritable.into());
+            return Err(crate::BlobStoreError::NotWritable);
         }
 
         let mut buffer = Vec::new();
-        blob_data.read_to_end(&mut buffer)?;
-        let blob_size = buffer.len() as u64;
-
-        let blob_hash = hash(&buffer);
+        let mut data_reader = HashingReader::with_max_size(blob_data, self.config.max_blob_size);
+        data_reader.read_to_end(&mut buffer)?;
+        let metadata = metadata.complete(data_reader.prefix());
+        let (blob_hash, blob_size) = data_reader.finalize();
         if let Some(blob_id) = self.index.get(&blob_hash) {
             return match self.get_by_id(*blob_id)? {
                 None => unreachable!("blob_id {} not found", blob_id),
@@ -62,12 +72,13 @@ impl BlobStore for EphemeralBlobStore {
         }
 
         let blob_id: BlobID = self.store.len() + 1;
-        let blob_data = Rc::new(RefCell::new(Cursor::new(buffer)));
+        let blob_data = Arc::new(Mutex::new(Cursor::new(buffer)));
         let blob = Blob {
             id: blob_id,
             hash: blob_hash,
             size: blob_size,
             data: Some(blob_data),
+            metadata: Some(metadata),
         };
 
         self.store.push(blob.clone());
@@ -76,9 +87,13 @@ impl BlobStore for EphemeralBlobStore {
         Ok((true, blob))
     }
 
+    fn max_blob_size(&self) -> Option<u64> {
+        self.config.max_blob_size
+    }
+
     fn remove(&mut self, blob_hash: BlobHash) -> Result<bool> {
         if !self.config.writable {
-            return Err(crate::BlobStoreError::NotWritable.into());
+            return Err(crate::BlobStoreError::NotWritable);
         }
 
         match self.hash_to_id(blob_hash)? {
@@ -90,6 +105,26 @@ impl BlobStore for EphemeralBlobStore {
             }
         }
     }
+
+    fn list(&self, token: Option<&str>) -> Result<BlobPage> {
+        let start = parse_id_token(token)?;
+        let blobs: Vec<_> = self
+            .store
+            .iter()
+            .skip(start)
+            .take(LIST_PAGE_SIZE)
+            .map(|blob| (blob.hash, blob.size))
+            .collect();
+        let end = start + blobs.len();
+        Ok(BlobPage {
+            blobs,
+            next: (end < self.store.len()).then(|| end.to_string()),
+        })
+    }
+
+    fn get_metadata(&self, blob_hash: BlobHash) -> Result<Option<BlobMetadata>> {
+        Ok(self.get_by_hash(blob_hash)?.and_then(|blob| blob.metadata))
+    }
 }
 
 impl IndexedBlobStore for EphemeralBlobStore {
@@ -120,6 +155,13 @@ mod test {
 
     #[test]
     fn test() {
+        // Filters are refused rather than silently ignored:
+        let config = BlobStoreOptions::default().filter(Box::new(crate::NoopFilter {}));
+        assert!(matches!(
+            EphemeralBlobStore::new(config),
+            Err(crate::BlobStoreError::Unimplemented(_))
+        ));
+
         let mut store = EphemeralBlobStore::default();
         assert_eq!(store.count().unwrap(), 0);
 
@@ -135,4 +177,14 @@ mod test {
         assert_eq!(store.count().unwrap(), 2);
         assert_eq!(bar.id, 2);
     }
+
+    #[test]
+    fn test_get_range() {
+        let mut store = EphemeralBlobStore::default();
+        let (_, foo) = store.put_string("Foo").unwrap();
+        assert_eq!(store.get_range(foo.hash, 1, 1).unwrap().unwrap(), b"o");
+        assert_eq!(store.get_range(foo.hash, 1, 10).unwrap().unwrap(), b"oo");
+        assert_eq!(store.get_range(foo.hash, 10, 1).unwrap().unwrap(), b"");
+        assert_eq!(store.get_range(BlobHash::zero(), 0, 1).unwrap(), None);
+    }
 }